@@ -0,0 +1,60 @@
+// Header hashes per second with the whole header hashed for each nonce, and with only the last SHA-256
+// block hashed from the state cached for the template: cargo bench --bench header_hashing
+//
+// The node is a binary crate, so its modules are compiled into the bench as well
+#![allow(dead_code, unused_imports, unused_variables, unused_macros)]
+
+#[macro_use]
+extern crate lazy_static;
+
+#[path = "../src/account.rs"] mod account;
+#[path = "../src/api/mod.rs"] mod api;
+#[path = "../src/block.rs"] mod block;
+#[path = "../src/blockchain.rs"] mod blockchain;
+#[path = "../src/crypto/mod.rs"] mod crypto;
+#[path = "../src/miner.rs"] mod miner;
+#[path = "../src/network/mod.rs"] mod network;
+#[path = "../src/transaction.rs"] mod transaction;
+#[path = "../src/config.rs"] mod config;
+#[path = "../src/helper.rs"] mod helper;
+#[path = "../src/mempool.rs"] mod mempool;
+#[path = "../src/transaction_generator.rs"] mod transaction_generator;
+#[path = "../src/peers.rs"] mod peers;
+#[path = "../src/store.rs"] mod store;
+#[path = "../src/orphan_pool.rs"] mod orphan_pool;
+#[path = "../src/orphan_block_pool.rs"] mod orphan_block_pool;
+#[path = "../src/orphan_tran_pool.rs"] mod orphan_tran_pool;
+#[path = "../src/chain_params.rs"] mod chain_params;
+#[path = "../src/block_assembler.rs"] mod block_assembler;
+#[path = "../src/spread.rs"] mod spread;
+
+use std::time::Instant;
+use crate::crypto::hash::H256;
+use crate::config::MINING_STEP;
+use crate::helper::{gen_difficulty_array, generate_header, generate_random_content, generate_random_hash};
+
+const ROUNDS: u32 = 200;  // mining steps hashed each way
+
+fn main() {
+    let difficulty: H256 = gen_difficulty_array(256).into();
+    let template = generate_header(&generate_random_hash(), &generate_random_content(), 0, &difficulty);
+
+    // the whole header hashed for each nonce
+    let mut header = template.clone();
+    let start = Instant::now();
+    for _ in 0..ROUNDS * MINING_STEP {
+        assert!(header.pow_hash() >= difficulty);
+        header.change_nonce();
+    }
+    let full = (ROUNDS * MINING_STEP) as f64 / start.elapsed().as_secs_f64();
+
+    // from the state cached for the template
+    let mut header = template;
+    let start = Instant::now();
+    for _ in 0..ROUNDS {
+        assert!(!miner::mining_base(&mut header, difficulty));
+    }
+    let cached = (ROUNDS * MINING_STEP) as f64 / start.elapsed().as_secs_f64();
+    println!("Header hashing: {:.0} hashes/s for whole headers, {:.0} hashes/s with the cached state ({:.2}x)",
+             full, cached, cached / full);
+}
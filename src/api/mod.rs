@@ -1,5 +1,6 @@
 use serde::Serialize;
 use crate::miner::Handle as MinerHandle;
+use crate::crypto::hash::{H256, H160};
 use crate::blockchain::Blockchain;
 use crate::block::{PrintableBlock, PrintableContent, PrintableState};
 use crate::mempool::MemPool;
@@ -8,6 +9,7 @@ use crate::transaction_generator::Handle as TxGeneratorHandle;
 use crate::peers::Peers;
 use crate::network::estimator::{start_first_timestamp_estimate};
 
+use hex::FromHex;
 use log::info;
 use std::collections::HashMap;
 use std::thread;
@@ -42,6 +44,71 @@ struct EstimatorRes {
     mempool_size: usize,
 }
 
+#[derive(Serialize)]
+struct HashRateRes {
+    success: bool,
+    hash_rate: f64,  // hashes per second of all mining threads
+}
+
+#[derive(Serialize)]
+struct BlockTemplateRes {
+    success: bool,
+    template: String,  // to submit the solved block with, the merkle root
+    parent: String,
+    difficulty: String,  // the proof-of-work hash has to be below it
+    timestamp: u64,
+    min_timestamp: u64,
+    height: usize,
+    coinbase: String,  // bincode of the unsigned coinbase transaction, to be signed by the owner of the address
+    coinbase_hash: String,
+    coinbase_value: u64,
+    merkle_branch: Vec<String>,  // from the bottom up, the coinbase side on the left
+    transactions: Vec<String>,  // hashes of the other transactions
+}
+
+#[derive(Serialize)]
+struct SubmitBlockRes {
+    success: bool,
+    hash: String,
+}
+
+#[derive(Serialize)]
+struct OrphanRes {
+    success: bool,
+    orphan_num: usize,
+    orphan_num_by_peer: HashMap<String, usize>,
+}
+
+#[derive(Serialize)]
+struct SupplyRes {
+    success: bool,
+    height: usize,  // height of the tip
+    issued_supply: u64,  // total subsidy of the longest chain
+    next_subsidy: u64,  // subsidy of the next block
+    max_supply: Option<u64>,  // none if the subsidy never runs out
+}
+
+#[derive(Serialize)]
+struct FeeBucket {
+    min_fee_rate: u64,  // fee rates(per 1000 bytes) of the bucket are below twice this, or 0
+    count: usize,
+    bytes: usize,
+}
+
+#[derive(Serialize)]
+struct FeeHistogramRes {
+    success: bool,
+    mempool_size: usize,
+    min_fee_rate: u64,  // to enter mempool now
+    buckets: Vec<FeeBucket>,
+}
+
+#[derive(Serialize)]
+struct RejectionRes {
+    success: bool,
+    rejections_by_peer: HashMap<String, HashMap<&'static str, usize>>,
+}
+
 macro_rules! respond_json {
     ($req:expr, $success:expr, $message:expr ) => {{
         let content_type = "Content-Type: application/json".parse::<Header>().unwrap();
@@ -70,6 +137,15 @@ macro_rules! check_estimator {
     }};
 }
 
+macro_rules! respond_payload {
+    ($req:expr, $payload:expr) => {{
+        let content_type = "Content-Type: application/json".parse::<Header>().unwrap();
+        let resp = Response::from_string(serde_json::to_string_pretty(&$payload).unwrap())
+            .with_header(content_type);
+        $req.respond(resp).unwrap();
+    }};
+}
+
 lazy_static! {
     pub static ref TEMPLATES: Tera = {
         let mut tera = match Tera::new("src/api/templates/**/*") {
@@ -153,6 +229,93 @@ impl Server {
                             miner.pause();
                             respond_json!(req, true, "ok");
                         }
+                        "/miner/hash-rate" => {
+                            let payload = HashRateRes {
+                                success: true,
+                                hash_rate: miner.hash_rate(),
+                            };
+                            respond_payload!(req, payload);
+                        }
+                        "/miner/block-template" => {
+                            let params: HashMap<_, _> = url.query_pairs().into_owned().collect();
+                            let addr = match params.get("address").map(<[u8; 20]>::from_hex) {
+                                Some(Ok(v)) => H160::from(v),
+                                Some(Err(e)) => {
+                                    respond_json!(req, false, format!("error parsing address: {}", e));
+                                    return;
+                                }
+                                None => {
+                                    respond_json!(req, false, "missing address");
+                                    return;
+                                }
+                            };
+                            let template = miner.block_template(&addr);
+                            let coinbase = &template.content.trans[0];
+                            let payload = BlockTemplateRes {
+                                success: true,
+                                template: hex::encode(template.id()),
+                                parent: hex::encode(template.header.parent),
+                                difficulty: hex::encode(template.header.difficulty),
+                                timestamp: template.header.timestamp,
+                                min_timestamp: template.min_timestamp,
+                                height: template.height,
+                                coinbase: hex::encode(bincode::serialize(&coinbase.transaction).unwrap()),
+                                coinbase_hash: hex::encode(coinbase.hash),
+                                coinbase_value: coinbase.transaction.outputs[0].val,
+                                merkle_branch: template.merkle_branch().iter().map(hex::encode).collect(),
+                                transactions: template.content.trans[1..].iter().map(|t| hex::encode(t.hash)).collect(),
+                            };
+                            respond_payload!(req, payload);
+                        }
+                        "/miner/submit-block" => {
+                            let params: HashMap<_, _> = url.query_pairs().into_owned().collect();
+                            let mut missing = ["template", "nonce", "public_key", "signature"].iter()
+                                .filter(|k| !params.contains_key(**k));
+                            if let Some(k) = missing.next() {
+                                respond_json!(req, false, format!("missing {}", k));
+                                return;
+                            }
+                            let id = match <[u8; 32]>::from_hex(&params["template"]) {
+                                Ok(v) => H256::from(v),
+                                Err(e) => {
+                                    respond_json!(req, false, format!("error parsing template: {}", e));
+                                    return;
+                                }
+                            };
+                            let nonce = match params["nonce"].parse::<u32>() {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    respond_json!(req, false, format!("error parsing nonce: {}", e));
+                                    return;
+                                }
+                            };
+                            let timestamp = match params.get("timestamp").map(|v| v.parse::<u64>()) {
+                                Some(Ok(v)) => Some(v),
+                                Some(Err(e)) => {
+                                    respond_json!(req, false, format!("error parsing timestamp: {}", e));
+                                    return;
+                                }
+                                None => None,
+                            };
+                            let (public_key, signature) = match (hex::decode(&params["public_key"]),
+                                                                 hex::decode(&params["signature"])) {
+                                (Ok(pk), Ok(sig)) => (pk, sig),
+                                (Err(e), _) | (_, Err(e)) => {
+                                    respond_json!(req, false, format!("error parsing key or signature: {}", e));
+                                    return;
+                                }
+                            };
+                            match miner.submit_block(&id, nonce, timestamp, &public_key, &signature) {
+                                Ok(hash) => {
+                                    let payload = SubmitBlockRes {
+                                        success: true,
+                                        hash: hex::encode(hash),
+                                    };
+                                    respond_payload!(req, payload);
+                                }
+                                Err(e) => respond_json!(req, false, e),
+                            }
+                        }
                         "/blockchain/showheader" => {
                             let blocks = blockchain.lock().unwrap().block_chain();
                             let pblock = PrintableBlock::from_block_vec(&blocks);
@@ -166,8 +329,11 @@ impl Server {
                             req.respond(resp).unwrap();
                         }
                         "/blockchain/showtx" => {
-                            let contents = blockchain.lock().unwrap().content_chain();
-                            let pcontent = PrintableContent::from_content_vec(&contents);
+                            let blockchain = blockchain.lock().unwrap();
+                            let contents = blockchain.content_chain();
+                            let fees = blockchain.fee_chain();
+                            drop(blockchain);
+                            let pcontent = PrintableContent::from_content_vec(&contents, &fees);
                             let mut context = Context::new();
                             context.insert("contents", &pcontent);
 
@@ -178,8 +344,7 @@ impl Server {
                             req.respond(resp).unwrap();
                         }
                         "/blockchain/showstate" => {
-                            let cur_state = blockchain.lock().unwrap().tip_block_state();
-                            let pstate = PrintableState::from_state(&cur_state);
+                            let pstate = PrintableState::from_state(blockchain.lock().unwrap().tip_state());
                             let mut context = Context::new();
                             context.insert("state", &pstate);
 
@@ -189,10 +354,48 @@ impl Server {
                                 .with_header(content_type);
                             req.respond(resp).unwrap();
                         }
+                        "/blockchain/orphans" => {
+                            let blockchain = blockchain.lock().unwrap();
+                            let payload = OrphanRes {
+                                success: true,
+                                orphan_num: blockchain.orphan_count(),
+                                orphan_num_by_peer: blockchain.orphan_count_by_peer().iter()
+                                    .map(|(addr, n)| (addr.to_string(), *n))
+                                    .collect(),
+                            };
+                            drop(blockchain);
+                            respond_payload!(req, payload);
+                        }
+                        "/blockchain/supply" => {
+                            let blockchain = blockchain.lock().unwrap();
+                            let params = blockchain.chain_params();
+                            let payload = SupplyRes {
+                                success: true,
+                                height: blockchain.length() - 1,
+                                issued_supply: blockchain.issued_supply(),
+                                next_subsidy: params.subsidy(blockchain.length()),
+                                max_supply: params.max_supply(),
+                            };
+                            drop(blockchain);
+                            respond_payload!(req, payload);
+                        }
+                        "/blockchain/rejections" => {
+                            let blockchain = blockchain.lock().unwrap();
+                            let payload = RejectionRes {
+                                success: true,
+                                rejections_by_peer: blockchain.rejection_counts().iter()
+                                    .map(|(addr, counts)| (addr.to_string(), counts.clone()))
+                                    .collect(),
+                            };
+                            drop(blockchain);
+                            respond_payload!(req, payload);
+                        }
                         "/mempool/showtx" => {
-                            let trans_map = &mempool.lock().unwrap().transactions;
-                            let trans: Vec<SignedTransaction> = trans_map.values().cloned().collect();
-                            let ptrans = PrintableTransaction::from_signedtx_vec(&trans);
+                            let mempool = mempool.lock().unwrap();
+                            let trans: Vec<SignedTransaction> = mempool.transactions.values().cloned().collect();
+                            let fees: Vec<Option<u64>> = trans.iter().map(|t| mempool.fee_of(&t.hash)).collect();
+                            drop(mempool);
+                            let ptrans = PrintableTransaction::from_signedtx_vec(&trans, &fees);
                             let mut context = Context::new();
                             context.insert("txs", &ptrans);
                             context.insert("size", &ptrans.len());
@@ -203,6 +406,19 @@ impl Server {
                                 .with_header(content_type);
                             req.respond(resp).unwrap();
                         }
+                        "/mempool/fee-histogram" => {
+                            let mempool = mempool.lock().unwrap();
+                            let payload = FeeHistogramRes {
+                                success: true,
+                                mempool_size: mempool.size(),
+                                min_fee_rate: mempool.min_fee_rate(),
+                                buckets: mempool.fee_histogram().into_iter()
+                                    .map(|(min_fee_rate, count, bytes)| FeeBucket { min_fee_rate, count, bytes })
+                                    .collect(),
+                            };
+                            drop(mempool);
+                            respond_payload!(req, payload);
+                        }
                         "/txgenerator/stop" => {
                             transaction_generator.stop();
                             respond_json!(req, true, "ok");
@@ -4,26 +4,50 @@ use serde::{Serialize, Deserialize};
 use chrono::prelude::DateTime;
 use chrono::Utc;
 use std::time::{UNIX_EPOCH, Duration};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use crate::crypto::hash::{H256, H160, Hashable};
 use crate::transaction::{SignedTransaction, TxInput, PrintableTransaction, PrintableTxInput, PrintableTxOutput, TxOutput};
 use crate::crypto::merkle::MerkleTree;
 use crate::config::DIFFICULTY;
 use crate::helper::gen_difficulty_array;
+use crate::chain_params::ChainParams;
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Block {
     pub hash: H256,         // the hash of the header in this block
     pub index: usize,       // the distance from the genesis block
+    pub work: u128,         // cumulative work of the chain ending at this block
     pub header: Header,
     pub content: Content,   // transaction in this block
 }
 
+// Reason a block is rejected
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum BlockValidationError {
+    Duplicate,  // already in chain, orphan pool or future buffer
+    HashMismatch,  // block hash is not the hash of its header
+    InvalidPow,
+    WrongDifficulty,
+    TimestampTooEarly,  // not above the median time past
+    TimestampTooFarAhead,  // beyond the future drift, the block is buffered and retried later
+    CheckpointMismatch,
+    MerkleRootMismatch,
+    InvalidSignature(H256),  // hash of the transaction
+    MissingCoinbase,
+    ExcessiveCoinbase,  // coinbase claims more than the subsidy plus fees of the block
+    DoubleSpend(H256),  // an input of the transaction is missing or already spent
+    ImmatureCoinbaseSpend(H256),  // the transaction spends a coinbase output without enough confirmations
+    WrongOwner(H256),  // an input of the transaction is not owned by its sender
+    UnbalancedTransaction(H256),  // outputs of the transaction exceed its inputs
+    StoreFailed,
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct PrintableBlock {
     pub hash: String,
     pub parent_hash: String,
     pub index: usize,
+    pub work: String,
     pub nonce: u32,
     pub difficulty: String,
     pub timestamp: String,
@@ -50,8 +74,27 @@ pub struct PrintableContent {
     pub index: usize,
 }
 
+// Where an unspent output comes from
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct UtxoOrigin {
+    pub height: usize,  // height of the block creating it
+    pub coinbase: bool,
+}
+
+// Unspent outputs, along with the origins of those created by blocks
 #[derive(Clone, Debug)]
-pub struct State (pub HashMap<(H256, u32), (u64, H160)>);
+pub struct State (pub HashMap<(H256, u32), (u64, H160)>, HashMap<(H256, u32), UtxoOrigin>);
+
+// Outputs spent & created by a block, enough to roll a state back (or forward again) without re-validation
+// An output both created and spent inside the block shows up in neither
+#[derive(Clone, Debug, Default)]
+pub struct BlockUndo {
+    pub spent: Vec<((H256, u32), (u64, H160))>,
+    pub created: Vec<((H256, u32), (u64, H160))>,
+    pub fees: Vec<u64>,  // fee of each transaction, 0 for the coinbase
+    spent_origins: HashMap<(H256, u32), UtxoOrigin>,
+    created_origins: HashMap<(H256, u32), UtxoOrigin>,
+}
 
 #[derive(Serialize, Deserialize)]
 pub struct PrintableState {
@@ -63,17 +106,38 @@ pub struct PrintableState {
 impl State {
     pub fn new() -> Self {
         let map: HashMap<(H256, u32), (u64, H160)> = HashMap::new();
-        Self(map)
+        Self(map, HashMap::new())
     }
 
+    // Insert an output of unknown origin, which can be spent at any height
     pub fn insert(&mut self, key: (H256, u32), val: (u64, H160)) {
         self.0.insert(key, val);
+        self.1.remove(&key);
+    }
+
+    pub fn insert_with_origin(&mut self, key: (H256, u32), val: (u64, H160), origin: UtxoOrigin) {
+        self.0.insert(key, val);
+        self.1.insert(key, origin);
     }
 
     pub fn remove(&mut self, key: &(H256, u32)) -> Option<(u64, H160)> {
+        self.1.remove(key);
         return self.0.remove(key);
     }
 
+    pub fn origin_of(&self, key: &(H256, u32)) -> Option<UtxoOrigin> {
+        self.1.get(key).copied()
+    }
+
+    // Whether an output can be spent in a block at the given height, i.e. it is not a coinbase output
+    // with less than maturity confirmations
+    pub fn is_mature(&self, key: &(H256, u32), height: usize, maturity: usize) -> bool {
+        match self.1.get(key) {
+            Some(origin) if origin.coinbase => height >= origin.height + maturity,
+            _ => true,
+        }
+    }
+
     pub fn contains_key(&self, key: &(H256, u32)) -> bool {
         return self.0.contains_key(key);
     }
@@ -84,6 +148,7 @@ impl State {
 
     pub fn clear(&mut self) {
         self.0.clear();
+        self.1.clear();
     }
 
     pub fn coins_of(&self, addr: &H160) -> (HashMap<TxInput, u64>, u64) {
@@ -97,6 +162,173 @@ impl State {
         }
         return (coins, balance);
     }
+
+    // Same as coins_of, but leave out coinbase outputs not mature for a block at the given height
+    pub fn spendable_coins_of(&self, addr: &H160, height: usize, maturity: usize) -> (HashMap<TxInput, u64>, u64) {
+        let (mut coins, mut balance) = self.coins_of(addr);
+        coins.retain(|input, val| {
+            let mature = self.is_mature(&(input.pre_hash, input.index), height, maturity);
+            if !mature {
+                balance -= *val;
+            }
+            mature
+        });
+        (coins, balance)
+    }
+
+    // Fee of a non-coinbase transaction, i.e. its inputs minus its outputs
+    // None if an input is not in this state or the outputs exceed the inputs
+    pub fn fee_of(&self, tran: &SignedTransaction) -> Option<u64> {
+        let mut input_val = 0u64;
+        for input in tran.transaction.inputs.iter() {
+            let (val, _) = self.get(&(input.pre_hash, input.index))?;
+            input_val = input_val.checked_add(*val)?;
+        }
+        let output_val = tran.transaction.outputs.iter().try_fold(0u64, |sum, o| sum.checked_add(o.val))?;
+        input_val.checked_sub(output_val)
+    }
+
+    // Apply a non-coinbase transaction of a block at the given height: spend its inputs and add its outputs,
+    // return its fee. Return the error and leave the state untouched if an input is missing, not owned
+    // by the sender, an immature coinbase output, or the outputs exceed the inputs
+    pub fn try_apply_tran(&mut self, tran: &SignedTransaction, height: usize, maturity: usize) -> Result<u64, BlockValidationError> {
+        let sender_addr: H160 = tran.sender_addr();
+        let mut spent = HashSet::<(H256, u32)>::new();
+        let mut balance = 0i64;
+        for input in tran.transaction.inputs.iter() {
+            let key = (input.pre_hash, input.index);
+            match self.get(&key) {
+                Some((val, owner_addr)) if *owner_addr == sender_addr && spent.insert(key) => {
+                    if !self.is_mature(&key, height, maturity) {
+                        return Err(BlockValidationError::ImmatureCoinbaseSpend(tran.hash));
+                    }
+                    balance += *val as i64;
+                }
+                Some((_, owner_addr)) if *owner_addr != sender_addr => {
+                    return Err(BlockValidationError::WrongOwner(tran.hash));
+                }
+                _ => return Err(BlockValidationError::DoubleSpend(tran.hash)),
+            }
+        }
+        for output in tran.transaction.outputs.iter() {
+            balance -= output.val as i64;
+        }
+        if balance < 0 {
+            return Err(BlockValidationError::UnbalancedTransaction(tran.hash));
+        }
+
+        for key in spent.iter() {
+            self.remove(key);
+        }
+        let origin = UtxoOrigin { height, coinbase: false };
+        for (index, output) in tran.transaction.outputs.iter().enumerate() {
+            self.insert_with_origin((tran.hash, index as u32), (output.val, output.rec_address), origin);
+        }
+        Ok(balance as u64)
+    }
+
+    // Roll back a block applied to this state
+    pub fn undo(&mut self, undo: &BlockUndo) {
+        for (key, _) in undo.created.iter() {
+            self.remove(key);
+        }
+        for (key, val) in undo.spent.iter() {
+            match undo.spent_origins.get(key) {
+                Some(origin) => self.insert_with_origin(*key, *val, *origin),
+                None => self.insert(*key, *val),
+            }
+        }
+    }
+
+    // Apply a block again after it was rolled back
+    pub fn redo(&mut self, undo: &BlockUndo) {
+        for (key, _) in undo.spent.iter() {
+            self.remove(key);
+        }
+        for (key, val) in undo.created.iter() {
+            match undo.created_origins.get(key) {
+                Some(origin) => self.insert_with_origin(*key, *val, *origin),
+                None => self.insert(*key, *val),
+            }
+        }
+    }
+}
+
+impl BlockUndo {
+    fn create(&mut self, state: &mut State, key: (H256, u32), val: (u64, H160), origin: UtxoOrigin) {
+        let prev_origin = state.origin_of(&key);
+        if let Some(prev) = state.0.insert(key, val) {
+            self.spent.push((key, prev));
+            if let Some(prev_origin) = prev_origin {
+                self.spent_origins.insert(key, prev_origin);
+            }
+        }
+        state.1.insert(key, origin);
+        self.created.push((key, val));
+        self.created_origins.insert(key, origin);
+    }
+
+    fn spend(&mut self, key: (H256, u32), val: (u64, H160), origin: Option<UtxoOrigin>) {
+        match self.created.iter().position(|(k, _)| *k == key) {
+            Some(pos) => {
+                self.created.swap_remove(pos);
+                self.created_origins.remove(&key);
+            }
+            None => {
+                self.spent.push((key, val));
+                if let Some(origin) = origin {
+                    self.spent_origins.insert(key, origin);
+                }
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for BlockValidationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            BlockValidationError::Duplicate => write!(f, "duplicate block"),
+            BlockValidationError::HashMismatch => write!(f, "hash mismatch"),
+            BlockValidationError::InvalidPow => write!(f, "invalid proof of work"),
+            BlockValidationError::WrongDifficulty => write!(f, "wrong difficulty"),
+            BlockValidationError::TimestampTooEarly => write!(f, "timestamp not above median time past"),
+            BlockValidationError::TimestampTooFarAhead => write!(f, "timestamp too far in the future"),
+            BlockValidationError::CheckpointMismatch => write!(f, "conflict with checkpoint"),
+            BlockValidationError::MerkleRootMismatch => write!(f, "merkle root mismatch"),
+            BlockValidationError::InvalidSignature(h) => write!(f, "invalid signature of tx {}", h),
+            BlockValidationError::MissingCoinbase => write!(f, "missing or invalid coinbase"),
+            BlockValidationError::ExcessiveCoinbase => write!(f, "coinbase exceeds subsidy plus fees"),
+            BlockValidationError::DoubleSpend(h) => write!(f, "double spend in tx {}", h),
+            BlockValidationError::ImmatureCoinbaseSpend(h) => write!(f, "spend of immature coinbase in tx {}", h),
+            BlockValidationError::WrongOwner(h) => write!(f, "input not owned by sender of tx {}", h),
+            BlockValidationError::UnbalancedTransaction(h) => write!(f, "outputs exceed inputs in tx {}", h),
+            BlockValidationError::StoreFailed => write!(f, "failed to persist"),
+        }
+    }
+}
+
+impl BlockValidationError {
+    // Name of the error kind regardless of the transaction, used to count rejections
+    pub fn kind(&self) -> &'static str {
+        match self {
+            BlockValidationError::Duplicate => "duplicate",
+            BlockValidationError::HashMismatch => "hash_mismatch",
+            BlockValidationError::InvalidPow => "invalid_pow",
+            BlockValidationError::WrongDifficulty => "wrong_difficulty",
+            BlockValidationError::TimestampTooEarly => "timestamp_too_early",
+            BlockValidationError::TimestampTooFarAhead => "timestamp_too_far_ahead",
+            BlockValidationError::CheckpointMismatch => "checkpoint_mismatch",
+            BlockValidationError::MerkleRootMismatch => "merkle_root_mismatch",
+            BlockValidationError::InvalidSignature(_) => "invalid_signature",
+            BlockValidationError::MissingCoinbase => "missing_coinbase",
+            BlockValidationError::ExcessiveCoinbase => "excessive_coinbase",
+            BlockValidationError::DoubleSpend(_) => "double_spend",
+            BlockValidationError::ImmatureCoinbaseSpend(_) => "immature_coinbase_spend",
+            BlockValidationError::WrongOwner(_) => "wrong_owner",
+            BlockValidationError::UnbalancedTransaction(_) => "unbalanced_transaction",
+            BlockValidationError::StoreFailed => "store_failed",
+        }
+    }
 }
 
 impl std::convert::AsRef<HashMap<(H256, u32), (u64, H160)>> for State {
@@ -139,6 +371,7 @@ impl Block {
         Block {
             hash: h.into(),
             index: 0,
+            work: 0,
             header: header,
             content: content,
         }
@@ -148,6 +381,7 @@ impl Block {
         Self {
             hash: header.hash(),
             index: 0,
+            work: 0,
             header: header,
             content: content,
         }
@@ -158,67 +392,79 @@ impl Block {
     }
 
     // Check transaction signature in content; if anyone fails, the whole block fails
-    pub fn validate_signature(&self) -> bool {
+    pub fn validate_signature(&self) -> Result<(), BlockValidationError> {
         let trans = &self.content.trans;
         for t in trans.iter() {
             if !t.sign_check() {
-                return false;
+                return Err(BlockValidationError::InvalidSignature(t.hash));
             }
         }
-        true
+        Ok(())
     }
 
-    // Try to generate a new state based on the parent_state
-    // Validate all transactions, such as coinbase transaction and double-spend issue
-    // return None if any check fails
-    pub fn try_generate_state(&self, parent_state: &State) -> Option<State> {
+    // Check the merkle root in header against the transactions
+    pub fn validate_merkle_root(&self) -> bool {
+        self.header.merkle_root == self.content.merkle_root()
+    }
+
+    // Try to generate a new state based on the parent_state, for this block at the given height
+    // Validate all transactions, such as coinbase transaction against the subsidy at this height plus fees,
+    // coinbase maturity and double-spend issue; return the error if any check fails
+    pub fn try_generate_state(&self, parent_state: &State, height: usize, params: &ChainParams) -> Result<State, BlockValidationError> {
         let mut state = parent_state.clone();
+        self.try_apply(&mut state, height, params)?;
+        Ok(state)
+    }
+
+    // Same checks as try_generate_state, but apply the block to state in place
+    // Return the undo record, or the error with state untouched if any check fails
+    pub fn try_apply(&self, state: &mut State, height: usize, params: &ChainParams) -> Result<BlockUndo, BlockValidationError> {
+        let mut undo = BlockUndo::default();
         let mut trans_iter = self.content.trans.iter();
 
-        // check coinbase transaction
-        if let Some(coinbase_tran) = trans_iter.next() {
-            if !coinbase_tran.is_coinbase_tran() {
-                return None;
+        // check coinbase transaction, its value is checked once fees are known
+        let coinbase_val = match trans_iter.next() {
+            Some(coinbase_tran) if coinbase_tran.is_coinbase_tran() => {
+                let output = &coinbase_tran.transaction.outputs[0];
+                let origin = UtxoOrigin { height, coinbase: true };
+                undo.create(state, (coinbase_tran.hash, 0), (output.val, output.rec_address), origin);
+                undo.fees.push(0);
+                output.val
             }
-            let output = coinbase_tran.transaction.outputs[0].clone();
-            state.insert((coinbase_tran.hash.clone(), 0),
-                (output.val, output.rec_address));
-        } else {
-            return None;
-        }
+            _ => return Err(BlockValidationError::MissingCoinbase),
+        };
 
         // check non-coinbase transactions
-        while let Some(tran) = trans_iter.next() {
-            let mut balance = 0i64;
-            let sender_addr: H160 = tran.sender_addr();
-
-            // remove inputs from state
-            for input in tran.transaction.inputs.iter() {
-                match state.remove(&(input.pre_hash, input.index)) {
-                    Some((val, owner_addr)) => {
-                        if owner_addr != sender_addr {
-                            return None;
-                        }
-                        balance += val as i64;
-                    }
-                    // double spend check
-                    None => return None
+        for tran in trans_iter {
+            let inputs: Vec<_> = tran.transaction.inputs.iter()
+                .map(|i| (i.pre_hash, i.index))
+                .filter_map(|key| state.get(&key).map(|val| (key, *val, state.origin_of(&key))))
+                .collect();
+            match state.try_apply_tran(tran, height, params.coinbase_maturity) {
+                Ok(fee) => undo.fees.push(fee),
+                Err(e) => {
+                    state.undo(&undo);
+                    return Err(e);
                 }
             }
-
-            // add output to state
+            for (key, val, origin) in inputs {
+                undo.spend(key, val, origin);
+            }
+            let origin = UtxoOrigin { height, coinbase: false };
             for (index, output) in tran.transaction.outputs.iter().enumerate() {
-                state.insert((tran.hash.clone(), index as u32),
-                             (output.val, output.rec_address));
-                balance -= output.val as i64;
+                let key = (tran.hash, index as u32);
+                undo.created.push((key, (output.val, output.rec_address)));
+                undo.created_origins.insert(key, origin);
             }
+        }
 
-            // check balance
-            if balance < 0 {
-                return None;
-            }
+        // the coinbase can claim the subsidy plus all fees, any surplus is destroyed
+        let total_fee = undo.fees.iter().fold(0u64, |sum, fee| sum.saturating_add(*fee));
+        if coinbase_val > params.subsidy(height).saturating_add(total_fee) {
+            state.undo(&undo);
+            return Err(BlockValidationError::ExcessiveCoinbase);
         }
-        return Some(state);
+        Ok(undo)
     }
 
     #[cfg(any(test, test_utilities))]
@@ -238,6 +484,7 @@ impl PrintableBlock {
                 hash: hex::encode(&b.hash),
                 parent_hash: hex::encode(&b.header.parent),
                 index: b.index,
+                work: b.work.to_string(),
                 nonce: b.header.nonce,
                 difficulty: hex::encode(&b.header.difficulty),
                 timestamp: ts_str,
@@ -271,8 +518,56 @@ impl Header {
         ctx.finish().into()
     }
 
-    pub fn change_nonce(&mut self) {
-        self.nonce = self.nonce.overflowing_add(1).0;
+    // Hash checked against the difficulty, see PowHasher. The block hash keeps its own layout
+    pub fn pow_hash(&self) -> H256 {
+        PowHasher::new(self).hash(self.nonce)
+    }
+
+    pub fn merkle_root(&self) -> H256 {
+        self.merkle_root
+    }
+
+    // Work needed to mine this header, see H256::work
+    pub fn work(&self) -> u128 {
+        self.difficulty.work()
+    }
+
+    // Return true if the nonce wraps, i.e. all nonces are tried under this merkle root
+    pub fn change_nonce(&mut self) -> bool {
+        let (nonce, wrapped) = self.nonce.overflowing_add(1);
+        self.nonce = nonce;
+        wrapped
+    }
+}
+
+// Proof-of-work hash of a header for any nonce, over a fixed layout with the nonce at the end:
+// parent and difficulty fill the first SHA-256 block, so its state is computed once per template
+// and each nonce only costs the last block.
+// This forks consensus from the rule it replaced, the block hash being below the difficulty: chains
+// mined under that rule, including ones stored by older nodes, fail the check now, and the block hash
+// of a valid block tells nothing about its work. Check proof of work with this, never with block.hash
+pub struct PowHasher {
+    midstate: digest::Context,  // after parent and difficulty
+    tail: [u8; 44],  // timestamp, merkle root and then the nonce
+}
+
+impl PowHasher {
+    pub fn new(header: &Header) -> Self {
+        let mut midstate = digest::Context::new(&digest::SHA256);
+        midstate.update(header.parent.as_ref());
+        midstate.update(header.difficulty.as_ref());
+        let mut tail = [0u8; 44];
+        tail[..8].copy_from_slice(&header.timestamp.to_be_bytes());
+        tail[8..40].copy_from_slice(header.merkle_root.as_ref());
+        Self { midstate, tail }
+    }
+
+    pub fn hash(&self, nonce: u32) -> H256 {
+        let mut tail = self.tail;
+        tail[40..].copy_from_slice(&nonce.to_be_bytes());
+        let mut ctx = self.midstate.clone();
+        ctx.update(&tail);
+        ctx.finish().into()
     }
 }
 
@@ -307,11 +602,15 @@ impl Content {
 }
 
 impl PrintableContent {
-    pub fn from_content_vec(contents: &Vec<Content>) -> Vec<Self> {
+    // fees[i] are the transaction fees of contents[i], empty if unknown
+    pub fn from_content_vec(contents: &[Content], fees: &[Vec<u64>]) -> Vec<Self> {
         let mut pcontents = Vec::<Self>::new();
         let len = contents.len();
         for (index, c) in contents.iter().enumerate() {
-            let pts = PrintableTransaction::from_signedtx_vec(&c.trans);
+            let tran_fees: Vec<Option<u64>> = fees.get(index)
+                .map(|f| f.iter().map(|fee| Some(*fee)).collect())
+                .unwrap_or_default();
+            let pts = PrintableTransaction::from_signedtx_vec(&c.trans, &tran_fees);
             let pc = Self { trans: pts, index: len - 1 - index};
             pcontents.push(pc);
         }
@@ -355,6 +654,36 @@ pub mod test {
         assert!(DIFFICULTY < 256);
     }
 
+    #[test]
+    fn test_pow_hash() {
+        let content = generate_random_content();
+        let mut header = generate_header(&generate_random_hash(), &content, 7, &generate_random_hash());
+        let hasher = PowHasher::new(&header);
+        assert_eq!(header.pow_hash(), hasher.hash(7));
+        assert_ne!(hasher.hash(7), hasher.hash(8));
+        header.change_nonce();
+        assert_eq!(header.pow_hash(), hasher.hash(8));
+
+        // the fixed layout, with the nonce at the end
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(header.parent.as_ref());
+        bytes.extend_from_slice(header.difficulty.as_ref());
+        bytes.extend_from_slice(&header.timestamp.to_be_bytes());
+        bytes.extend_from_slice(header.merkle_root.as_ref());
+        bytes.extend_from_slice(&8u32.to_be_bytes());
+        assert_eq!(108, bytes.len());
+        assert_eq!(H256::from(digest::digest(&digest::SHA256, &bytes)), header.pow_hash());
+
+        // the block hash is unchanged
+        let mut ctx = digest::Context::new(&digest::SHA256);
+        ctx.update(header.parent.as_ref());
+        ctx.update(&8u32.to_be_bytes());
+        ctx.update(header.difficulty.as_ref());
+        ctx.update(&header.timestamp.to_be_bytes());
+        ctx.update(header.merkle_root.as_ref());
+        assert_eq!(H256::from(ctx.finish()), header.hash());
+    }
+
     #[test]
     fn test_content_new_with_trans() {
         let mut trans = Vec::<SignedTransaction>::new();
@@ -433,6 +762,8 @@ pub mod test {
 
     #[test]
     fn test_try_generate_state() {
+        let params = ChainParams::main();
+        let spend_height = 2 + params.coinbase_maturity;  // coinbase of height 2 is mature
         let key_1 = key_pair::random();
         let addr_1: H160 = digest::digest(&digest::SHA256, key_1.public_key().as_ref()).into();
         let random_h256 = generate_random_hash();
@@ -440,8 +771,8 @@ pub mod test {
         let content = Content::new_with_trans(&vec![signed_coinbase_tran.clone()]);
         let header = generate_header(&random_h256, &content, 0, &random_h256);
         let block = Block::new(header, content.clone());
-        let new_state = block.try_generate_state(&State::new());
-        if let Some(state) = new_state.clone() {
+        let new_state = block.try_generate_state(&State::new(), 1, &params);
+        if let Ok(state) = new_state.clone() {
             assert!(state.contains_key(&(signed_coinbase_tran.hash.clone(), 0)));
             let value = state.get(&(signed_coinbase_tran.hash.clone(), 0)).unwrap().clone();
             assert_eq!((COINBASE_REWARD, addr_1.clone()), value);
@@ -449,12 +780,13 @@ pub mod test {
             assert!(false);
         }
 
+        std::thread::sleep(Duration::from_millis(2));  // keep coinbase transactions distinct
         let signed_coinbase_tran_2 = generate_signed_coinbase_transaction(&key_1);
         let content = Content::new_with_trans(&vec![signed_coinbase_tran_2.clone()]);
         let header = generate_header(&random_h256, &content, 0, &random_h256);
         let block = Block::new(header, content.clone());
-        let state_2 = block.try_generate_state(&new_state.unwrap());
-        if let Some(state) = state_2.clone() {
+        let state_2 = block.try_generate_state(&new_state.unwrap(), 2, &params);
+        if let Ok(state) = state_2.clone() {
             assert!(state.contains_key(&(signed_coinbase_tran.hash.clone(), 0)));
             let value = state.get(&(signed_coinbase_tran.hash.clone(), 0)).unwrap().clone();
             assert_eq!((COINBASE_REWARD, addr_1.clone()), value);
@@ -466,6 +798,7 @@ pub mod test {
         }
 
         // correct
+        std::thread::sleep(Duration::from_millis(2));  // keep coinbase transactions distinct
         let signed_coinbase_tran_3 = generate_signed_coinbase_transaction(&key_1);
         let random_h160 = generate_random_h160();
         let txinput = TxInput {pre_hash: signed_coinbase_tran_2.hash.clone(), index: 0};
@@ -475,8 +808,8 @@ pub mod test {
         let content = Content::new_with_trans(&vec![signed_coinbase_tran_3.clone(), valid_tran.clone()]);
         let header = generate_header(&random_h256, &content, 0, &random_h256);
         let block = Block::new(header, content.clone());
-        let non_state = block.try_generate_state(&state_2.clone().unwrap());
-        if let Some(state) = non_state.clone() {
+        let non_state = block.try_generate_state(&state_2.clone().unwrap(), spend_height, &params);
+        if let Ok(state) = non_state.clone() {
             assert!(!state.contains_key(&(signed_coinbase_tran_2.hash.clone(), 0)));
             assert!(state.contains_key(&(valid_tran.hash.clone(), 0)));
             assert!(state.contains_key(&(valid_tran.hash.clone(), 1)));
@@ -484,10 +817,15 @@ pub mod test {
             assert_eq!((COINBASE_REWARD-1, random_h160), value);
             let value = state.get(&(valid_tran.hash.clone(), 1)).unwrap().clone();
             assert_eq!((1, random_h160), value);
+            assert_eq!(Some(UtxoOrigin { height: spend_height, coinbase: false }), state.origin_of(&(valid_tran.hash, 0)));
         } else {
             assert!(false);
         }
 
+        // wrong: coinbase is not mature yet
+        let non_state = block.try_generate_state(&state_2.clone().unwrap(), spend_height - 1, &params);
+        assert_eq!(Some(BlockValidationError::ImmatureCoinbaseSpend(valid_tran.hash)), non_state.err());
+
         // wrong: output is bigger than input
         let signed_coinbase_tran = generate_signed_coinbase_transaction(&key_1);
         let random_h160 = generate_random_h160();
@@ -497,10 +835,8 @@ pub mod test {
         let content = Content::new_with_trans(&vec![signed_coinbase_tran.clone(), invalid_tran.clone()]);
         let header = generate_header(&random_h256, &content, 0, &random_h256);
         let block = Block::new(header, content.clone());
-        let non_state = block.try_generate_state(&state_2.clone().unwrap());
-        if let Some(_) = non_state {
-            assert!(false);
-        }
+        let non_state = block.try_generate_state(&state_2.clone().unwrap(), spend_height, &params);
+        assert_eq!(Some(BlockValidationError::UnbalancedTransaction(invalid_tran.hash)), non_state.err());
 
         // wrong public key
         let key_2 = key_pair::random();
@@ -512,10 +848,8 @@ pub mod test {
         let content = Content::new_with_trans(&vec![signed_coinbase_tran.clone(), invalid_tran.clone()]);
         let header = generate_header(&random_h256, &content, 0, &random_h256);
         let block = Block::new(header, content.clone());
-        let non_state = block.try_generate_state(&state_2.clone().unwrap());
-        if let Some(_) = non_state {
-            assert!(false);
-        }
+        let non_state = block.try_generate_state(&state_2.clone().unwrap(), spend_height, &params);
+        assert_eq!(Some(BlockValidationError::WrongOwner(invalid_tran.hash)), non_state.err());
 
         // wrong pre_hash
         let signed_coinbase_tran = generate_signed_coinbase_transaction(&key_1);
@@ -526,10 +860,144 @@ pub mod test {
         let content = Content::new_with_trans(&vec![signed_coinbase_tran.clone(), invalid_tran.clone()]);
         let header = generate_header(&random_h256, &content, 0, &random_h256);
         let block = Block::new(header, content.clone());
-        let non_state = block.try_generate_state(&state_2.clone().unwrap());
-        if let Some(_) = non_state {
-            assert!(false);
-        }
+        let non_state = block.try_generate_state(&state_2.clone().unwrap(), spend_height, &params);
+        assert_eq!(Some(BlockValidationError::DoubleSpend(invalid_tran.hash)), non_state.err());
+
+        // missing coinbase
+        let content = Content::new_with_trans(&vec![valid_tran.clone()]);
+        let header = generate_header(&random_h256, &content, 0, &random_h256);
+        let block = Block::new(header, content.clone());
+        let non_state = block.try_generate_state(&state_2.clone().unwrap(), spend_height, &params);
+        assert_eq!(Some(BlockValidationError::MissingCoinbase), non_state.err());
+    }
+
+    #[test]
+    fn test_try_apply_and_undo() {
+        let params = ChainParams::main();
+        let key = key_pair::random();
+        let addr: H160 = digest::digest(&digest::SHA256, key.public_key().as_ref()).into();
+        let random_h256 = generate_random_hash();
+        let mut state = State::new();
+        let prev_hash = generate_random_hash();
+        state.insert((prev_hash, 0), (10, addr));
+        let origin = state.clone();
+
+        // tran_2 spends the output of tran_1 inside the same block
+        let coinbase_tran = generate_signed_coinbase_transaction(&key);
+        let tran_1 = generate_signed_transaction(&key, vec![TxInput::new(prev_hash, 0)],
+                                                 vec![TxOutput::new(addr, 6), TxOutput::new(addr, 4)]);
+        let tran_2 = generate_signed_transaction(&key, vec![TxInput::new(tran_1.hash, 0)],
+                                                 vec![TxOutput::new(addr, 6)]);
+        let content = Content::new_with_trans(&vec![coinbase_tran.clone(), tran_1.clone(), tran_2.clone()]);
+        let header = generate_header(&random_h256, &content, 0, &random_h256);
+        let block = Block::new(header, content);
+        let undo = block.try_apply(&mut state, 1, &params).unwrap();
+        assert_eq!(block.try_generate_state(&origin, 1, &params).unwrap().0, state.0);
+        assert_eq!(vec![((prev_hash, 0), (10, addr))], undo.spent);
+        assert_eq!(3, undo.created.len());
+        assert_eq!(vec![0, 0, 0], undo.fees);
+        assert_eq!(3, state.0.len());
+        let applied = state.clone();
+
+        state.undo(&undo);
+        assert_eq!(origin.0, state.0);
+        state.redo(&undo);
+        assert_eq!(applied.0, state.0);
+
+        // a failing block leaves the state untouched
+        let coinbase_tran = generate_signed_coinbase_transaction(&key);
+        let double_spend = generate_signed_transaction(&key, vec![TxInput::new(tran_1.hash, 0)],
+                                                       vec![TxOutput::new(addr, 1)]);
+        let content = Content::new_with_trans(&vec![coinbase_tran, tran_1.clone(), double_spend]);
+        let header = generate_header(&random_h256, &content, 0, &random_h256);
+        let block = Block::new(header, content);
+        assert_eq!(Some(BlockValidationError::DoubleSpend(tran_1.hash)), block.try_apply(&mut state, 1, &params).err());
+        assert_eq!(applied.0, state.0);
+    }
+
+    #[test]
+    fn test_coinbase_with_fees() {
+        let params = ChainParams::main();
+        let key = key_pair::random();
+        let addr: H160 = digest::digest(&digest::SHA256, key.public_key().as_ref()).into();
+        let random_h256 = generate_random_hash();
+        let mut state = State::new();
+        let prev_hash = generate_random_hash();
+        state.insert((prev_hash, 0), (10, addr));
+        state.insert((prev_hash, 1), (10, addr));
+        let origin = state.clone();
+
+        // fees of 3 and 2
+        let tran_1 = generate_signed_transaction(&key, vec![TxInput::new(prev_hash, 0)], vec![TxOutput::new(addr, 7)]);
+        let tran_2 = generate_signed_transaction(&key, vec![TxInput::new(prev_hash, 1)], vec![TxOutput::new(addr, 8)]);
+        assert_eq!(Some(3), state.fee_of(&tran_1));
+        assert_eq!(None, state.fee_of(&generate_random_signed_transaction()));
+
+        let new_block = |coinbase_fee: u64| {
+            let coinbase = generate_signed_coinbase_transaction_with_val(&key, COINBASE_REWARD + coinbase_fee);
+            let content = Content::new_with_trans(&vec![coinbase, tran_1.clone(), tran_2.clone()]);
+            let header = generate_header(&random_h256, &content, 0, &random_h256);
+            Block::new(header, content)
+        };
+
+        // claiming more than reward plus fees fails and leaves the state untouched
+        let block = new_block(6);
+        assert_eq!(Some(BlockValidationError::ExcessiveCoinbase), block.try_apply(&mut state, 1, &params).err());
+        assert_eq!(origin.0, state.0);
+
+        let block = new_block(5);
+        let undo = block.try_apply(&mut state, 1, &params).unwrap();
+        assert_eq!(vec![0, 3, 2], undo.fees);
+        let coinbase = &block.content.trans[0];
+        assert_eq!(Some(&(COINBASE_REWARD + 5, addr)), state.get(&(coinbase.hash, 0)));
+
+        // claiming less is fine
+        assert!(new_block(0).try_generate_state(&origin, 1, &params).is_ok());
+    }
+
+    #[test]
+    fn test_coinbase_maturity() {
+        let mut params = ChainParams::main();
+        params.coinbase_maturity = 3;
+        let key = key_pair::random();
+        let addr: H160 = digest::digest(&digest::SHA256, key.public_key().as_ref()).into();
+        let random_h256 = generate_random_hash();
+        let mut state = State::new();
+        let prev_hash = generate_random_hash();
+        state.insert((prev_hash, 0), (10, addr));  // unknown origin, always spendable
+
+        let coinbase_tran = generate_signed_coinbase_transaction(&key);
+        let content = Content::new_with_trans(&vec![coinbase_tran.clone()]);
+        let header = generate_header(&random_h256, &content, 0, &random_h256);
+        let undo = Block::new(header, content).try_apply(&mut state, 5, &params).unwrap();
+        let coinbase_key = (coinbase_tran.hash, 0);
+        assert_eq!(Some(UtxoOrigin { height: 5, coinbase: true }), state.origin_of(&coinbase_key));
+        assert!(!state.is_mature(&coinbase_key, 7, params.coinbase_maturity));
+        assert!(state.is_mature(&coinbase_key, 8, params.coinbase_maturity));
+        assert_eq!(10, state.spendable_coins_of(&addr, 7, params.coinbase_maturity).1);
+        assert_eq!(10 + COINBASE_REWARD, state.spendable_coins_of(&addr, 8, params.coinbase_maturity).1);
+
+        // spend it at height 8, origins are restored after rolling back and forth
+        let before = state.clone();
+        let coinbase_tran_2 = generate_signed_coinbase_transaction_with_val(&key, COINBASE_REWARD - 1);
+        let tran = generate_signed_transaction(&key, vec![TxInput::new(coinbase_tran.hash, 0)], vec![TxOutput::new(addr, COINBASE_REWARD)]);
+        let content = Content::new_with_trans(&vec![coinbase_tran_2, tran.clone()]);
+        let header = generate_header(&random_h256, &content, 0, &random_h256);
+        let block = Block::new(header, content);
+        assert_eq!(Some(BlockValidationError::ImmatureCoinbaseSpend(tran.hash)), block.try_apply(&mut state, 7, &params).err());
+        let spend_undo = block.try_apply(&mut state, 8, &params).unwrap();
+        assert_eq!(None, state.origin_of(&coinbase_key));
+        let after = state.clone();
+        state.undo(&spend_undo);
+        assert_eq!(before.0, state.0);
+        assert_eq!(Some(UtxoOrigin { height: 5, coinbase: true }), state.origin_of(&coinbase_key));
+        state.redo(&spend_undo);
+        assert_eq!(after.0, state.0);
+        assert_eq!(Some(UtxoOrigin { height: 8, coinbase: false }), state.origin_of(&(tran.hash, 0)));
+        state.undo(&spend_undo);
+        state.undo(&undo);
+        assert_eq!(None, state.origin_of(&coinbase_key));
+        assert_eq!(None, state.origin_of(&(prev_hash, 0)));
     }
 
     #[test]
@@ -0,0 +1,274 @@
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap};
+use log::debug;
+use ring::signature::Ed25519KeyPair;
+
+use crate::block::{Block, Content, State};
+use crate::chain_params::ChainParams;
+use crate::config::{BLOCK_SIZE_LIMIT, BLOCK_BYTES_LIMIT};
+use crate::crypto::hash::{H256, H160};
+use crate::crypto::key_pair;
+use crate::helper::generate_signed_coinbase_transaction_with_extra_nonce;
+use crate::transaction::{SignedTransaction, Transaction, TxOutput};
+
+// A transaction whose inputs are all available, ordered by fee rate
+#[derive(PartialEq, Eq)]
+struct Candidate {
+    hash: H256,
+    fee: u64,
+    size: usize,  // serialized size(bytes)
+}
+
+impl Ord for Candidate {
+    fn cmp(&self, other: &Self) -> Ordering {
+        let rate = self.fee as u128 * other.size as u128;
+        let other_rate = other.fee as u128 * self.size as u128;
+        rate.cmp(&other_rate).then_with(|| other.hash.cmp(&self.hash))
+    }
+}
+
+impl PartialOrd for Candidate {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+// Builds the content of a new block on top of the tip state from pool transactions
+pub struct BlockAssembler<'a> {
+    tip_state: &'a State,
+    height: usize,  // height of the new block
+    params: &'a ChainParams,
+    max_bytes: usize,  // serialized size of the whole block
+    max_trans: usize,  // including the coinbase
+    extra_nonce: u32,  // of the coinbase
+}
+
+impl<'a> BlockAssembler<'a> {
+    pub fn new(tip_state: &'a State, height: usize, params: &'a ChainParams) -> Self {
+        Self {
+            tip_state,
+            height,
+            params,
+            max_bytes: BLOCK_BYTES_LIMIT,
+            max_trans: BLOCK_SIZE_LIMIT,
+            extra_nonce: 0,
+        }
+    }
+
+    pub fn with_limits(mut self, max_bytes: usize, max_trans: usize) -> Self {
+        self.max_bytes = max_bytes;
+        self.max_trans = max_trans;
+        self
+    }
+
+    pub fn with_extra_nonce(mut self, extra_nonce: u32) -> Self {
+        self.extra_nonce = extra_nonce;
+        self
+    }
+
+    // Pick transactions with the highest fee rate first, a transaction spending outputs of other pool
+    // transactions becomes a candidate once all those parents are picked. Transactions with inputs neither
+    // in the tip state nor in the pool, or failing to apply, are left out with their descendants.
+    // The coinbase claims the subsidy plus fees of the picked transactions
+    pub fn assemble<'t, I>(&self, trans: I, key_pair: &Ed25519KeyPair) -> Content
+        where I: IntoIterator<Item = &'t SignedTransaction> {
+        // the coinbase has a fixed size whatever its value
+        let coinbase_size = generate_signed_coinbase_transaction_with_extra_nonce(key_pair, 0, 0).size();
+        let (picked, total_fee) = self.pick(trans, coinbase_size);
+        let coinbase_val = self.params.subsidy(self.height) + total_fee;
+        let mut trans = vec![generate_signed_coinbase_transaction_with_extra_nonce(key_pair, coinbase_val, self.extra_nonce)];
+        trans.extend(picked);
+        Content::new_with_trans(&trans)
+    }
+
+    // Same as assemble, but the coinbase pays the given address and is left unsigned, for the owner
+    // of the address to sign once the block is mined. Room is kept for its public key and signature
+    pub fn assemble_paying<'t, I>(&self, trans: I, addr: &H160) -> Content
+        where I: IntoIterator<Item = &'t SignedTransaction> {
+        let coinbase_size = generate_signed_coinbase_transaction_with_extra_nonce(&key_pair::random(), 0, 0).size();
+        let (picked, total_fee) = self.pick(trans, coinbase_size);
+        let coinbase_val = self.params.subsidy(self.height) + total_fee;
+        let mut coinbase = Transaction::new(Vec::new(), vec![TxOutput::new(*addr, coinbase_val)]);
+        coinbase.extra_nonce = self.extra_nonce;
+        let mut trans = vec![SignedTransaction::new(coinbase, Box::new([]), Box::new([]))];
+        trans.extend(picked);
+        Content::new_with_trans(&trans)
+    }
+
+    // Transactions picked in order, and their total fee
+    fn pick<'t, I>(&self, trans: I, coinbase_size: usize) -> (Vec<SignedTransaction>, u64)
+        where I: IntoIterator<Item = &'t SignedTransaction> {
+        let pool: HashMap<H256, &SignedTransaction> = trans.into_iter().map(|t| (t.hash, t)).collect();
+
+        // number of inputs spending outputs of other pool transactions, and the reverse links
+        let mut waiting: HashMap<H256, usize> = HashMap::new();
+        let mut children: HashMap<H256, Vec<H256>> = HashMap::new();
+        for tran in pool.values() {
+            for input in tran.transaction.inputs.iter() {
+                if input.pre_hash != tran.hash && pool.contains_key(&input.pre_hash) {
+                    *waiting.entry(tran.hash).or_insert(0) += 1;
+                    children.entry(input.pre_hash).or_default().push(tran.hash);
+                }
+            }
+        }
+
+        let mut state = self.tip_state.clone();
+        let mut candidates = BinaryHeap::new();
+        for tran in pool.values() {
+            if !waiting.contains_key(&tran.hash) {
+                push_candidate(&mut candidates, &state, tran);
+            }
+        }
+
+        let mut bytes = bincode::serialized_size(&Block::genesis()).unwrap() as usize + coinbase_size;
+        let mut picked = Vec::<SignedTransaction>::new();
+        let mut total_fee = 0u64;
+        while let Some(candidate) = candidates.pop() {
+            if picked.len() + 1 >= self.max_trans {
+                break;
+            }
+            if bytes + candidate.size > self.max_bytes {
+                continue;  // a smaller one may still fit
+            }
+            let tran = pool[&candidate.hash];
+            match state.try_apply_tran(tran, self.height, self.params.coinbase_maturity) {
+                Ok(fee) => {
+                    bytes += candidate.size;
+                    total_fee += fee;
+                    picked.push(tran.clone());
+                }
+                Err(e) => {
+                    debug!("Leave {:?} out of the new block: {}", tran.hash, e);
+                    continue;
+                }
+            }
+            for child in children.get(&candidate.hash).into_iter().flatten() {
+                let parents_left = waiting.get_mut(child).unwrap();
+                *parents_left -= 1;
+                if *parents_left == 0 {
+                    push_candidate(&mut candidates, &state, pool[child]);
+                }
+            }
+        }
+        debug!("Assemble block at height {}: {} transactions, {} bytes, {} fees",
+               self.height, picked.len(), bytes, total_fee);
+        (picked, total_fee)
+    }
+}
+
+fn push_candidate(candidates: &mut BinaryHeap<Candidate>, state: &State, tran: &SignedTransaction) {
+    match state.fee_of(tran) {
+        Some(fee) => candidates.push(Candidate { hash: tran.hash, fee, size: tran.size() }),
+        None => debug!("Inputs of {:?} are missing or smaller than its outputs", tran.hash),
+    }
+}
+
+#[cfg(any(test, test_utilities))]
+mod tests {
+    use super::*;
+    use crate::helper::*;
+    use crate::crypto::key_pair;
+    use crate::crypto::hash::H160;
+    use crate::transaction::{TxInput, TxOutput, sign};
+    use crate::config::COINBASE_REWARD;
+    use ring::signature::KeyPair;
+
+    fn setup() -> (Ed25519KeyPair, H160, State, H256) {
+        let key = key_pair::random();
+        let addr = generate_signed_coinbase_transaction(&key).sender_addr();
+        let mut state = State::new();
+        let prev_hash = generate_random_hash();
+        for index in 0..4 {
+            state.insert((prev_hash, index), (100, addr));
+        }
+        (key, addr, state, prev_hash)
+    }
+
+    #[test]
+    fn test_fee_rate_order() {
+        let (key, addr, state, prev_hash) = setup();
+        let params = ChainParams::main();
+        let tran = |index: u32, fee: u64| generate_signed_transaction(
+            &key, vec![TxInput::new(prev_hash, index)], vec![TxOutput::new(addr, 100 - fee)]);
+        let low = tran(0, 1);
+        let high = tran(1, 9);
+        let mid = tran(2, 5);
+        let stale = generate_random_signed_transaction();
+        let content = BlockAssembler::new(&state, 1, &params)
+            .assemble(vec![&low, &high, &mid, &stale], &key);
+        assert_eq!(vec![high.hash, mid.hash, low.hash], content.get_trans_hashes()[1..].to_vec());
+        assert_eq!(COINBASE_REWARD + 15, content.trans[0].transaction.outputs[0].val);
+
+        // count limit includes the coinbase
+        let content = BlockAssembler::new(&state, 1, &params)
+            .with_limits(BLOCK_BYTES_LIMIT, 3)
+            .assemble(vec![&low, &high, &mid], &key);
+        assert_eq!(vec![high.hash, mid.hash], content.get_trans_hashes()[1..].to_vec());
+    }
+
+    #[test]
+    fn test_byte_limit() {
+        let (key, addr, state, prev_hash) = setup();
+        let params = ChainParams::main();
+        // a big transaction with a high fee, and a small one with a lower fee rate
+        let big = generate_signed_transaction(&key, vec![TxInput::new(prev_hash, 0)],
+                                              (0..20).map(|_| TxOutput::new(addr, 1)).collect());
+        let small = generate_signed_transaction(&key, vec![TxInput::new(prev_hash, 1)], vec![TxOutput::new(addr, 99)]);
+        let empty = BlockAssembler::new(&state, 1, &params).assemble(vec![], &key);
+        let base = bincode::serialized_size(&Block::genesis()).unwrap() as usize + empty.trans[0].size();
+
+        let content = BlockAssembler::new(&state, 1, &params)
+            .with_limits(base + big.size() - 1, BLOCK_SIZE_LIMIT)
+            .assemble(vec![&big, &small], &key);
+        assert_eq!(vec![small.hash], content.get_trans_hashes()[1..].to_vec());
+        let content = BlockAssembler::new(&state, 1, &params)
+            .with_limits(base + big.size(), BLOCK_SIZE_LIMIT)
+            .assemble(vec![&big, &small], &key);
+        assert_eq!(vec![big.hash], content.get_trans_hashes()[1..].to_vec());
+    }
+
+    #[test]
+    fn test_parents_first() {
+        let (key, addr, state, prev_hash) = setup();
+        let params = ChainParams::main();
+        // a low fee parent with a high fee child, and a child of a missing parent
+        let parent = generate_signed_transaction(&key, vec![TxInput::new(prev_hash, 0)], vec![TxOutput::new(addr, 99)]);
+        let child = generate_signed_transaction(&key, vec![TxInput::new(parent.hash, 0)], vec![TxOutput::new(addr, 50)]);
+        let other = generate_signed_transaction(&key, vec![TxInput::new(prev_hash, 1)], vec![TxOutput::new(addr, 90)]);
+        let orphan = generate_signed_transaction(&key, vec![TxInput::new(generate_random_hash(), 0)],
+                                                 vec![TxOutput::new(addr, 1)]);
+        let orphan_child = generate_signed_transaction(&key, vec![TxInput::new(orphan.hash, 0)], vec![]);
+        let content = BlockAssembler::new(&state, 1, &params)
+            .assemble(vec![&orphan_child, &child, &other, &orphan, &parent], &key);
+        assert_eq!(vec![other.hash, parent.hash, child.hash], content.get_trans_hashes()[1..].to_vec());
+        assert_eq!(COINBASE_REWARD + 60, content.trans[0].transaction.outputs[0].val);
+
+        // the whole block is valid
+        let header = generate_header(&generate_random_hash(), &content, 0, &generate_random_hash());
+        assert!(Block::new(header, content).try_generate_state(&state, 1, &params).is_ok());
+    }
+
+    #[test]
+    fn test_assemble_paying() {
+        let (key, addr, state, prev_hash) = setup();
+        let params = ChainParams::main();
+        let tran = generate_signed_transaction(&key, vec![TxInput::new(prev_hash, 0)], vec![TxOutput::new(addr, 90)]);
+        let miner_key = key_pair::random();
+        let miner_addr = generate_signed_coinbase_transaction(&miner_key).sender_addr();
+        let mut content = BlockAssembler::new(&state, 1, &params).assemble_paying(vec![&tran], &miner_addr);
+        assert_eq!(vec![tran.hash], content.get_trans_hashes()[1..].to_vec());
+        let coinbase = content.trans[0].clone();
+        assert_eq!(vec![TxOutput::new(miner_addr, COINBASE_REWARD + 10)], coinbase.transaction.outputs);
+        assert!(!coinbase.is_coinbase_tran());
+
+        // signing the coinbase keeps the merkle root, and makes the block valid
+        let merkle_root = content.merkle_root();
+        let signature: Box<[u8]> = sign(&coinbase.transaction, &miner_key).as_ref().into();
+        let public_key: Box<[u8]> = miner_key.public_key().as_ref().into();
+        content.trans[0] = SignedTransaction::new(coinbase.transaction, signature, public_key);
+        assert_eq!(merkle_root, content.merkle_root());
+        assert!(content.trans[0].is_coinbase_tran() && content.trans[0].sign_check());
+        let header = generate_header(&generate_random_hash(), &content, 0, &generate_random_hash());
+        assert!(Block::new(header, content).try_generate_state(&state, 1, &params).is_ok());
+    }
+}
@@ -1,18 +1,65 @@
-use std::collections::HashMap;
-use log::info;
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::time::{Duration, Instant, SystemTime};
+use log::{info, debug, error};
 
-use crate::block::{Block, Header, Content, State};
+use crate::block::{Block, BlockUndo, BlockValidationError, Header, Content, State};
 use crate::crypto::hash::H256;
+use crate::store::BlockStore;
+use crate::orphan_block_pool::OrphanBlockPool;
+use crate::chain_params::ChainParams;
+use crate::config::{RETARGET_INTERVAL, TARGET_BLOCK_INTERVAL, MAX_RETARGET_FACTOR,
+                    ORPHAN_POOL_SIZE_LIMIT, ORPHAN_BLOCK_EXPIRY, MEDIAN_TIME_SPAN,
+                    MAX_FUTURE_DRIFT, FUTURE_BLOCK_LIMIT, BLOCK_FETCH_TIMEOUT};
+
+// Change of the longest chain between two tips
+pub struct TipChange {
+    pub disconnected: Vec<Block>,  // from the old tip back to (excluding) the fork point
+    pub connected: Vec<Block>,     // from (excluding) the fork point up to the new tip
+}
+
+impl TipChange {
+    // The old tip is no longer in the longest chain
+    pub fn is_reorg(&self) -> bool {
+        !self.disconnected.is_empty()
+    }
+}
+
+// Whether signatures of a block were verified in validation
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum SignatureCheck {
+    Verified,
+    Skipped,  // the block is the assumed-valid one or its ancestor
+}
+
+// Entry of the header-only index
+struct HeaderNode {
+    header: Header,
+    index: usize,
+    work: u128,  // cumulative work from genesis
+}
 
 pub struct Blockchain {
     blocks: HashMap<H256, Block>,
-    orphans_map: HashMap<H256, Vec<Block>>, // key is the hash of the parent
-    orphans: HashMap<H256, Block>,
+    headers: HashMap<H256, HeaderNode>,  // headers of all blocks in chain plus those whose body is not here yet
+    best_header: H256,  // last header of the heaviest header chain
+    in_flight: HashMap<H256, Instant>,  // bodies requested in headers-first sync
+    orphans: OrphanBlockPool,
+    future_blocks: HashMap<H256, (Block, Option<SocketAddr>)>,  // valid blocks too far ahead of local time by now
     longest_hash: H256,
     max_index: usize,
-    difficulty: H256,  // assume difficulty is consistent
-    states: HashMap<H256, State>,
+    difficulty: H256,  // initial difficulty, used until the first retarget
+    utxo: State,  // live state at utxo_tip, which is the tip of the longest chain between insertions
+    utxo_tip: H256,
+    undo: HashMap<H256, BlockUndo>,  // for every block in chain except genesis, to move utxo across forks
     check_trans: bool,  // can only be false in test
+    store: Option<BlockStore>,  // none if blocks are kept in memory only
+    params: ChainParams,
+    assumed_valid_chain: HashSet<H256>,  // the assumed-valid block and its ancestors, once its header is indexed
+    skipped_signature_checks: usize,  // number of blocks accepted without verifying signatures
+    rejections: HashMap<SocketAddr, HashMap<&'static str, usize>>,  // rejected blocks of each peer by reason
 }
 
 impl Blockchain {
@@ -23,91 +70,394 @@ impl Blockchain {
         let difficulty = genesis.header.difficulty.clone();
         let longest_hash = genesis.get_hash();
         let mut map: HashMap<H256, Block> = HashMap::new();
-        let orphans_map: HashMap<H256, Vec<Block>> = HashMap::new();
+        let mut headers: HashMap<H256, HeaderNode> = HashMap::new();
+        headers.insert(genesis_hash, HeaderNode { header: genesis.header.clone(), index: 0, work: 0 });
         map.insert(genesis.get_hash(), genesis);
-        let mut states: HashMap<H256, State> = HashMap::new();
-        let genesis_state = State::new();
-        states.insert(genesis_hash, genesis_state);
         Self {
             blocks: map,
-            orphans_map,
-            orphans: HashMap::new(),
+            headers,
+            best_header: genesis_hash,
+            in_flight: HashMap::new(),
+            orphans: OrphanBlockPool::new(ORPHAN_POOL_SIZE_LIMIT,
+                                          Duration::from_millis(ORPHAN_BLOCK_EXPIRY)),
+            future_blocks: HashMap::new(),
             longest_hash,
             max_index: 0,
             difficulty,
-            states,
+            utxo: State::new(),
+            utxo_tip: genesis_hash,
+            undo: HashMap::new(),
             check_trans: true,
+            store: None,
+            params: ChainParams::main(),
+            assumed_valid_chain: HashSet::new(),
+            skipped_signature_checks: 0,
+            rejections: HashMap::new(),
         }
     }
 
+    // Open a blockchain persisted in dir, rebuilding the longest chain & states from stored blocks,
+    // failing with InvalidData if a stored block or the stored tip does not fit the rebuilt chain
+    pub fn open(dir: &Path) -> io::Result<Self> {
+        let mut store = BlockStore::open(dir)?;
+        let mut blockchain = Self::new();
+        // stored blocks were all valid and connected when written, so anything else means the store is corrupt
+        for block in store.load_blocks()?.iter() {
+            if let Err(e) = blockchain.insert(block) {
+                return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                          format!("stored block {:?} fails validation: {}", block.hash, e)));
+            }
+            if !blockchain.exist(&block.hash) {
+                return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                          format!("stored block {:?} has no stored parent", block.hash)));
+            }
+        }
+        if !store.is_empty() && store.tip() != Some(blockchain.tip()) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                      format!("stored tip {:?} differs from the rebuilt tip {:?}",
+                                              store.tip(), blockchain.tip())));
+        }
+        info!("Restored blockchain with length {} from {:?}", blockchain.length(), dir);
+        blockchain.store = Some(store);
+        Ok(blockchain)
+    }
+
     // Insert a block with existence & validation check (used in inter-miner blocks broadcast)
-    pub fn insert_with_check(&mut self, block: &Block) -> bool {
-        if self.exist(&block.hash) || !self.validate_block_meta(block) {
-            return false;
+    pub fn insert_with_check(&mut self, block: &Block) -> Result<(), BlockValidationError> {
+        self.insert_with_check_from(block, None)
+    }
+
+    // Same as insert_with_check, remembering the peer who supplied the block if it becomes an orphan
+    // and counting the peer's rejected blocks by reason
+    pub fn insert_with_check_from(&mut self, block: &Block, peer: Option<SocketAddr>) -> Result<(), BlockValidationError> {
+        self.in_flight.remove(&block.hash);
+        if self.exist(&block.hash) || self.future_blocks.contains_key(&block.hash) {
+            return Err(BlockValidationError::Duplicate);
         }
-        return self.insert(block);
+        let result = self.check_and_insert(block, peer);
+        if let Err(e) = result {
+            self.count_rejection(peer, &e);
+        }
+        result
+    }
+
+    fn check_and_insert(&mut self, block: &Block, peer: Option<SocketAddr>) -> Result<(), BlockValidationError> {
+        if let SignatureCheck::Skipped = self.validate_block(block)? {
+            debug!("Skip signature checks of assumed-valid block {:?}", block.hash);
+            self.skipped_signature_checks += 1;
+        }
+        if is_from_future(&block.header) {
+            if self.future_blocks.len() < FUTURE_BLOCK_LIMIT {
+                info!("Block {:?} is too far in the future, buffer it", block.hash);
+                self.future_blocks.insert(block.hash, (block.clone(), peer));
+            } else {
+                info!("Drop block {:?} from the future, buffer is full", block.hash);
+            }
+            return Err(BlockValidationError::TimestampTooFarAhead);
+        }
+        if !self.blocks.contains_key(&block.header.parent) {
+            self.orphans.insert(block.clone(), peer, true);
+            return Ok(());
+        }
+        self.insert(block)
+    }
+
+    // Count a rejected block of the peer; duplicates and blocks buffered for the future are not rejections
+    fn count_rejection(&mut self, peer: Option<SocketAddr>, e: &BlockValidationError) {
+        match (peer, e) {
+            (_, BlockValidationError::Duplicate) | (_, BlockValidationError::TimestampTooFarAhead) => {}
+            (Some(peer), e) => {
+                *self.rejections.entry(peer).or_default().entry(e.kind()).or_insert(0) += 1;
+            }
+            (None, _) => {}
+        }
+    }
+
+    // Number of blocks rejected from each peer, by reason
+    pub fn rejection_counts(&self) -> &HashMap<SocketAddr, HashMap<&'static str, usize>> {
+        &self.rejections
     }
 
     // Insert a block into blockchain if parent exists; otherwise, put it into orphan buffer
-    pub fn insert(&mut self, block: &Block) -> bool {
+    pub fn insert(&mut self, block: &Block) -> Result<(), BlockValidationError> {
         let mut b = block.clone();
         let parent_hash = &b.header.parent;
 
-        match self.blocks.get(parent_hash) {
-            Some(prev_block) => {
-                // validate transaction and generate new state
-                if let Some(new_state) = self.try_generate_new_state(block) {
-                    self.states.insert(block.hash.clone(), new_state);
-                } else {
-                    return false;
-                }
-                let cur_index = prev_block.index + 1;
+        match self.blocks.get(parent_hash).map(|prev_block| (prev_block.index, prev_block.work)) {
+            Some((prev_index, prev_work)) => {
+                // validate transaction and move utxo onto the new block
+                let undo = match self.try_apply_block(block, prev_index + 1) {
+                    Ok(undo) => undo,
+                    Err(e) => {
+                        info!("Reject block {:?}: {}", b.hash, e);
+                        self.drop_header_branch(&b.hash);
+                        return Err(e);
+                    }
+                };
+                let cur_index = prev_index + 1;
                 b.index = cur_index;
+                b.work = prev_work.saturating_add(b.header.work());
+                // persist before touching in-memory chain, so that a failed write leaves both untouched
+                if let Some(store) = self.store.as_mut() {
+                    if let Err(e) = store.put(&b) {
+                        error!("Failed to persist block {:?}: {}", b.hash, e);
+                        if self.check_trans {
+                            self.utxo.undo(&undo);
+                            self.utxo_tip = *parent_hash;
+                            self.move_utxo_to(&self.longest_hash.clone());
+                        }
+                        return Err(BlockValidationError::StoreFailed);
+                    }
+                }
+                if self.check_trans {
+                    self.undo.insert(b.hash, undo);
+                }
+                self.index_header(&b.header, b.hash, cur_index, b.work);
+                // choose the tip by heaviest chain; on a tie the first seen one is kept
                 let longest_block = self.blocks.get(&self.longest_hash).unwrap();
-                if cur_index > longest_block.index {
+                if b.work > longest_block.work {
+                    if b.header.parent != self.longest_hash {
+                        info!("Reorg: switch tip from {:?} (index {}) to {:?} (index {})",
+                              self.longest_hash, longest_block.index, b.hash, cur_index);
+                    }
                     self.longest_hash = b.hash.clone();
                     self.max_index = cur_index;
+                    if let Some(store) = self.store.as_mut() {
+                        if let Err(e) = store.set_tip(&self.longest_hash) {
+                            error!("Failed to persist tip {:?}: {}", self.longest_hash, e);
+                        }
+                    }
                 }
                 let new_parent_hash = b.hash.clone();
-                info!("Insert block with index {:?}: {:?}, nonce: {}, parent: {:?}",
-                      &b.index, &b.hash, b.header.nonce, parent_hash);
+                info!("Insert block with index {:?}: {:?}, nonce: {}, work: {}, parent: {:?}",
+                      &b.index, &b.hash, b.header.nonce, b.work, parent_hash);
 
                 self.blocks.insert(b.hash.clone(), b);
+                if self.check_trans {
+                    self.move_utxo_to(&self.longest_hash.clone());
+                }
                 info!("Length of longest chain is {:?}, Total number of blocks is {:?}", self.length(), self.blocks.len());
 
                 self.handle_orphan(&new_parent_hash);
             },
             None => {
-                self.orphans.insert(b.hash.clone(), b.clone());
-                match self.orphans_map.get_mut(parent_hash) {
-                    Some(children_vec) => {
-                        children_vec.push(b);
-                    },
-                    None => {
-                        let mut children_vec = Vec::<Block>::new();
-                        let parent_hash_copy = parent_hash.clone();
-                        children_vec.push(b);
-                        self.orphans_map.insert(parent_hash_copy, children_vec);
-                    }
-                }
+                self.orphans.insert(b, None, false);
+            }
+        }
+        Ok(())
+    }
+
+    // Add a header to the header-only index after checking PoW, difficulty and timestamp against
+    // its parent, which must be indexed already. Return false if the header is invalid or unconnected
+    pub fn insert_header(&mut self, header: &Header) -> bool {
+        let hash = header.hash();
+        if self.headers.contains_key(&hash) {
+            return true;
+        }
+        let (parent_index, parent_work) = match self.headers.get(&header.parent) {
+            Some(parent) => (parent.index, parent.work),
+            None => return false,
+        };
+        if header.pow_hash() >= header.difficulty
+            || self.expected_difficulty(&header.parent) != Some(header.difficulty)
+            || self.median_time_past(&header.parent).unwrap() >= header.timestamp
+            || is_from_future(header)
+            || !self.validate_checkpoint(&hash, &header.parent) {
+            info!("Reject invalid header {:?}", hash);
+            return false;
+        }
+        self.index_header(header, hash, parent_index + 1, parent_work.saturating_add(header.work()));
+        true
+    }
+
+    fn index_header(&mut self, header: &Header, hash: H256, index: usize, work: u128) {
+        if self.headers.contains_key(&hash) {
+            return;
+        }
+        self.headers.insert(hash, HeaderNode { header: header.clone(), index, work });
+        if work > self.headers.get(&self.best_header).unwrap().work {
+            self.best_header = hash;
+        }
+        if self.params.assumed_valid == Some(hash) {
+            self.index_assumed_valid_chain();
+        }
+    }
+
+    // Collect the assumed-valid block and its ancestors, if its header is indexed
+    fn index_assumed_valid_chain(&mut self) {
+        self.assumed_valid_chain.clear();
+        let mut hash = match self.params.assumed_valid {
+            Some(hash) if self.headers.contains_key(&hash) => hash,
+            _ => return,
+        };
+        loop {
+            self.assumed_valid_chain.insert(hash);
+            let node = self.headers.get(&hash).unwrap();
+            if node.index == 0 {
+                break;
+            }
+            hash = node.header.parent;
+        }
+        info!("Assume {} blocks up to {:?} valid", self.assumed_valid_chain.len(), self.params.assumed_valid.unwrap());
+    }
+
+    // Replace the chain parameters; blocks already in chain are not checked again
+    pub fn set_chain_params(&mut self, params: ChainParams) {
+        self.params = params;
+        self.index_assumed_valid_chain();
+    }
+
+    pub fn chain_params(&self) -> &ChainParams {
+        &self.params
+    }
+
+    // Coins issued by the subsidies of all blocks in longest chain
+    pub fn issued_supply(&self) -> u64 {
+        self.params.total_supply(self.max_index)
+    }
+
+    // Number of blocks accepted without verifying their signatures since they were assumed valid
+    pub fn skipped_signature_checks(&self) -> usize {
+        self.skipped_signature_checks
+    }
+
+    // Remove an indexed header whose block turned out invalid, along with all its descendants
+    fn drop_header_branch(&mut self, hash: &H256) {
+        let index = match self.headers.get(hash) {
+            Some(node) => node.index,
+            None => return,
+        };
+        let mut descendants: Vec<(usize, H256)> = self.headers.iter()
+            .filter(|(_, node)| node.index > index)
+            .map(|(h, node)| (node.index, *h))
+            .collect();
+        descendants.sort();
+        let mut dropped: HashSet<H256> = HashSet::new();
+        dropped.insert(*hash);
+        for (_, h) in descendants.iter() {
+            if dropped.contains(&self.headers.get(h).unwrap().header.parent) {
+                dropped.insert(*h);
             }
         }
-        return true;
+        for h in dropped.iter() {
+            self.headers.remove(h);
+            self.in_flight.remove(h);
+        }
+        if !self.headers.contains_key(&self.best_header) {
+            let tip_work = self.headers.get(&self.longest_hash).unwrap().work;
+            self.best_header = self.headers.iter()
+                .filter(|(_, node)| node.work > tip_work)
+                .max_by_key(|(_, node)| node.work)
+                .map_or(self.longest_hash, |(h, _)| *h);
+        }
+        info!("Drop {} headers from invalid block {:?}", dropped.len(), hash);
+    }
+
+    pub fn has_header(&self, hash: &H256) -> bool {
+        self.headers.contains_key(hash)
+    }
+
+    // Get the last header of the heaviest header chain, which may be ahead of the tip
+    pub fn best_header(&self) -> H256 {
+        self.best_header
+    }
+
+    // Block locator of the best header chain: the last 10 hashes one by one, then exponentially
+    // spaced ones back to genesis, which is always the last
+    pub fn locator(&self) -> Vec<H256> {
+        let mut locator = Vec::new();
+        let mut cur = self.headers.get(&self.best_header).unwrap();
+        let mut hash = self.best_header;
+        let mut step = 1;
+        loop {
+            locator.push(hash);
+            if cur.index == 0 {
+                break;
+            }
+            if locator.len() >= 10 {
+                step *= 2;
+            }
+            let target = cur.index.saturating_sub(step);
+            while cur.index > target {
+                hash = cur.header.parent;
+                cur = self.headers.get(&hash).unwrap();
+            }
+        }
+        locator
+    }
+
+    // Headers of the longest chain after the first locator hash found in it (genesis if none)
+    pub fn headers_after(&self, locator: &[H256], limit: usize) -> Vec<Header> {
+        let mut chain = self.hash_chain();
+        chain.reverse();
+        let start = locator.iter()
+            .filter_map(|h| self.blocks.get(h))
+            .find(|b| chain.get(b.index) == Some(&b.hash))
+            .map_or(0, |b| b.index);
+        chain.iter().skip(start + 1).take(limit)
+            .map(|h| self.blocks.get(h).unwrap().header.clone())
+            .collect()
+    }
+
+    // Hashes of blocks on the best header chain whose body is still missing, oldest first. Bodies
+    // requested within BLOCK_FETCH_TIMEOUT are skipped, and the returned ones are marked requested
+    pub fn blocks_to_fetch(&mut self, limit: usize) -> Vec<H256> {
+        let mut missing = Vec::new();
+        let mut hash = self.best_header;
+        while !self.blocks.contains_key(&hash) {
+            missing.push(hash);
+            hash = self.headers.get(&hash).unwrap().header.parent;
+        }
+        missing.reverse();
+        let timeout = Duration::from_millis(BLOCK_FETCH_TIMEOUT);
+        let in_flight = &self.in_flight;
+        let to_fetch: Vec<H256> = missing.into_iter()
+            .filter(|h| !self.orphans.contains(h) && !self.future_blocks.contains_key(h))
+            .filter(|h| in_flight.get(h).is_none_or(|t| t.elapsed() > timeout))
+            .take(limit)
+            .collect();
+        let now = Instant::now();
+        for h in to_fetch.iter() {
+            self.in_flight.insert(*h, now);
+        }
+        to_fetch
+    }
+
+    // Re-evaluate buffered future blocks against local time, return hashes of the newly accepted ones
+    pub fn retry_future_blocks(&mut self) -> Vec<H256> {
+        let mut ready: Vec<Block> = self.future_blocks.values()
+            .filter(|(b, _)| !is_from_future(&b.header))
+            .map(|(b, _)| b.clone())
+            .collect();
+        ready.sort_by_key(|b| b.header.timestamp);
+        let mut accepted = Vec::new();
+        for block in ready.iter() {
+            let (_, peer) = self.future_blocks.remove(&block.hash).unwrap();
+            if self.insert_with_check_from(block, peer).is_ok() {
+                accepted.push(block.hash);
+            }
+        }
+        accepted
     }
 
     // Deal with a newly-arrived parent block's orphans
     fn handle_orphan(&mut self, new_parent: &H256) {
-        if let Some(children_vec) = self.orphans_map.remove(new_parent) {
-            for child in children_vec.iter() {
-                self.orphans.remove(&child.hash);
-                self.insert(child);
+        for child in self.orphans.remove_children(new_parent) {
+            let result = if child.unchecked {
+                self.validate_against_parent(&child.block)
+            } else {
+                Ok(())
+            };
+            if let Err(e) = result.and_then(|_| self.insert(&child.block)) {
+                info!("Drop orphan {:?}: {}", child.block.hash, e);
+                self.count_rejection(child.peer, &e);
             }
         }
     }
 
     // Check if a block is orphan
     pub fn is_orphan(&self, hash: &H256) -> bool {
-        self.orphans.contains_key(hash)
+        self.orphans.contains(hash)
     }
 
     // Trace back the very-first missing block of a block's hash
@@ -116,8 +466,8 @@ impl Blockchain {
             return None
         }
         let mut cur = orphan_hash;
-        while self.orphans.contains_key(&cur) {
-            cur = &self.orphans.get(cur).unwrap().header.parent;
+        while let Some(orphan) = self.orphans.get(cur) {
+            cur = &orphan.header.parent;
         }
         Some(cur.clone())
     }
@@ -127,20 +477,210 @@ impl Blockchain {
         if !self.check_trans {
             return Some(State::new());  // skip in test
         }
-        let parent_state = self.states.get(&block.header.parent).unwrap();
-        return block.try_generate_state(parent_state);
+        let parent_state = self.state_at(&block.header.parent)?;
+        let height = self.blocks.get(&block.header.parent)?.index + 1;
+        block.try_generate_state(&parent_state, height, &self.params).ok()
+    }
+
+    // Validate transactions of a block whose parent is in chain, leaving utxo at the block on success
+    // and at the longest chain's tip on failure
+    fn try_apply_block(&mut self, block: &Block, height: usize) -> Result<BlockUndo, BlockValidationError> {
+        if !self.check_trans {
+            return Ok(BlockUndo::default());  // skip in test
+        }
+        self.move_utxo_to(&block.header.parent);
+        match block.try_apply(&mut self.utxo, height, &self.params) {
+            Ok(undo) => {
+                self.utxo_tip = block.hash;
+                Ok(undo)
+            }
+            Err(e) => {
+                self.move_utxo_to(&self.longest_hash.clone());
+                Err(e)
+            }
+        }
+    }
+
+    // Roll utxo back to the fork point of utxo_tip and target, then forward to target
+    fn move_utxo_to(&mut self, target: &H256) {
+        if self.utxo_tip == *target {
+            return;
+        }
+        let (disconnected, connected) = self.path(&self.utxo_tip, target);
+        for hash in disconnected.iter() {
+            self.utxo.undo(self.undo.get(hash).unwrap());
+        }
+        for hash in connected.iter() {
+            self.utxo.redo(self.undo.get(hash).unwrap());
+        }
+        debug!("Move utxo from {:?} to {:?}: {} blocks rolled back, {} rolled forward",
+               self.utxo_tip, target, disconnected.len(), connected.len());
+        self.utxo_tip = *target;
     }
 
-    // Perform validation checks on PoW & difficulty & all transactions within it
-    pub fn validate_block_meta(&self, block: &Block) -> bool {
+    // Rebuild the state at any block in chain (including forks) from the live one
+    pub fn state_at(&self, hash: &H256) -> Option<State> {
+        if !self.blocks.contains_key(hash) {
+            return None;
+        }
+        if !self.check_trans {
+            return Some(State::new());
+        }
+        let mut state = self.utxo.clone();
+        let (disconnected, connected) = self.path(&self.utxo_tip, hash);
+        for h in disconnected.iter() {
+            state.undo(self.undo.get(h).unwrap());
+        }
+        for h in connected.iter() {
+            state.redo(self.undo.get(h).unwrap());
+        }
+        Some(state)
+    }
+
+    // Hashes from `from` back to (excluding) the fork point, and from (excluding) the fork point to `to`
+    fn path(&self, from: &H256, to: &H256) -> (Vec<H256>, Vec<H256>) {
+        let mut backward = Vec::<H256>::new();
+        let mut forward = Vec::<H256>::new();
+        let mut old = self.blocks.get(from).unwrap();
+        let mut new = self.blocks.get(to).unwrap();
+        while old.hash != new.hash {
+            if old.index >= new.index {
+                backward.push(old.hash);
+                old = self.blocks.get(&old.header.parent).unwrap();
+            } else {
+                forward.push(new.hash);
+                new = self.blocks.get(&new.header.parent).unwrap();
+            }
+        }
+        forward.reverse();
+        (backward, forward)
+    }
+
+    // Perform validation checks on PoW & difficulty & timestamp & checkpoints & all transactions within it
+    // Difficulty, median time past and checkpoints of an orphan can only be checked when its parent arrives,
+    // while the future drift is checked separately since such a block may become valid later
+    pub fn validate_block_meta(&self, block: &Block) -> Result<(), BlockValidationError> {
+        self.validate_block(block).map(|_| ())
+    }
+
+    // Same checks as validate_block_meta, but report whether signatures were verified or skipped
+    // for an assumed-valid block
+    pub fn validate_block(&self, block: &Block) -> Result<SignatureCheck, BlockValidationError> {
         let header_hash = block.header.hash();
-        if header_hash == block.hash
-            && block.header.difficulty == self.difficulty
-            && header_hash < self.difficulty
-            && block.validate_signature() {
-            return true;
+        if header_hash != block.hash {
+            return Err(BlockValidationError::HashMismatch);
+        }
+        if block.header.pow_hash() >= block.header.difficulty {
+            return Err(BlockValidationError::InvalidPow);
+        }
+        if block.content.trans.is_empty() {
+            return Err(BlockValidationError::MissingCoinbase);
+        }
+        if !block.validate_merkle_root() {
+            return Err(BlockValidationError::MerkleRootMismatch);
+        }
+        self.validate_against_parent(block)?;
+        if self.assumed_valid_chain.contains(&block.hash) {
+            return Ok(SignatureCheck::Skipped);
+        }
+        block.validate_signature()?;
+        Ok(SignatureCheck::Verified)
+    }
+
+    // Checks that depend on the parent: difficulty, checkpoints and median time past (ok if parent is unknown)
+    fn validate_against_parent(&self, block: &Block) -> Result<(), BlockValidationError> {
+        if !self.validate_difficulty(block) {
+            return Err(BlockValidationError::WrongDifficulty);
+        }
+        if !self.validate_checkpoint(&block.hash, &block.header.parent) {
+            return Err(BlockValidationError::CheckpointMismatch);
+        }
+        if !self.validate_timestamp(block) {
+            return Err(BlockValidationError::TimestampTooEarly);
+        }
+        Ok(())
+    }
+
+    // Check the hash against the checkpoint at its height, if any (true if parent is unknown)
+    fn validate_checkpoint(&self, hash: &H256, parent_hash: &H256) -> bool {
+        let height = match self.headers.get(parent_hash) {
+            Some(parent) => parent.index + 1,
+            None => return true,
+        };
+        match self.params.checkpoint(height) {
+            Some(checkpoint) => checkpoint == *hash,
+            None => true,
+        }
+    }
+
+    // Check the block's difficulty against the expected one on its own fork (true if parent is unknown)
+    fn validate_difficulty(&self, block: &Block) -> bool {
+        match self.expected_difficulty(&block.header.parent) {
+            Some(difficulty) => block.header.difficulty == difficulty,
+            None => true,
+        }
+    }
+
+    // Check the block's timestamp is above the median time past of its parent (true if parent is unknown)
+    fn validate_timestamp(&self, block: &Block) -> bool {
+        match self.median_time_past(&block.header.parent) {
+            Some(median) => block.header.timestamp > median,
+            None => true,
+        }
+    }
+
+    // Get the median timestamp of the last MEDIAN_TIME_SPAN blocks ending at the given one,
+    // None if its header is not indexed
+    pub fn median_time_past(&self, hash: &H256) -> Option<u64> {
+        let mut cur = self.headers.get(hash)?;
+        let mut timestamps = vec![cur.header.timestamp];
+        while timestamps.len() < MEDIAN_TIME_SPAN && cur.index > 0 {
+            cur = self.headers.get(&cur.header.parent).unwrap();
+            timestamps.push(cur.header.timestamp);
+        }
+        timestamps.sort_unstable();
+        Some(timestamps[timestamps.len() / 2])
+    }
+
+    // Get the difficulty that a child of the given block must have, None if its header is not indexed
+    // The difficulty is retargeted every RETARGET_INTERVAL blocks based on timestamps of the last
+    // window on that fork (genesis excluded, since its timestamp is fixed), and kept in between
+    pub fn expected_difficulty(&self, parent_hash: &H256) -> Option<H256> {
+        let parent = self.headers.get(parent_hash)?;
+        let height = parent.index + 1;
+        if height < RETARGET_INTERVAL {
+            return Some(self.difficulty);
+        }
+        if height % RETARGET_INTERVAL != 0 {
+            return Some(parent.header.difficulty);
+        }
+
+        let start_height = std::cmp::max(height - RETARGET_INTERVAL, 1);
+        let intervals = (parent.index - start_height) as u64;
+        if intervals == 0 {
+            return Some(parent.header.difficulty);
+        }
+        let mut first = parent;
+        while first.index > start_height {
+            first = self.headers.get(&first.header.parent).unwrap();
+        }
+
+        let expected_span = intervals * TARGET_BLOCK_INTERVAL;
+        let actual_span = parent.header.timestamp.saturating_sub(first.header.timestamp);
+        let actual_span = std::cmp::min(std::cmp::max(actual_span, expected_span / MAX_RETARGET_FACTOR),
+                                        expected_span * MAX_RETARGET_FACTOR);
+        let difficulty = parent.header.difficulty.mul_div(actual_span, expected_span);
+        debug!("Retarget difficulty at height {}: {:?} -> {:?}", height, parent.header.difficulty, difficulty);
+        Some(difficulty)
+    }
+
+    // Get blocks disconnected from & connected to the longest chain since it ended at old_tip
+    pub fn tip_change(&self, old_tip: &H256) -> TipChange {
+        let (disconnected, connected) = self.path(old_tip, &self.longest_hash);
+        TipChange {
+            disconnected: disconnected.iter().map(|h| self.blocks.get(h).unwrap().clone()).collect(),
+            connected: connected.iter().map(|h| self.blocks.get(h).unwrap().clone()).collect(),
         }
-        return false;
     }
 
     // Get the last block's hash of the longest chain
@@ -150,7 +690,12 @@ impl Blockchain {
 
     // Get state of the longest chain(tip)
     pub fn tip_block_state(&self) -> State {
-        self.states.get(&self.longest_hash).unwrap().clone()
+        self.utxo.clone()
+    }
+
+    // Same as tip_block_state, without copying the state
+    pub fn tip_state(&self) -> &State {
+        &self.utxo
     }
 
     // include genesis block
@@ -158,14 +703,24 @@ impl Blockchain {
         self.max_index + 1
     }
 
+    // Get difficulty of the next block on the longest chain
     pub fn difficulty(&self) -> H256 {
-        self.difficulty.clone()
+        self.expected_difficulty(&self.longest_hash).unwrap()
+    }
+
+    // Number of orphans in total and supplied by each peer
+    pub fn orphan_count(&self) -> usize {
+        self.orphans.len()
+    }
+
+    pub fn orphan_count_by_peer(&self) -> HashMap<SocketAddr, usize> {
+        self.orphans.count_by_peer()
     }
 
-    // check existence, including orphans_map
+    // check existence, including orphans
     pub fn exist(&self, hash: &H256) -> bool {
         self.blocks.contains_key(hash)
-            || self.orphans.contains_key(hash)
+            || self.orphans.contains(hash)
     }
 
     // Given hashes, get blocks from chain & orphan buffer
@@ -234,6 +789,14 @@ impl Blockchain {
         content_chain
     }
 
+    // Get the transaction fees of each block in longest-chain from tip to genesis, in the order of content_chain
+    // Empty for genesis, or if transactions are not checked
+    pub fn fee_chain(&self) -> Vec<Vec<u64>> {
+        self.hash_chain().iter()
+            .map(|h| self.undo.get(h).map(|u| u.fees.clone()).unwrap_or_default())
+            .collect()
+    }
+
     #[cfg(any(test, test_utilities))]
     pub fn all_blocks_in_longest_chain(&self) -> Vec<H256> {
         let mut cur_hash = self.tip();
@@ -249,6 +812,7 @@ impl Blockchain {
         result
     }
 
+    // Change the initial difficulty (only blocks before the first retarget are affected)
     pub fn change_difficulty(&mut self, difficulty: &H256) {
         self.difficulty = difficulty.clone();
     }
@@ -265,6 +829,13 @@ impl Blockchain {
     }
 }
 
+// Check if a header's timestamp is more than MAX_FUTURE_DRIFT ahead of local time
+pub fn is_from_future(header: &Header) -> bool {
+    let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap().as_millis() as u64;
+    header.timestamp > now + MAX_FUTURE_DRIFT
+}
+
 #[cfg(any(test, test_utilities))]
 mod tests {
     use super::*;
@@ -273,6 +844,10 @@ mod tests {
     use crate::spread::Spreader;
     use crate::crypto::key_pair;
     use crate::network::message::Message;
+    use crate::transaction::{SignedTransaction, TxInput, TxOutput};
+    use crate::config::{EASIEST_DIF, TEST_DIF, MEDIAN_TIME_SPAN, MAX_FUTURE_DRIFT, COINBASE_REWARD};
+    use crate::miner::mining_base;
+    use crate::chain_params::ChainParams;
 
     use std::net::{SocketAddr, IpAddr, Ipv4Addr};
     use std::time;
@@ -285,10 +860,10 @@ mod tests {
         let genesis_hash = blockchain.tip();
         assert_eq!(&genesis_hash, &H256::from([0u8; 32]));
         let block = generate_random_block(&genesis_hash);
-        blockchain.insert(&block);
+        blockchain.insert(&block).unwrap();
         assert_eq!(blockchain.tip(), block.hash());
         assert_eq!(blockchain.tip_difficulty(), block.header.difficulty);
-        assert!(!blockchain.insert_with_check(&block));
+        assert!(blockchain.insert_with_check(&block).is_err());
 
         let mut blockchain = Blockchain::new();
         let key = key_pair::random();
@@ -296,13 +871,459 @@ mod tests {
         let content = Content::new_with_trans(&vec![signed_coinbase_tran.clone()]);
         let header = generate_header(&genesis_hash, &content, 0, &generate_random_hash());
         let block = Block::new(header, content);
-        assert!(blockchain.insert(&block));
+        assert!(blockchain.insert(&block).is_ok());
 
         let invalid_signed_tran = generate_random_signed_transaction();
         let content = Content::new_with_trans(&vec![invalid_signed_tran.clone()]);
         let header = generate_header(&block.hash, &content, 0, &generate_random_hash());
         let block = Block::new(header, content);
-        assert!(!blockchain.insert(&block));
+        assert!(blockchain.insert(&block).is_err());
+    }
+
+    #[test]
+    fn test_open() {
+        /*
+         * structure:
+         * genesis <- block_1 <- block_2 <- block_3
+         *              ^
+         *              ------  fork_2
+         */
+        let dir = crate::store::tests::temp_data_dir();
+        let key = key_pair::random();
+        let mut blockchain = Blockchain::open(&dir).unwrap();
+        let mut blocks = Vec::<Block>::new();
+        let mut parent = blockchain.tip();
+        for _ in 0..3 {
+            let content = Content::new_with_trans(&vec![generate_signed_coinbase_transaction(&key)]);
+            let header = generate_header(&parent, &content, 0, &blockchain.difficulty());
+            let block = Block::new(header, content);
+            assert!(blockchain.insert(&block).is_ok());
+            parent = block.hash;
+            blocks.push(block);
+        }
+        let content = Content::new_with_trans(&vec![generate_signed_coinbase_transaction(&key)]);
+        let header = generate_header(&blocks[0].hash, &content, 0, &blockchain.difficulty());
+        let fork_2 = Block::new(header, content);
+        assert!(blockchain.insert(&fork_2).is_ok());
+        // orphans are not persisted
+        let orphan = generate_random_block(&generate_random_hash());
+        blockchain.insert(&orphan).unwrap();
+        let tip_state = blockchain.tip_block_state();
+        drop(blockchain);
+
+        let blockchain = Blockchain::open(&dir).unwrap();
+        assert_eq!(blocks[2].hash, blockchain.tip());
+        assert_eq!(4, blockchain.length());
+        assert!(blockchain.exist(&fork_2.hash));
+        assert!(!blockchain.exist(&orphan.hash));
+        assert_eq!(tip_state.0, blockchain.tip_block_state().0);
+        drop(blockchain);
+
+        // a tip pointer that does not match the stored blocks is an error, not silently rewritten
+        BlockStore::open(&dir).unwrap().set_tip(&blocks[1].hash).unwrap();
+        assert_eq!(io::ErrorKind::InvalidData, Blockchain::open(&dir).err().unwrap().kind());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_retarget() {
+        /*
+         * structure:
+         * genesis <- block_1 <- slow blocks ... <- slow_tip (height RETARGET_INTERVAL - 1)
+         *              ^
+         *              ------  fast blocks ... <- fast_tip (height RETARGET_INTERVAL - 1)
+         */
+        let mut blockchain = Blockchain::new();
+        blockchain.set_check_trans(false);
+        let difficulty: H256 = gen_difficulty_array(8).into();
+        blockchain.change_difficulty(&difficulty);
+        let build_fork = |blockchain: &mut Blockchain, parent: &H256, interval: u64| -> H256 {
+            let mut parent = *parent;
+            let mut ts = blockchain.get_block(&parent).unwrap().header.timestamp;
+            while blockchain.get_block(&parent).unwrap().index < RETARGET_INTERVAL - 1 {
+                ts += interval;
+                let content = generate_random_content();
+                assert_eq!(Some(difficulty), blockchain.expected_difficulty(&parent));
+                let header = Header::new(&parent, 0, ts as u128, &difficulty, &content.merkle_root());
+                let block = Block::new(header, content);
+                assert!(blockchain.insert(&block).is_ok());
+                parent = block.hash;
+            }
+            parent
+        };
+        let block_1 = generate_mined_block(&blockchain.tip(), &difficulty);
+        assert!(blockchain.insert_with_check(&block_1).is_ok());
+        let slow_tip = build_fork(&mut blockchain, &block_1.hash, 2 * TARGET_BLOCK_INTERVAL);
+        let fast_tip = build_fork(&mut blockchain, &block_1.hash, TARGET_BLOCK_INTERVAL / 2);
+
+        let slow_difficulty = difficulty.mul_div(2, 1);
+        let fast_difficulty = difficulty.mul_div(1, 2);
+        assert_eq!(Some(slow_difficulty), blockchain.expected_difficulty(&slow_tip));
+        assert_eq!(Some(fast_difficulty), blockchain.expected_difficulty(&fast_tip));
+        assert_eq!(slow_tip, blockchain.tip());
+        assert_eq!(slow_difficulty, blockchain.difficulty());
+
+        // fork timestamps run ahead of local time, so children are mined right after their parents
+        let mine_on = |parent: &Block, difficulty: &H256| -> Block {
+            let content = generate_random_content();
+            let ts = parent.header.timestamp + 1;
+            let mut header = Header::new(&parent.hash, 0, ts as u128, difficulty, &content.merkle_root());
+            assert!(mining_base(&mut header, *difficulty));
+            Block::new(header, content)
+        };
+        let slow_tip = blockchain.get_block(&slow_tip).unwrap();
+        let fast_tip = blockchain.get_block(&fast_tip).unwrap();
+
+        // each fork is checked against its own target
+        let block = mine_on(&slow_tip, &slow_difficulty);
+        assert!(blockchain.insert_with_check(&block).is_ok());
+        let block = mine_on(&fast_tip, &slow_difficulty);
+        assert!(blockchain.validate_block_meta(&block).is_err());
+        let block = mine_on(&fast_tip, &fast_difficulty);
+        assert!(blockchain.insert_with_check(&block).is_ok());
+
+        // the harder block makes the fast fork heavier, and difficulty is kept within a window
+        assert_eq!(block.hash, blockchain.tip());
+        assert_eq!(fast_difficulty, blockchain.difficulty());
+
+        // an orphan with wrong difficulty is dropped once its parent arrives
+        let parent = mine_on(&block, &fast_difficulty);
+        let orphan = mine_on(&parent, &difficulty);
+        assert!(blockchain.insert_with_check(&orphan).is_ok());
+        assert!(blockchain.insert_with_check(&parent).is_ok());
+        assert!(!blockchain.exist(&orphan.hash));
+        assert_eq!(parent.hash, blockchain.tip());
+    }
+
+    #[test]
+    fn test_timestamp() {
+        let mut blockchain = Blockchain::new();
+        blockchain.set_check_trans(false);
+        let difficulty: H256 = gen_difficulty_array(TEST_DIF).into();
+        blockchain.change_difficulty(&difficulty);
+        let mine_at = |parent: &H256, ts: u64| -> Block {
+            let content = generate_random_content();
+            let mut header = Header::new(parent, 0, ts as u128, &difficulty, &content.merkle_root());
+            assert!(mining_base(&mut header, difficulty));
+            Block::new(header, content)
+        };
+        for _ in 0..MEDIAN_TIME_SPAN {
+            let block = generate_mined_block(&blockchain.tip(), &difficulty);
+            assert!(blockchain.insert_with_check(&block).is_ok());
+        }
+
+        // the timestamp has to be strictly above the median time past
+        let tip = blockchain.tip();
+        let median = blockchain.median_time_past(&tip).unwrap();
+        assert!(median < blockchain.get_block(&tip).unwrap().header.timestamp);
+        assert!(blockchain.insert_with_check(&mine_at(&tip, median)).is_err());
+        assert!(blockchain.insert_with_check(&mine_at(&tip, median - 1)).is_err());
+        assert_eq!(tip, blockchain.tip());
+
+        // a block too far ahead is buffered until local time catches up
+        let now = generate_increasing_timestamp() as u64;
+        let future = mine_at(&tip, now + MAX_FUTURE_DRIFT + 200);
+        assert!(blockchain.insert_with_check(&future).is_err());
+        assert!(!blockchain.exist(&future.hash));
+        assert!(blockchain.insert_with_check(&future).is_err());
+        assert!(blockchain.retry_future_blocks().is_empty());
+        thread::sleep(time::Duration::from_millis(400));
+        assert_eq!(vec![future.hash], blockchain.retry_future_blocks());
+        assert_eq!(future.hash, blockchain.tip());
+
+        // a late orphan is dropped once its parent arrives
+        let parent = mine_at(&future.hash, now + MAX_FUTURE_DRIFT + 300);
+        let orphan = mine_at(&parent.hash, median);
+        assert!(blockchain.insert_with_check(&orphan).is_ok());
+        thread::sleep(time::Duration::from_millis(200));
+        assert!(blockchain.insert_with_check(&parent).is_ok());
+        assert!(!blockchain.exist(&orphan.hash));
+        assert_eq!(parent.hash, blockchain.tip());
+    }
+
+    #[test]
+    fn test_headers_first() {
+        let difficulty: H256 = gen_difficulty_array(TEST_DIF).into();
+        let mut source = Blockchain::new();
+        let mut syncing = Blockchain::new();
+        for blockchain in [&mut source, &mut syncing].iter_mut() {
+            blockchain.set_check_trans(false);
+            blockchain.change_difficulty(&difficulty);
+        }
+        let mut blocks = Vec::new();
+        for _ in 0..RETARGET_INTERVAL - 1 {
+            let block = generate_mined_block(&source.tip(), &difficulty);
+            assert!(source.insert_with_check(&block).is_ok());
+            blocks.push(block);
+        }
+
+        // locator is dense near the best header and ends with genesis
+        let locator = source.locator();
+        assert_eq!(blocks[18].hash, locator[0]);
+        assert_eq!(blocks[9].hash, locator[9]);
+        assert_eq!(blocks[7].hash, locator[10]);
+        assert_eq!(Block::genesis().hash, *locator.last().unwrap());
+
+        // only headers after the last common block are served
+        assert_eq!(19, source.headers_after(&syncing.locator(), 100).len());
+        let headers = source.headers_after(&[generate_random_hash(), blocks[9].hash], 5);
+        assert_eq!(5, headers.len());
+        assert_eq!(blocks[10].hash, headers[0].hash());
+
+        // the syncing node indexes the header chain before any body arrives
+        let headers = source.headers_after(&syncing.locator(), 12);
+        assert!(!syncing.insert_header(&headers[1]));
+        for header in headers.iter() {
+            assert!(syncing.insert_header(header));
+        }
+        assert_eq!(blocks[11].hash, syncing.best_header());
+        assert_eq!(blocks[11].hash, syncing.locator()[0]);
+        assert_eq!(1, syncing.length());
+        let content = generate_random_content();
+        let easiest: H256 = gen_difficulty_array(EASIEST_DIF).into();
+        let mut wrong_difficulty = Header::new(&blocks[11].hash, 0, generate_increasing_timestamp(),
+                                               &easiest, &content.merkle_root());
+        assert!(mining_base(&mut wrong_difficulty, easiest));
+        assert!(!syncing.insert_header(&wrong_difficulty));
+
+        // bodies are handed out once, oldest first, and the chain follows the headers
+        let to_fetch = syncing.blocks_to_fetch(8);
+        assert_eq!(blocks[0..8].iter().map(|b| b.hash).collect::<Vec<H256>>(), to_fetch);
+        assert_eq!(vec![blocks[8].hash], syncing.blocks_to_fetch(1));
+        assert_eq!(3, syncing.blocks_to_fetch(100).len());
+        for block in blocks[0..12].iter().rev() {
+            assert!(syncing.insert_with_check(block).is_ok());
+        }
+        assert_eq!(blocks[11].hash, syncing.tip());
+        assert!(syncing.blocks_to_fetch(100).is_empty());
+    }
+
+    #[test]
+    fn test_checkpoints_and_assumed_valid() {
+        let difficulty: H256 = gen_difficulty_array(TEST_DIF).into();
+        let mut source = Blockchain::new();
+        source.set_check_trans(false);
+        source.change_difficulty(&difficulty);
+        let mut blocks = Vec::new();
+        for i in 0..4 {
+            let mut content = generate_random_content();
+            if i == 1 {
+                // signature of another transaction
+                content.trans[0].signature = content.trans[1].signature.clone();
+            }
+            let mut header = Header::new(&source.tip(), 0, generate_increasing_timestamp(),
+                                         &difficulty, &content.merkle_root());
+            assert!(mining_base(&mut header, difficulty));
+            let block = Block::new(header, content);
+            assert!(source.insert(&block).is_ok());
+            blocks.push(block);
+        }
+        assert!(source.validate_block_meta(&blocks[1]).is_err());
+
+        let params = ChainParams {
+            checkpoints: vec![(0, Block::genesis().hash), (2, blocks[1].hash)],
+            assumed_valid: Some(blocks[2].hash),
+            ..ChainParams::main()
+        };
+        let mut blockchain = Blockchain::new();
+        blockchain.set_check_trans(false);
+        blockchain.change_difficulty(&difficulty);
+        blockchain.set_chain_params(params);
+
+        // a block conflicting with the checkpoint at height 2 is rejected, as is its header
+        let conflicting = generate_mined_block(&blocks[0].hash, &difficulty);
+        assert!(blockchain.insert_with_check(&blocks[0]).is_ok());
+        assert!(!blockchain.insert_header(&conflicting.header));
+        assert!(blockchain.insert_with_check(&conflicting).is_err());
+        assert!(!blockchain.exist(&conflicting.hash));
+
+        // signatures are verified until the assumed-valid header is known
+        assert_eq!(Ok(SignatureCheck::Verified), blockchain.validate_block(&blocks[2]));
+        for block in blocks.iter() {
+            assert!(blockchain.insert_header(&block.header));
+        }
+        assert_eq!(Ok(SignatureCheck::Skipped), blockchain.validate_block(&blocks[1]));
+        assert_eq!(Ok(SignatureCheck::Verified), blockchain.validate_block(&blocks[3]));
+        for block in blocks[1..].iter() {
+            assert!(blockchain.insert_with_check(block).is_ok());
+        }
+        assert_eq!(blocks[3].hash, blockchain.tip());
+        assert_eq!(2, blockchain.skipped_signature_checks());
+    }
+
+    #[test]
+    fn test_heaviest_chain() {
+        /*
+         * structure:
+         * genesis <- light_1 <- light_2 <- light_3
+         *    ^
+         *    -------- heavy_1
+         */
+        let mut blockchain = Blockchain::new();
+        blockchain.set_check_trans(false);
+        let genesis_hash = blockchain.tip();
+        let light_difficulty: H256 = gen_difficulty_array(0).into();
+        let heavy_difficulty: H256 = gen_difficulty_array(8).into();
+        let light_1 = generate_block(&genesis_hash, 0, &light_difficulty);
+        blockchain.insert(&light_1).unwrap();
+        let light_2 = generate_block(&light_1.hash, 0, &light_difficulty);
+        blockchain.insert(&light_2).unwrap();
+        let light_3 = generate_block(&light_2.hash, 0, &light_difficulty);
+        blockchain.insert(&light_3).unwrap();
+        assert_eq!(light_3.hash, blockchain.tip());
+        assert_eq!(3, blockchain.get_block(&light_3.hash).unwrap().work);
+
+        let heavy_1 = generate_block(&genesis_hash, 0, &heavy_difficulty);
+        blockchain.insert(&heavy_1).unwrap();
+        assert_eq!(heavy_1.hash, blockchain.tip());
+        assert_eq!(2, blockchain.length());
+        assert_eq!(256, blockchain.get_block(&heavy_1.hash).unwrap().work);
+        assert_eq!(vec![heavy_1.hash, genesis_hash], blockchain.hash_chain());
+
+        // the long chain has to catch up on work, not on length (a tie keeps the first seen tip)
+        let mut parent = light_3.hash;
+        for _ in 0..253 {
+            let block = generate_block(&parent, 0, &light_difficulty);
+            blockchain.insert(&block).unwrap();
+            parent = block.hash;
+        }
+        assert_eq!(heavy_1.hash, blockchain.tip());
+        let block = generate_block(&parent, 0, &light_difficulty);
+        blockchain.insert(&block).unwrap();
+        assert_eq!(block.hash, blockchain.tip());
+        assert_eq!(258, blockchain.length());
+    }
+
+    #[test]
+    fn test_tip_change() {
+        /*
+         * structure:
+         * genesis <- block_1 <- block_2 <- block_3
+         *              ^
+         *              -------  fork_2 <- fork_3 <- fork_4
+         */
+        let mut blockchain = Blockchain::new();
+        blockchain.set_check_trans(false);
+        let genesis_hash = blockchain.tip();
+        let block_1 = generate_random_block(&genesis_hash);
+        blockchain.insert(&block_1).unwrap();
+        let block_2 = generate_random_block(&block_1.hash);
+        blockchain.insert(&block_2).unwrap();
+        let block_3 = generate_random_block(&block_2.hash);
+        blockchain.insert(&block_3).unwrap();
+
+        let change = blockchain.tip_change(&genesis_hash);
+        assert!(!change.is_reorg());
+        let connected: Vec<H256> = change.connected.iter().map(|b| b.hash).collect();
+        assert_eq!(vec![block_1.hash, block_2.hash, block_3.hash], connected);
+
+        let old_tip = blockchain.tip();
+        let fork_2 = generate_random_block(&block_1.hash);
+        blockchain.insert(&fork_2).unwrap();
+        let fork_3 = generate_random_block(&fork_2.hash);
+        blockchain.insert(&fork_3).unwrap();
+        assert_eq!(old_tip, blockchain.tip());
+        assert!(blockchain.tip_change(&old_tip).connected.is_empty());
+        let fork_4 = generate_random_block(&fork_3.hash);
+        blockchain.insert(&fork_4).unwrap();
+
+        let change = blockchain.tip_change(&old_tip);
+        assert!(change.is_reorg());
+        let disconnected: Vec<H256> = change.disconnected.iter().map(|b| b.hash).collect();
+        let connected: Vec<H256> = change.connected.iter().map(|b| b.hash).collect();
+        assert_eq!(vec![block_3.hash, block_2.hash], disconnected);
+        assert_eq!(vec![fork_2.hash, fork_3.hash, fork_4.hash], connected);
+    }
+
+    #[test]
+    fn test_fork_states() {
+        /*
+         * structure:
+         * genesis <- block_1 <- block_2 (tran_1) <- block_3
+         *              ^
+         *              -------  fork_2 (tran_2) <- fork_3 <- fork_4
+         * tran_1 & tran_2 both spend block_1's coinbase
+         */
+        let key = key_pair::random();
+        let mut blockchain = Blockchain::new();
+        let params = ChainParams { coinbase_maturity: 1, ..ChainParams::main() };
+        blockchain.set_chain_params(params.clone());
+        let new_block = |parent: &H256, trans: Vec<SignedTransaction>| {
+            thread::sleep(time::Duration::from_millis(2));  // keep coinbase transactions distinct
+            let mut all_trans = vec![generate_signed_coinbase_transaction(&key)];
+            all_trans.extend(trans);
+            let content = Content::new_with_trans(&all_trans);
+            let header = generate_header(parent, &content, 0, &gen_difficulty_array(EASIEST_DIF).into());
+            Block::new(header, content)
+        };
+        let block_1 = new_block(&blockchain.tip(), vec![]);
+        let addr = block_1.content.trans[0].sender_addr();
+        let coinbase_1 = TxInput::new(block_1.content.trans[0].hash, 0);
+        let tran_1 = generate_signed_transaction(&key, vec![coinbase_1.clone()], vec![TxOutput::new(addr, 50)]);
+        let tran_2 = generate_signed_transaction(&key, vec![coinbase_1], vec![TxOutput::new(addr, 40)]);
+        let block_2 = new_block(&block_1.hash, vec![tran_1.clone()]);
+        let block_3 = new_block(&block_2.hash, vec![]);
+        let fork_2 = new_block(&block_1.hash, vec![tran_2.clone()]);
+        let fork_3 = new_block(&fork_2.hash, vec![]);
+        let fork_4 = new_block(&fork_3.hash, vec![]);
+
+        let mut expected = State::new();
+        let mut states = HashMap::<H256, State>::new();
+        states.insert(blockchain.tip(), expected.clone());
+        for (block, parent, height) in [(&block_1, &blockchain.tip(), 1), (&block_2, &block_1.hash, 2),
+                                        (&block_3, &block_2.hash, 3), (&fork_2, &block_1.hash, 2),
+                                        (&fork_3, &fork_2.hash, 3)].iter() {
+            expected = block.try_generate_state(&states[*parent], *height, &params).unwrap();
+            states.insert(block.hash, expected.clone());
+            assert!(blockchain.insert(block).is_ok());
+        }
+        assert_eq!(block_3.hash, blockchain.tip());
+        assert_eq!(states[&block_3.hash].0, blockchain.tip_block_state().0);
+        assert!(blockchain.tip_block_state().contains_key(&(tran_1.hash, 0)));
+
+        // states of other blocks are rebuilt on demand
+        for (hash, state) in states.iter() {
+            assert_eq!(state.0, blockchain.state_at(hash).unwrap().0);
+        }
+        let block = new_block(&fork_2.hash, vec![tran_1.clone()]);
+        assert!(blockchain.try_generate_new_state(&block).is_none());
+        assert!(blockchain.insert(&block).is_err());
+        assert_eq!(states[&block_3.hash].0, blockchain.tip_block_state().0);
+
+        // switching fork rolls back tran_1 and rolls forward tran_2
+        assert!(blockchain.insert(&fork_4).is_ok());
+        assert_eq!(fork_4.hash, blockchain.tip());
+        let state = blockchain.tip_block_state();
+        assert!(!state.contains_key(&(tran_1.hash, 0)));
+        assert!(state.contains_key(&(tran_2.hash, 0)));
+        assert_eq!(fork_4.try_generate_state(&states[&fork_3.hash], 4, &params).unwrap().0, state.0);
+    }
+
+    #[test]
+    fn test_subsidy_halving() {
+        let key = key_pair::random();
+        let mut blockchain = Blockchain::new();
+        let mut params = ChainParams::main();
+        params.halving_interval = 2;
+        blockchain.set_chain_params(params);
+        let new_block = |parent: &H256, val: u64| {
+            let content = Content::new_with_trans(&vec![generate_signed_coinbase_transaction_with_val(&key, val)]);
+            let header = generate_header(parent, &content, 0, &gen_difficulty_array(EASIEST_DIF).into());
+            Block::new(header, content)
+        };
+
+        let block_1 = new_block(&blockchain.tip(), COINBASE_REWARD);
+        assert!(blockchain.insert(&block_1).is_ok());
+        // halved at height 2
+        let block_2 = new_block(&block_1.hash, COINBASE_REWARD);
+        assert_eq!(Err(BlockValidationError::ExcessiveCoinbase), blockchain.insert(&block_2));
+        assert!(blockchain.try_generate_new_state(&block_2).is_none());
+        let block_2 = new_block(&block_1.hash, COINBASE_REWARD / 2);
+        assert!(blockchain.try_generate_new_state(&block_2).is_some());
+        assert!(blockchain.insert(&block_2).is_ok());
+        assert_eq!(COINBASE_REWARD / 2, blockchain.chain_params().subsidy(3));
+        assert_eq!(COINBASE_REWARD / 4, blockchain.chain_params().subsidy(4));
+        assert_eq!(COINBASE_REWARD + COINBASE_REWARD / 2, blockchain.issued_supply());
     }
 
     #[test]
@@ -317,21 +1338,21 @@ mod tests {
         blockchain.set_check_trans(false);
         let genesis_hash = blockchain.tip();
         let block_1_1 = generate_random_block(&genesis_hash);
-        blockchain.insert(&block_1_1);
+        blockchain.insert(&block_1_1).unwrap();
         let block_1_2 = generate_random_block(&block_1_1.hash());
-        blockchain.insert(&block_1_2);
+        blockchain.insert(&block_1_2).unwrap();
         assert_eq!(blockchain.tip(), block_1_2.hash());
         let block_2_1 = generate_random_block(&block_1_1.hash());
-        blockchain.insert(&block_2_1);
+        blockchain.insert(&block_2_1).unwrap();
         assert_eq!(blockchain.tip(), block_1_2.hash());
         let block_2_2 = generate_random_block(&block_2_1.hash());
-        blockchain.insert(&block_2_2);
+        blockchain.insert(&block_2_2).unwrap();
         assert_eq!(blockchain.tip(), block_2_2.hash());
         let block_1_3 = generate_random_block(&block_1_2.hash());
-        blockchain.insert(&block_1_3);
+        blockchain.insert(&block_1_3).unwrap();
         assert_eq!(blockchain.tip(), block_2_2.hash());
         let block_1_4 = generate_random_block(&block_1_3.hash());
-        blockchain.insert(&block_1_4);
+        blockchain.insert(&block_1_4).unwrap();
         assert_eq!(blockchain.tip(), block_1_4.hash());
     }
 
@@ -344,9 +1365,9 @@ mod tests {
         let block1 = generate_random_block(&genesis_hash);
         let block2 = generate_random_block(&block1.hash());
         let block3 = generate_random_block(&block2.hash());
-        blockchain.insert(&block3);
-        blockchain.insert(&block2);
-        blockchain.insert(&block1);
+        blockchain.insert(&block3).unwrap();
+        blockchain.insert(&block2).unwrap();
+        blockchain.insert(&block1).unwrap();
         assert_eq!(blockchain.tip(), block3.hash());
         assert_eq!(4, blockchain.length());
 
@@ -361,14 +1382,14 @@ mod tests {
         let block_2_3 = generate_random_block(&block_2_2.hash());
         let block_2_4 = generate_random_block(&block_2_3.hash());
         let block_2_5 = generate_random_block(&block_2_4.hash());
-        blockchain.insert(&block_2_5);
-        blockchain.insert(&block_2_4);
-        blockchain.insert(&block_2_3);
-        blockchain.insert(&block_2_2);
-        blockchain.insert(&block_1_3);
-        blockchain.insert(&block_1_2);
+        blockchain.insert(&block_2_5).unwrap();
+        blockchain.insert(&block_2_4).unwrap();
+        blockchain.insert(&block_2_3).unwrap();
+        blockchain.insert(&block_2_2).unwrap();
+        blockchain.insert(&block_1_3).unwrap();
+        blockchain.insert(&block_1_2).unwrap();
         assert_eq!(blockchain.tip(), genesis_hash);
-        blockchain.insert(&block_1_1);
+        blockchain.insert(&block_1_1).unwrap();
         assert_eq!(blockchain.tip(), block_2_5.hash());
         assert_eq!(6, blockchain.length());
     }
@@ -381,9 +1402,9 @@ mod tests {
         let block1 = generate_random_block(&genesis_hash);
         let block2 = generate_random_block(&block1.hash());
         let block3 = generate_random_block(&block2.hash());
-        blockchain.insert(&block3);
-        blockchain.insert(&block2);
-        blockchain.insert(&block1);
+        blockchain.insert(&block3).unwrap();
+        blockchain.insert(&block2).unwrap();
+        blockchain.insert(&block1).unwrap();
         assert_eq!(blockchain.tip(), block3.hash());
         let chain_hash = blockchain.all_blocks_in_longest_chain();
         assert_eq!(chain_hash[0], block3.hash);
@@ -400,10 +1421,10 @@ mod tests {
         let block2 = generate_random_block(&block1.hash());
         let block3 = generate_random_block(&block2.hash());
         assert!(!blockchain.exist(&block3.hash));
-        blockchain.insert(&block3);
+        blockchain.insert(&block3).unwrap();
         assert!(blockchain.exist(&block3.hash));
         assert!(!blockchain.exist(&block1.hash));
-        blockchain.insert(&block1);
+        blockchain.insert(&block1).unwrap();
         assert!(blockchain.exist(&block1.hash));
     }
 
@@ -415,8 +1436,8 @@ mod tests {
         let block1 = generate_random_block(&genesis_hash);
         let block2 = generate_random_block(&block1.hash);
         let block3 = generate_random_block(&block2.hash);
-        blockchain.insert(&block1);
-        blockchain.insert(&block2);
+        blockchain.insert(&block1).unwrap();
+        blockchain.insert(&block2).unwrap();
         let hashes = vec![block1.hash(), block2.hash(), block3.hash()];
         let blocks = blockchain.get_blocks(&hashes);
         assert_eq!(2, blocks.len());
@@ -432,10 +1453,10 @@ mod tests {
         let block1 = generate_random_block(&genesis_hash);
         let block2 = generate_random_block(&block1.hash);
         let block3 = generate_random_block(&block2.hash);
-        blockchain.insert(&block3);
+        blockchain.insert(&block3).unwrap();
         assert_eq!(block3, blockchain.get_block(&block3.hash).unwrap());
         assert_eq!(None, blockchain.get_block(&block1.hash));
-        blockchain.insert(&block2);
+        blockchain.insert(&block2).unwrap();
         assert_eq!(block2, blockchain.get_block(&block2.hash).unwrap());
     }
 
@@ -447,9 +1468,9 @@ mod tests {
         let block1 = generate_random_block(&genesis_hash);
         let block2 = generate_random_block(&block1.hash);
         let block3 = generate_random_block(&block2.hash);
-        blockchain.insert(&block1);
-        blockchain.insert(&block2);
-        blockchain.insert(&block3);
+        blockchain.insert(&block1).unwrap();
+        blockchain.insert(&block2).unwrap();
+        blockchain.insert(&block3).unwrap();
         let hashes = blockchain.hash_chain();
         assert_eq!(genesis_hash, hashes[3]);
         assert_eq!(block1.hash, hashes[2]);
@@ -465,9 +1486,9 @@ mod tests {
         let block1 = generate_random_block(&genesis_hash);
         let block2 = generate_random_block(&block1.hash);
         let block3 = generate_random_block(&block2.hash);
-        blockchain.insert(&block1);
-        blockchain.insert(&block2);
-        blockchain.insert(&block3);
+        blockchain.insert(&block1).unwrap();
+        blockchain.insert(&block2).unwrap();
+        blockchain.insert(&block3).unwrap();
         let headers = blockchain.header_chain();
         assert_eq!(genesis_hash, headers[2].parent);
         assert_eq!(block1.hash, headers[1].parent);
@@ -482,9 +1503,9 @@ mod tests {
         let block1 = generate_random_block(&genesis_hash);
         let block2 = generate_random_block(&block1.hash);
         let block3 = generate_random_block(&block2.hash);
-        blockchain.insert(&block1);
-        blockchain.insert(&block2);
-        blockchain.insert(&block3);
+        blockchain.insert(&block1).unwrap();
+        blockchain.insert(&block2).unwrap();
+        blockchain.insert(&block3).unwrap();
         let blocks = blockchain.block_chain();
         assert_eq!(block1.hash, blocks[2].hash);
         assert_eq!(block2.hash, blocks[1].hash);
@@ -501,14 +1522,14 @@ mod tests {
         let block3 = generate_random_block(&block2.hash);
         assert!(!blockchain.is_orphan(&block3.hash));
         assert!(!blockchain.is_orphan(&block1.hash));
-        blockchain.insert(&block2);
-        blockchain.insert(&block3);
+        blockchain.insert(&block2).unwrap();
+        blockchain.insert(&block3).unwrap();
         assert!(blockchain.is_orphan(&block2.hash));
         assert!(blockchain.is_orphan(&block3.hash));
         assert!(!blockchain.is_orphan(&block1.hash));
         assert_eq!(block1.hash, blockchain.missing_parent(&block3.hash).unwrap());
         assert_eq!(block1.hash, blockchain.missing_parent(&block2.hash).unwrap());
-        blockchain.insert(&block1);
+        blockchain.insert(&block1).unwrap();
         assert!(!blockchain.is_orphan(&block3.hash));
         assert!(!blockchain.is_orphan(&block2.hash));
         assert!(!blockchain.is_orphan(&block1.hash));
@@ -517,6 +1538,27 @@ mod tests {
         assert_eq!(None, blockchain.missing_parent(&block1.hash));
     }
 
+    #[test]
+    fn test_orphan_peer() {
+        let peer = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 17061);
+        let mut blockchain = Blockchain::new();
+        blockchain.set_check_trans(false);
+        let difficulty: H256 = gen_difficulty_array(TEST_DIF).into();
+        blockchain.change_difficulty(&difficulty);
+        let block1 = generate_mined_block(&blockchain.tip(), &difficulty);
+        let block2 = generate_mined_block(&block1.hash, &difficulty);
+        let block3 = generate_mined_block(&block2.hash, &difficulty);
+        assert!(blockchain.insert_with_check_from(&block3, Some(peer)).is_ok());
+        assert!(blockchain.insert_with_check_from(&block2, None).is_ok());
+        assert_eq!(2, blockchain.orphan_count());
+        assert_eq!(Some(&1), blockchain.orphan_count_by_peer().get(&peer));
+        assert_eq!(Some(block1.hash), blockchain.missing_parent(&block3.hash));
+        assert!(blockchain.insert_with_check_from(&block1, Some(peer)).is_ok());
+        assert_eq!(0, blockchain.orphan_count());
+        assert!(blockchain.orphan_count_by_peer().is_empty());
+        assert_eq!(block3.hash, blockchain.tip());
+    }
+
     #[test]
     fn test_sync_longest_chain() {
         let p2p_addr_1 = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 17051);
@@ -546,9 +1588,9 @@ mod tests {
         let genesis = chain_1.tip();
         let difficulty = chain_1.difficulty();
         let block_1 = generate_mined_block(&genesis, &difficulty);
-        chain_1.insert(&block_1);
+        chain_1.insert(&block_1).unwrap();
         let block_2 = generate_mined_block(&block_1.hash, &difficulty);
-        chain_1.insert(&block_2);
+        chain_1.insert(&block_2).unwrap();
         drop(chain_1);
         drop(chain_2);
 
@@ -574,7 +1616,7 @@ mod tests {
         blockchain.set_check_trans(false);
         let genesis_hash = blockchain.tip();
         let block = generate_random_block(&genesis_hash);
-        blockchain.insert(&block);
+        blockchain.insert(&block).unwrap();
         assert_eq!(blockchain.tip(), block.hash());
     }
     #[test]
@@ -583,19 +1625,19 @@ mod tests {
         blockchain.set_check_trans(false);
         let genesis_hash = blockchain.tip();
         let block_1 = generate_random_block(&genesis_hash);
-        blockchain.insert(&block_1);
+        blockchain.insert(&block_1).unwrap();
         assert_eq!(blockchain.tip(), block_1.hash());
         let block_2 = generate_random_block(&block_1.hash());
-        blockchain.insert(&block_2);
+        blockchain.insert(&block_2).unwrap();
         assert_eq!(blockchain.tip(), block_2.hash());
         let block_3 = generate_random_block(&block_2.hash());
-        blockchain.insert(&block_3);
+        blockchain.insert(&block_3).unwrap();
         assert_eq!(blockchain.tip(), block_3.hash());
         let fork_block_1 = generate_random_block(&genesis_hash);
-        blockchain.insert(&fork_block_1);
+        blockchain.insert(&fork_block_1).unwrap();
         assert_eq!(blockchain.tip(), block_3.hash());
         let fork_block_2 = generate_random_block(&fork_block_1.hash());
-        blockchain.insert(&fork_block_2);
+        blockchain.insert(&fork_block_2).unwrap();
         assert_eq!(blockchain.tip(), block_3.hash());
     }
     #[test]
@@ -604,19 +1646,19 @@ mod tests {
         blockchain.set_check_trans(false);
         let genesis_hash = blockchain.tip();
         let block_1 = generate_random_block(&genesis_hash);
-        blockchain.insert(&block_1);
+        blockchain.insert(&block_1).unwrap();
         assert_eq!(blockchain.tip(), block_1.hash());
         let block_2 = generate_random_block(&block_1.hash());
-        blockchain.insert(&block_2);
+        blockchain.insert(&block_2).unwrap();
         assert_eq!(blockchain.tip(), block_2.hash());
         let fork_block_1 = generate_random_block(&genesis_hash);
-        blockchain.insert(&fork_block_1);
+        blockchain.insert(&fork_block_1).unwrap();
         assert_eq!(blockchain.tip(), block_2.hash());
         let fork_block_2 = generate_random_block(&fork_block_1.hash());
-        blockchain.insert(&fork_block_2);
+        blockchain.insert(&fork_block_2).unwrap();
         //assert_eq!(blockchain.tip(), block_2.hash());
         let fork_block_3 = generate_random_block(&fork_block_2.hash());
-        blockchain.insert(&fork_block_3);
+        blockchain.insert(&fork_block_3).unwrap();
         assert_eq!(blockchain.tip(), fork_block_3.hash());
     }
     #[test]
@@ -625,23 +1667,23 @@ mod tests {
         blockchain.set_check_trans(false);
         let genesis_hash = blockchain.tip();
         let block_1 = generate_random_block(&genesis_hash);
-        blockchain.insert(&block_1);
+        blockchain.insert(&block_1).unwrap();
         assert_eq!(blockchain.tip(), block_1.hash());
         let block_2 = generate_random_block(&block_1.hash());
-        blockchain.insert(&block_2);
+        blockchain.insert(&block_2).unwrap();
         assert_eq!(blockchain.tip(), block_2.hash());
         let block_3 = generate_random_block(&block_2.hash());
-        blockchain.insert(&block_3);
+        blockchain.insert(&block_3).unwrap();
         assert_eq!(blockchain.tip(), block_3.hash());
         let fork_block_1 = generate_random_block(&block_2.hash());
-        blockchain.insert(&fork_block_1);
+        blockchain.insert(&fork_block_1).unwrap();
         let fork_block_2 = generate_random_block(&fork_block_1.hash());
-        blockchain.insert(&fork_block_2);
+        blockchain.insert(&fork_block_2).unwrap();
         assert_eq!(blockchain.tip(), fork_block_2.hash());
         let block_4 = generate_random_block(&block_3.hash());
-        blockchain.insert(&block_4);
+        blockchain.insert(&block_4).unwrap();
         let block_5 = generate_random_block(&block_4.hash());
-        blockchain.insert(&block_5);
+        blockchain.insert(&block_5).unwrap();
         assert_eq!(blockchain.tip(), block_5.hash());
     }
     #[test]
@@ -650,35 +1692,35 @@ mod tests {
         blockchain.set_check_trans(false);
         let genesis_hash = blockchain.tip();
         let block_1 = generate_random_block(&genesis_hash);
-        blockchain.insert(&block_1);
+        blockchain.insert(&block_1).unwrap();
         assert_eq!(blockchain.tip(), block_1.hash());
         let block_2 = generate_random_block(&block_1.hash());
-        blockchain.insert(&block_2);
+        blockchain.insert(&block_2).unwrap();
         assert_eq!(blockchain.tip(), block_2.hash());
         let block_3 = generate_random_block(&block_2.hash());
-        blockchain.insert(&block_3);
+        blockchain.insert(&block_3).unwrap();
         assert_eq!(blockchain.tip(), block_3.hash());
         let fork_block_1 = generate_random_block(&block_2.hash());
-        blockchain.insert(&fork_block_1);
+        blockchain.insert(&fork_block_1).unwrap();
         let fork_block_2 = generate_random_block(&fork_block_1.hash());
-        blockchain.insert(&fork_block_2);
+        blockchain.insert(&fork_block_2).unwrap();
         assert_eq!(blockchain.tip(), fork_block_2.hash());
         let another_block_1 = generate_random_block(&genesis_hash);
-        blockchain.insert(&another_block_1);
+        blockchain.insert(&another_block_1).unwrap();
         assert_eq!(blockchain.tip(), fork_block_2.hash());
         let another_block_2 = generate_random_block(&another_block_1.hash());
-        blockchain.insert(&another_block_2);
+        blockchain.insert(&another_block_2).unwrap();
         assert_eq!(blockchain.tip(), fork_block_2.hash());
         let another_block_3 = generate_random_block(&another_block_2.hash());
-        blockchain.insert(&another_block_3);
+        blockchain.insert(&another_block_3).unwrap();
         assert_eq!(blockchain.tip(), fork_block_2.hash());
         let another_block_4 = generate_random_block(&another_block_3.hash());
-        blockchain.insert(&another_block_4);
+        blockchain.insert(&another_block_4).unwrap();
         let another_block_5 = generate_random_block(&another_block_4.hash());
-        blockchain.insert(&another_block_5);
+        blockchain.insert(&another_block_5).unwrap();
         assert_eq!(blockchain.tip(), another_block_5.hash());
         let another_block_6 = generate_random_block(&another_block_5.hash());
-        blockchain.insert(&another_block_6);
+        blockchain.insert(&another_block_6).unwrap();
         assert_eq!(blockchain.tip(), another_block_6.hash());
     }
 
@@ -689,17 +1731,66 @@ mod tests {
         let difficulty: H256 = gen_difficulty_array(0).into();
         blockchain.change_difficulty(&difficulty);
         let mut block = generate_block(&genesis_hash, 40, &difficulty);
-        assert!(blockchain.validate_block_meta(&block));
+        assert!(blockchain.validate_block_meta(&block).is_ok());
+
+        // Merkle root validate
+        let mut tampered = block.clone();
+        tampered.content.trans.push(generate_random_signed_transaction());
+        assert_eq!(Err(BlockValidationError::MerkleRootMismatch), blockchain.validate_block_meta(&tampered));
+        tampered.content.trans.clear();
+        assert_eq!(Err(BlockValidationError::MissingCoinbase), blockchain.validate_block_meta(&tampered));
+
+        // Signature validate
+        let mut tampered = block.clone();
+        tampered.content.trans.push(generate_random_signed_transaction());
+        tampered.content.trans[0].signature = tampered.content.trans[1].signature.clone();
+        let content = tampered.content.clone();
+        let header = generate_header(&genesis_hash, &content, 0, &difficulty);
+        let tampered = Block::new(header, content);
+        assert_eq!(Err(BlockValidationError::InvalidSignature(tampered.content.trans[0].hash)),
+                   blockchain.validate_block_meta(&tampered));
 
         // Hash Validate
         let hash: H256 = gen_difficulty_array(20).into();
         block.change_hash(&hash);
-        assert!(!blockchain.validate_block_meta(&block));
+        assert_eq!(Err(BlockValidationError::HashMismatch), blockchain.validate_block_meta(&block));
+
+        // Difficulty validate
+        let block = generate_mined_block(&genesis_hash, &gen_difficulty_array(TEST_DIF).into());
+        assert_eq!(Err(BlockValidationError::WrongDifficulty), blockchain.validate_block_meta(&block));
 
         //POW validate
         let difficulty: H256 = gen_difficulty_array(20).into();
         blockchain.change_difficulty(&difficulty);
         let block = generate_block(&genesis_hash, 1, &difficulty);
-        assert!(!blockchain.validate_block_meta(&block));
+        assert_eq!(Err(BlockValidationError::InvalidPow), blockchain.validate_block_meta(&block));
+    }
+
+    #[test]
+    fn test_rejection_counts() {
+        let peer = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 17062);
+        let mut blockchain = Blockchain::new();
+        blockchain.set_check_trans(false);
+        let difficulty: H256 = gen_difficulty_array(TEST_DIF).into();
+        blockchain.change_difficulty(&difficulty);
+        let block = generate_mined_block(&blockchain.tip(), &difficulty);
+        let wrong_difficulty = generate_mined_block(&blockchain.tip(), &gen_difficulty_array(EASIEST_DIF).into());
+        let parent = generate_mined_block(&block.hash, &difficulty);
+        let orphan = generate_mined_block(&parent.hash, &gen_difficulty_array(EASIEST_DIF).into());
+
+        assert!(blockchain.insert_with_check_from(&block, Some(peer)).is_ok());
+        assert_eq!(Err(BlockValidationError::Duplicate), blockchain.insert_with_check_from(&block, Some(peer)));
+        assert_eq!(Err(BlockValidationError::WrongDifficulty),
+                   blockchain.insert_with_check_from(&wrong_difficulty, Some(peer)));
+        assert_eq!(Err(BlockValidationError::WrongDifficulty), blockchain.insert_with_check(&wrong_difficulty));
+        // the orphan's difficulty is checked once its parent arrives
+        assert!(blockchain.insert_with_check_from(&orphan, Some(peer)).is_ok());
+        assert!(blockchain.insert_with_check(&parent).is_ok());
+        assert!(!blockchain.exist(&orphan.hash));
+
+        let counts = blockchain.rejection_counts().get(&peer).unwrap();
+        assert_eq!(Some(&2), counts.get("wrong_difficulty"));
+        assert_eq!(1, counts.len());
+        assert_eq!(1, blockchain.rejection_counts().len());
     }
 }
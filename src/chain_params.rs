@@ -0,0 +1,124 @@
+use crate::crypto::hash::H256;
+use crate::config::{COINBASE_REWARD, HALVING_INTERVAL, COINBASE_MATURITY};
+
+// Known (height, hash) pairs of the default chain
+static CHECKPOINTS: &[(usize, [u8; 32])] = &[
+    (0, [0u8; 32]),  // genesis
+];
+
+// Signatures of this block and its ancestors are not verified, none to verify every block
+static ASSUMED_VALID: Option<[u8; 32]> = None;
+
+// Parameters that differ between chains
+#[derive(Clone, Debug)]
+pub struct ChainParams {
+    pub checkpoints: Vec<(usize, H256)>,  // blocks conflicting with them are rejected
+    pub assumed_valid: Option<H256>,
+    pub initial_subsidy: u64,  // subsidy of blocks before the first halving
+    pub halving_interval: usize,  // 0 to never halve
+    pub coinbase_maturity: usize,  // a coinbase output at height h can be spent from height h + maturity on
+}
+
+impl ChainParams {
+    // Parameters of the default chain
+    pub fn main() -> Self {
+        Self {
+            checkpoints: CHECKPOINTS.iter().map(|(height, hash)| (*height, (*hash).into())).collect(),
+            assumed_valid: ASSUMED_VALID.map(|hash| hash.into()),
+            initial_subsidy: COINBASE_REWARD,
+            halving_interval: HALVING_INTERVAL,
+            coinbase_maturity: COINBASE_MATURITY,
+        }
+    }
+
+    // Get the pinned hash at the given height
+    pub fn checkpoint(&self, height: usize) -> Option<H256> {
+        self.checkpoints.iter().find(|(h, _)| *h == height).map(|(_, hash)| *hash)
+    }
+
+    // Newly issued coins the coinbase of the block at the given height can claim, besides fees
+    pub fn subsidy(&self, height: usize) -> u64 {
+        if height == 0 {
+            return 0;  // genesis has no coinbase
+        }
+        if self.halving_interval == 0 {
+            return self.initial_subsidy;
+        }
+        let halvings = height / self.halving_interval;
+        if halvings >= 64 {
+            return 0;
+        }
+        self.initial_subsidy >> halvings
+    }
+
+    // Sum of the subsidies of all blocks up to and including the given height
+    pub fn total_supply(&self, height: usize) -> u64 {
+        if self.halving_interval == 0 {
+            return self.initial_subsidy.saturating_mul(height as u64);
+        }
+        let mut total = 0u64;
+        let mut era_start = 1usize;
+        while era_start <= height {
+            let subsidy = self.subsidy(era_start);
+            if subsidy == 0 {
+                break;
+            }
+            // last height with the same subsidy as era_start
+            let era_end = std::cmp::min(height, (era_start / self.halving_interval + 1).saturating_mul(self.halving_interval) - 1);
+            total = total.saturating_add(subsidy.saturating_mul((era_end - era_start + 1) as u64));
+            era_start = era_end + 1;
+        }
+        total
+    }
+
+    // Upper bound of the supply once the subsidy reaches 0, none if it never does
+    pub fn max_supply(&self) -> Option<u64> {
+        if self.halving_interval == 0 && self.initial_subsidy > 0 {
+            return None;
+        }
+        let mut height = 0usize;
+        while self.subsidy(height + 1) > 0 {
+            height += self.halving_interval;
+        }
+        Some(self.total_supply(height))
+    }
+}
+
+#[cfg(any(test, test_utilities))]
+mod tests {
+    use super::*;
+    use crate::block::Block;
+
+    #[test]
+    fn test_main_checkpoints() {
+        let params = ChainParams::main();
+        assert_eq!(Some(Block::genesis().hash), params.checkpoint(0));
+        assert_eq!(None, params.checkpoint(1));
+    }
+
+    #[test]
+    fn test_subsidy_schedule() {
+        let mut params = ChainParams::main();
+        params.initial_subsidy = 50;
+        params.halving_interval = 10;
+        assert_eq!(0, params.subsidy(0));
+        assert_eq!(50, params.subsidy(1));
+        assert_eq!(50, params.subsidy(9));
+        assert_eq!(25, params.subsidy(10));
+        assert_eq!(12, params.subsidy(29));
+        assert_eq!(0, params.subsidy(60));
+        assert_eq!(0, params.subsidy(usize::MAX));
+
+        assert_eq!(0, params.total_supply(0));
+        assert_eq!(450, params.total_supply(9));
+        assert_eq!(475, params.total_supply(10));
+        assert_eq!(450 + 250 + 120 + 60 + 30 + 10, params.total_supply(59));
+        assert_eq!(params.total_supply(59), params.total_supply(1000));
+        assert_eq!(Some(params.total_supply(59)), params.max_supply());
+
+        params.halving_interval = 0;
+        assert_eq!(50, params.subsidy(1000));
+        assert_eq!(500, params.total_supply(10));
+        assert_eq!(None, params.max_supply());
+    }
+}
@@ -2,11 +2,63 @@ use crate::spread::Spreader;
 
 pub static DIFFICULTY: i32 = 17; // number of leading zero
 
+pub static RETARGET_INTERVAL: usize = 20; // number of blocks between two difficulty adjustments
+
+pub static TARGET_BLOCK_INTERVAL: u64 = 10000; // expected time(ms) between two blocks
+
+pub static MAX_RETARGET_FACTOR: u64 = 4; // max factor the difficulty can change by in one adjustment
+
+pub static MEDIAN_TIME_SPAN: usize = 11; // number of blocks whose median timestamp a new block must exceed
+
+pub static MAX_FUTURE_DRIFT: u64 = 7200000; // max time(ms) a block timestamp can be ahead of local time
+
+pub static FUTURE_BLOCK_LIMIT: usize = 100; // max number of blocks buffered for being too far in the future
+
+pub static MAX_HEADERS_PER_MSG: usize = 2000; // max number of headers in a Headers message
+
+pub static BLOCK_FETCH_WINDOW: usize = 128; // max number of block bodies requested at once in headers-first sync
+
+pub static BLOCK_FETCH_BATCH: usize = 16; // number of block bodies per GetBlocks message in headers-first sync
+
+pub static BLOCK_FETCH_TIMEOUT: u64 = 5000; // time(ms) after which a requested block body is requested again
+
+pub static MAX_MEMPOOL_HASHES_PER_MSG: usize = 1000; // max number of hashes in each NewTransactionHashes message answering GetMempool
+
 pub static MINING_STEP: u32 = 8192; // number of mining step
 
-pub static BLOCK_SIZE_LIMIT: usize = 256; // size limit of transactions in a block
+pub static MINER_TIP_POLL: u64 = 5; // time(ms) between two checks of the tip while mining threads search
+
+pub static HASH_RATE_WINDOW: u64 = 10000; // time(ms) over which the hash rate of the miner is measured
+
+pub static MAX_BLOCK_TEMPLATES: usize = 16; // number of latest block templates kept for external miners to submit solved blocks of
+
+pub static BLOCK_SIZE_LIMIT: usize = 256; // max number of transactions in a block, including the coinbase
 
-pub static POOL_SIZE_LIMIT: usize = 100000; // size limit of mempool
+pub static BLOCK_BYTES_LIMIT: usize = 1000000; // max serialized size(bytes) of a block
+
+pub static POOL_SIZE_LIMIT: usize = 100000; // max number of transactions in mempool, the lowest fee rates are evicted beyond it
+
+pub static MIN_RELAY_FEE_RATE: u64 = 0; // min fee per 1000 bytes for a transaction to enter mempool
+
+pub static INCREMENTAL_FEE_RATE: u64 = 1; // fee rate(per 1000 bytes) added to that of an evicted transaction to get the dynamic min fee rate
+
+pub static MIN_FEE_HALF_LIFE: u64 = 600000; // time(ms) for the dynamic min fee rate to halve
+
+pub static MEMPOOL_EXPIRY: u64 = 1209600000; // time(ms) a transaction can stay in mempool
+
+pub static MEMPOOL_EXPIRY_CHECK: u64 = 60000; // time(ms) between two sweeps of expired transactions out of mempool
+
+pub static STEM_BUFFER_EXPIRY: u64 = 600000; // time(ms) a transaction can stay in the dandelion stem buffer
+
+pub static ORPHAN_POOL_SIZE_LIMIT: usize = 1000; // max number of orphan blocks kept
+
+pub static ORPHAN_BLOCK_EXPIRY: u64 = 600000; // time(ms) an orphan block is kept before it is dropped
+
+pub static ORPHAN_TRAN_POOL_SIZE_LIMIT: usize = 100; // max number of orphan transactions kept
+
+pub static ORPHAN_TRAN_PEER_LIMIT: usize = 25; // max number of orphan transactions kept from one peer
+
+pub static ORPHAN_TRAN_EXPIRY: u64 = 1200000; // time(ms) an orphan transaction is kept before it is dropped
 
 pub static TRANSACTION_GENERATE_INTERVAL: u64 = 8000; // time interval(ms) to add a new-created transaction to mempool
 
@@ -14,7 +66,11 @@ pub static TEST_DIF: i32 = 4; // difficulty used for mod test
 
 pub static EASIEST_DIF: i32 = 0; // all-1-difficulty
 
-pub static COINBASE_REWARD: u64 = 50; // reward for miner
+pub static COINBASE_REWARD: u64 = 50; // reward for miner before the first halving
+
+pub static HALVING_INTERVAL: usize = 210000; // number of blocks between two halvings of the block subsidy
+
+pub static COINBASE_MATURITY: usize = 10; // number of confirmations before a coinbase output can be spent
 
 pub static RAND_INPUTS_NUM: usize = 4; // number of inputs in generate_random_txinput
 
@@ -38,6 +38,75 @@ impl H256 {
         buffer[..].copy_from_slice(bytes[12..32].as_ref());
         H160(buffer)
     }
+
+    // Multiply by mul and then divide by div (rounding down), saturating at the max value
+    pub fn mul_div(&self, mul: u64, div: u64) -> H256 {
+        assert!(div != 0, "divide by zero");
+        let mut limbs = [0u64; 4];  // big endian, limbs[0] is the most significant
+        for (i, limb) in limbs.iter_mut().enumerate() {
+            *limb = u64::from_be_bytes(self.0[i * 8..i * 8 + 8].try_into().unwrap());
+        }
+
+        let mut product = [0u64; 5];
+        let mut carry = 0u128;
+        for i in (0..4).rev() {
+            let cur = limbs[i] as u128 * mul as u128 + carry;
+            product[i + 1] = cur as u64;
+            carry = cur >> 64;
+        }
+        product[0] = carry as u64;
+
+        let mut quotient = [0u64; 5];
+        let mut rem = 0u128;
+        for i in 0..5 {
+            let cur = (rem << 64) | product[i] as u128;
+            quotient[i] = (cur / div as u128) as u64;
+            rem = cur % div as u128;
+        }
+        if quotient[0] != 0 {
+            return H256([u8::MAX; 32]);
+        }
+
+        let mut raw_hash: [u8; 32] = [0; 32];
+        for i in 0..4 {
+            raw_hash[i * 8..i * 8 + 8].copy_from_slice(&quotient[i + 1].to_be_bytes());
+        }
+        H256(raw_hash)
+    }
+
+    // Work represented by this hash as a target: the expected number of hashes to find one
+    // below it, i.e. 2^256 / (target + 1), saturating at u128::MAX
+    pub fn work(&self) -> u128 {
+        let target = (u128::from_be_bytes(self.0[0..16].try_into().unwrap()),
+                      u128::from_be_bytes(self.0[16..32].try_into().unwrap()));
+        if target == (u128::MAX, u128::MAX) {
+            return 1;
+        }
+        // 2^256 / (target + 1) == (2^256 - 1 - target) / (target + 1) + 1
+        let numerator = (!target.0, !target.1);
+        let divisor = match target.1.checked_add(1) {
+            Some(lo) => (target.0, lo),
+            None => (target.0 + 1, 0),
+        };
+
+        let mut rem = (0u128, 0u128);
+        let mut quotient = (0u128, 0u128);
+        for i in (0..256).rev() {
+            let bit = if i >= 128 { numerator.0 >> (i - 128) & 1 } else { numerator.1 >> i & 1 };
+            let carry = rem.0 >> 127;
+            rem = ((rem.0 << 1) | (rem.1 >> 127), (rem.1 << 1) | bit);
+            quotient = ((quotient.0 << 1) | (quotient.1 >> 127), quotient.1 << 1);
+            if carry == 1 || rem >= divisor {
+                let (lo, borrow) = rem.1.overflowing_sub(divisor.1);
+                rem = (rem.0.wrapping_sub(divisor.0).wrapping_sub(borrow as u128), lo);
+                quotient.1 |= 1;
+            }
+        }
+        if quotient.0 != 0 {
+            return u128::MAX;
+        }
+        quotient.1.saturating_add(1)
+    }
 }
 
 impl Hashable for H256 {
@@ -240,4 +309,32 @@ pub mod tests {
         assert_eq!(81, h160.0[1]);
         assert_eq!(160, h160.0[19]);
     }
+
+    #[test]
+    fn test_mul_div() {
+        let h256: H256 = hex!("00000000000000000000000000000001ffffffffffffffffffffffffffffffff").into();
+        let doubled: H256 = hex!("00000000000000000000000000000003fffffffffffffffffffffffffffffffe").into();
+        assert_eq!(doubled, h256.mul_div(2, 1));
+        assert_eq!(h256, doubled.mul_div(1, 2));
+        assert_eq!(h256, h256.mul_div(u64::MAX, u64::MAX));
+        let max: H256 = [u8::MAX; 32].into();
+        assert_eq!(max, max.mul_div(4, 1));
+        assert_eq!(max, h256.mul_div(u64::MAX, 1).mul_div(u64::MAX, 1));
+    }
+
+    #[test]
+    fn test_work() {
+        let max: H256 = [u8::MAX; 32].into();
+        assert_eq!(1, max.work());
+        let h256: H256 = hex!("00ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff").into();
+        assert_eq!(256, h256.work());
+        let h256: H256 = hex!("0000000000000000000000000000000100000000000000000000000000000000").into();
+        assert_eq!(u128::MAX, h256.work());
+        let h256: H256 = hex!("0000000000000000000000000000000200000000000000000000000000000000").into();
+        assert_eq!((1u128 << 127) - 1, h256.work());
+        let h256: H256 = hex!("7fffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff").into();
+        assert_eq!(2, h256.work());
+        let h256: H256 = hex!("aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa").into();
+        assert_eq!(1, h256.work());
+    }
 }
@@ -23,6 +23,8 @@ use chrono::prelude::*;
 use std::net::SocketAddr;
 use crossbeam::channel;
 use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::SystemTime;
 use std::fs::File;
 use std::io::{self, BufRead};
 use std::path::Path;
@@ -41,7 +43,9 @@ pub fn new_server_env(ipv4_addr: SocketAddr, spreader_type : Spreader, is_supern
     blockchain.change_difficulty(&difficulty);
     let blockchain =  Arc::new(Mutex::new(blockchain));
 
-    let mempool = Arc::new(Mutex::new(MemPool::new()));
+    let mut mempool = MemPool::new();
+    mempool.set_check_trans(false);  // relay tests use random transactions
+    let mempool = Arc::new(Mutex::new(mempool));
 
     let using_dandelion =  spreader_type == Spreader::Dandelion || spreader_type == Spreader::DandelionPlus;
 
@@ -88,6 +92,17 @@ pub fn connect_peers(server: &server::Handle, known_peers: &Vec<SocketAddr>) {
     }
 }
 
+// Current time(ms), strictly increasing across calls so that a chain of generated blocks
+// keeps passing the median-time-past check
+pub fn generate_increasing_timestamp() -> u128 {
+    static LAST_TS: AtomicU64 = AtomicU64::new(0);
+    let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap().as_millis() as u64;
+    let prev = LAST_TS.fetch_update(Ordering::SeqCst, Ordering::SeqCst,
+                                    |last| Some(std::cmp::max(last + 1, now))).unwrap();
+    std::cmp::max(prev + 1, now) as u128
+}
+
 ///Block
 pub fn generate_mined_block(parent_hash: &H256, difficulty: &H256) -> Block {
     let content = generate_random_content();
@@ -107,7 +122,8 @@ pub fn generate_random_header(parent: &H256, content: &Content) -> Header {
     let mut rng = rand::thread_rng();
     let nonce: u32 = rng.gen();
     let timestamp: u128 = rng.gen();
-    let difficulty = generate_random_hash();
+    // same difficulty for every random block, so that chain work only depends on the length
+    let difficulty: H256 = gen_difficulty_array(DIFFICULTY).into();
     let merkle_root = content.merkle_root();
     Header::new(
         parent, nonce, timestamp,
@@ -134,7 +150,7 @@ pub fn generate_block(parent: &H256, nonce: u32, difficulty: &H256)
 
 pub fn generate_header(parent: &H256, content: &Content, nonce: u32,
                    difficulty: &H256) -> Header {
-    let ts = 100u128;
+    let ts = generate_increasing_timestamp();
     let merkle_root = content.merkle_root();
     Header::new(
         parent, nonce, ts,
@@ -152,8 +168,10 @@ fn generate_content() -> Content {
 /// Transaction
 
 // Create valid transactions under current state (For now: Send to one peer & myself)
-pub fn generate_valid_tran(state: &State, account: &Account, rec_addr: &H160) -> Option<SignedTransaction> {
-    let (coins, balance) = state.coins_of(&account.addr);
+// spending only coins mature for a block at the given height
+pub fn generate_valid_tran(state: &State, account: &Account, rec_addr: &H160,
+                           height: usize, maturity: usize) -> Option<SignedTransaction> {
+    let (coins, balance) = state.spendable_coins_of(&account.addr, height, maturity);
     if balance > 0 {
         let transfer_val = gen_random_num(1, balance);
         let mut acc = 0u64;
@@ -186,9 +204,23 @@ pub fn generate_signed_transaction(key: &Ed25519KeyPair,
 }
 
 pub fn generate_signed_coinbase_transaction(key: &Ed25519KeyPair) -> SignedTransaction {
+    generate_signed_coinbase_transaction_with_val(key, COINBASE_REWARD)
+}
+
+// Coinbase claiming the given value, i.e. the subsidy plus fees
+pub fn generate_signed_coinbase_transaction_with_val(key: &Ed25519KeyPair, val: u64) -> SignedTransaction {
+    generate_signed_coinbase_transaction_with_extra_nonce(key, val, 0)
+}
+
+// Same as generate_signed_coinbase_transaction_with_val, with the extra nonce the miner searches under
+pub fn generate_signed_coinbase_transaction_with_extra_nonce(key: &Ed25519KeyPair, val: u64, extra_nonce: u32) -> SignedTransaction {
     let addr: H160 = digest::digest(&digest::SHA256, key.public_key().as_ref()).into();
-    let txoutput = TxOutput {rec_address: addr.clone(), val: COINBASE_REWARD};
-    return generate_signed_transaction(key, Vec::new(), vec![txoutput]);
+    let txoutput = TxOutput {rec_address: addr, val};
+    let mut transaction = Transaction::new(Vec::new(), vec![txoutput]);
+    transaction.extra_nonce = extra_nonce;
+    let public_key: Box<[u8]> = key.public_key().as_ref().into();
+    let signature: Box<[u8]> = sign(&transaction, key).as_ref().into();
+    SignedTransaction::new(transaction, signature, public_key)
 }
 
 pub fn generate_random_signed_transaction_from_keypair(key: &Ed25519KeyPair) -> SignedTransaction {
@@ -259,6 +291,18 @@ pub fn generate_random_state(inputs: Vec<(H256, u32)>, outputs: Vec<(u64, H160)>
     state
 }
 
+// A new key pair and its address, owning outputs of the given values at indexes 0.. of a random transaction
+pub fn generate_funded_state(vals: &[u64]) -> (Ed25519KeyPair, H160, H256, State) {
+    let key = key_pair::random();
+    let addr = generate_signed_coinbase_transaction(&key).sender_addr();
+    let prev_hash = generate_random_hash();
+    let mut state = State::new();
+    for (index, val) in vals.iter().enumerate() {
+        state.insert((prev_hash, index as u32), (*val, addr));
+    }
+    (key, addr, prev_hash, state)
+}
+
 ///Dandelion
 pub fn set_routing_table(peer_list: &Vec<usize>, table: &mut HashMap<usize, usize>) {
     let outbound_peers = get_k_random_peers(peer_list, 2);
@@ -359,6 +403,10 @@ pub fn get_current_time_in_nano() -> i64{
     now.timestamp_nanos()
 }
 
+pub fn get_current_time_in_millis() -> u64 {
+    SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_millis() as u64
+}
+
 fn read_lines<P>(filename: P) -> io::Result<io::Lines<io::BufReader<File>>>
         where P: AsRef<Path>, {
     let file = File::open(filename)?;
@@ -416,11 +464,19 @@ pub mod tests {
         state.insert((h256_1, 1), (3, h160_1));
         state.insert((h256_2, 5), (7, h160_2));
         state.insert((h256_3, 11), (17, h160_1));
-        let tran = generate_valid_tran(&state, &account, &h160_2);
+        let tran = generate_valid_tran(&state, &account, &h160_2, 1, 0);
         assert!(tran.is_some());
-        let tran = generate_valid_tran(&state, &account_2, &h160_2);
+        let tran = generate_valid_tran(&state, &account_2, &h160_2, 1, 0);
         assert!(!tran.is_some());
 
+        // immature coinbase outputs are not spent
+        let mut state = State::new();
+        let origin = UtxoOrigin { height: 5, coinbase: true };
+        state.insert_with_origin((h256_1, 0), (COINBASE_REWARD, h160_1), origin);
+        assert!(generate_valid_tran(&state, &account, &h160_2, 7, 3).is_none());
+        let tran = generate_valid_tran(&state, &account, &h160_2, 8, 3);
+        assert!(tran.unwrap().transaction.inputs[0] == TxInput::new(h256_1, 0));
+
         let mut state = State::new();
         let h256_1 = generate_random_hash();
         let h256_2 = generate_random_hash();
@@ -428,7 +484,7 @@ pub mod tests {
         let h160_2 = generate_random_h160();
         state.insert((h256_1, 1), (1, h160_1));
         state.insert((h256_2, 5), (7, h160_2));
-        let tran = generate_valid_tran(&state, &account, &h160_2);
+        let tran = generate_valid_tran(&state, &account, &h160_2, 1, 0);
         assert!(tran.is_some());
         let tran = tran.unwrap();
         assert!(tran.transaction.inputs.len() == 1);
@@ -18,6 +18,12 @@ pub mod helper;
 pub mod mempool;
 pub mod transaction_generator;
 pub mod peers;
+pub mod store;
+pub mod orphan_pool;
+pub mod orphan_block_pool;
+pub mod orphan_tran_pool;
+pub mod chain_params;
+pub mod block_assembler;
 #[allow(unused_variables)] // TODO: remove
 #[allow(dead_code)] // TODO: remove
 pub mod spread;
@@ -28,6 +34,7 @@ use log::{error, info};
 use api::Server as ApiServer;
 use network::{server, worker};
 use std::net;
+use std::path::Path;
 use std::process;
 use std::thread;
 use std::sync::{Arc, Mutex};
@@ -70,10 +77,46 @@ fn run_regular_server(matches: ArgMatches) {
 
     // create peer(for transaction)
     let peers = Arc::new(Mutex::new(Peers::new()));
-    // create blockchain
-    let blockchain = Arc::new(Mutex::new(Blockchain::new()));
-    // create mempool
-    let mempool = Arc::new(Mutex::new(MemPool::new()));
+    // create blockchain, restoring it from data directory if given
+    let blockchain = match matches.value_of("data_dir") {
+        Some(dir) => Blockchain::open(Path::new(dir)).unwrap_or_else(|e| {
+            error!("Error opening data directory: {}", e);
+            process::exit(1);
+        }),
+        None => Blockchain::new(),
+    };
+    // create mempool, validating transactions against the tip, and reload the one dumped on last shutdown
+    let mut mempool = MemPool::new();
+    if let Some(expiry) = matches.value_of("mempool_expiry") {
+        let expiry = expiry.parse::<u64>().unwrap_or_else(|e| {
+            error!("Error parsing mempool expiry: {}", e);
+            process::exit(1);
+        });
+        mempool.set_expiry(time::Duration::from_millis(expiry), time::Duration::from_millis(config::STEM_BUFFER_EXPIRY));
+    }
+    mempool.set_tip(blockchain.tip_block_state(), blockchain.length(), blockchain.chain_params());
+    if let Some(dir) = matches.value_of("data_dir") {
+        if let Err(e) = mempool.load(Path::new(dir)) {
+            error!("Error reloading mempool, start with an empty one: {}", e);
+        }
+    }
+    let blockchain = Arc::new(Mutex::new(blockchain));
+    let mempool = Arc::new(Mutex::new(mempool));
+
+    // dump mempool on shutdown
+    if let Some(dir) = matches.value_of("data_dir") {
+        let dir = Path::new(dir).to_path_buf();
+        let mempool = mempool.clone();
+        ctrlc::set_handler(move || {
+            if let Err(e) = mempool.lock().unwrap().dump(&dir) {
+                error!("Error dumping mempool: {}", e);
+            }
+            process::exit(0);
+        }).unwrap_or_else(|e| {
+            error!("Error setting shutdown handler: {}", e);
+            process::exit(1);
+        });
+    }
 
     let spreader_type = config::SPREADER;
     let using_dandelion = spreader_type == Spreader::Dandelion || spreader_type == Spreader::DandelionPlus;
@@ -126,12 +169,21 @@ fn run_regular_server(matches: ArgMatches) {
     worker_ctx.start();
 
     // start the miner
-    let (miner_ctx, miner) = miner::new(
+    let mining_threads = matches
+        .value_of("mining_threads")
+        .unwrap()
+        .parse::<usize>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing mining threads: {}", e);
+            process::exit(1);
+        });
+    let (mut miner_ctx, miner) = miner::new(
         server.clone(),
         blockchain.clone(),
         mempool.clone(),
         key_pair.clone(),
     );
+    miner_ctx.set_threads(mining_threads);
     miner_ctx.start();
 
     // connect to known peers
@@ -141,8 +193,9 @@ fn run_regular_server(matches: ArgMatches) {
     }
 
     thread::sleep(time::Duration::from_millis(200));
-    // introduce myself to network_peers
+    // introduce myself to network_peers, and catch up on transactions announced before joining
     server.broadcast(Message::Introduce((addr, pub_key, port)), None);
+    server.broadcast(Message::GetMempool, None);
 
     // start the API server
     ApiServer::start(
@@ -210,7 +263,10 @@ fn run_supernode(matches: ArgMatches) {
 
     let peers = Arc::new(Mutex::new(Peers::new()));
     let blockchain = Arc::new(Mutex::new(Blockchain::new()));
-    let mempool = Arc::new(Mutex::new(MemPool::new()));
+    // the supernode keeps its pool off the tip, so it records whatever transactions it hears of
+    let mut mempool = MemPool::new();
+    mempool.set_check_trans(false);
+    let mempool = Arc::new(Mutex::new(mempool));
 
     for addr in nodes_addr.iter() {
         let (msg_tx, msg_rx) = channel::unbounded();
@@ -239,6 +295,7 @@ fn run_supernode(matches: ArgMatches) {
 
         helper::connect_peers(&server, &known_peers);
         server.broadcast(Message::Introduce((account.addr, pub_key, addr.port())), None);
+        server.broadcast(Message::GetMempool, None);
     }
 
     let (msg_tx, _) = channel::unbounded();
@@ -288,6 +345,9 @@ fn main() {
      (@arg api_addr: --api [ADDR] default_value("127.0.0.1:7000") "Sets the IP address and the port of the API server")
      (@arg known_peer: -c --connect ... [PEER] "Sets the peers to connect to at start")
      (@arg p2p_workers: --("p2p-workers") [INT] default_value("4") "Sets the number of worker threads for P2P server")
+     (@arg mining_threads: --("mining-threads") [INT] default_value("1") "Sets the number of threads searching nonces for the miner")
+     (@arg data_dir: --("data-dir") [DIR] "Sets the directory to persist blocks and mempool in; both are kept in memory only if not set")
+     (@arg mempool_expiry: --("mempool-expiry") [MS] "Sets the time(ms) a transaction can stay in mempool")
      (@arg supernode: --supernode "Run as a super node")
      (@arg probe: -p --probe [INT] default_value("2") "Number of connect to each regular server for supernode")
     )
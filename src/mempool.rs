@@ -1,30 +1,92 @@
-use crate::crypto::hash::H256;
+use crate::crypto::hash::{H256, H160};
 use crate::transaction::{SignedTransaction, TxInput};
-use crate::block::Content;
-use crate::config::POOL_SIZE_LIMIT;
+use crate::block::{BlockValidationError, Content, State};
+use crate::blockchain::TipChange;
+use crate::chain_params::ChainParams;
+use crate::block_assembler::BlockAssembler;
+use crate::orphan_tran_pool::OrphanTranPool;
+use crate::config::{POOL_SIZE_LIMIT, MIN_RELAY_FEE_RATE, INCREMENTAL_FEE_RATE, MIN_FEE_HALF_LIFE,
+                    MEMPOOL_EXPIRY, STEM_BUFFER_EXPIRY, ORPHAN_TRAN_POOL_SIZE_LIMIT,
+                    ORPHAN_TRAN_PEER_LIMIT, ORPHAN_TRAN_EXPIRY};
 use crate::helper;
 
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter};
 use std::net::SocketAddr;
-use log::debug;
+use std::path::Path;
+use std::time::{Duration, Instant};
+use log::{debug, info};
 use ring::signature::Ed25519KeyPair;
-use crate::helper::generate_signed_coinbase_transaction;
+use serde::{Serialize, Deserialize};
+
+static MEMPOOL_FILE: &str = "mempool.dat";
+static MEMPOOL_TMP_FILE: &str = "mempool.dat.tmp";
+
+// Pool and stem buffer with the time(ms) each transaction entered, as persisted across restarts
+#[derive(Serialize, Deserialize)]
+struct MempoolDump {
+    trans: Vec<(SignedTransaction, u64)>,
+    stem: Vec<(SignedTransaction, u64)>,
+    private: Vec<H256>,
+}
 
 pub struct MemPool {
     pub transactions: HashMap<H256, SignedTransaction>,
-    pub input_tran_map: HashMap<TxInput, (H256, u64)>, //Key: TxInput, Val: (hash, timestamp)
+    pub input_tran_map: HashMap<TxInput, H256>, //Key: TxInput, Val: hash of the pool transaction spending it
     pub ts_addr_map: HashMap<H256, Vec<(SocketAddr, i64)>>,
-    dandelion_buffer: HashMap<H256, SignedTransaction>,
+    dandelion_buffer: HashMap<H256, (SignedTransaction, u64)>,  // with the time(ms) each transaction entered
+    private: HashSet<H256>,  // own pool transactions still in the stem phase, not to be announced
+    orphans: OrphanTranPool,  // transactions waiting for the transactions whose outputs they spend
+    children: HashMap<H256, HashSet<H256>>,  // pool transactions spending outputs of a pool transaction
+    fees: HashMap<H256, (u64, usize)>,  // fee and serialized size of each pool transaction
+    by_fee_rate: BTreeSet<(u64, H256)>,  // pool transactions from the lowest fee rate
+    rolling_min_fee_rate: u64,  // raised on eviction, then decays
+    rolling_min_fee_updated: Instant,
+    fee_filter_sent: u64,  // min fee rate last announced to peers
+    size_limit: usize,  // max number of transactions
+    entered: HashMap<H256, u64>,  // time(ms) each pool transaction entered
+    by_entry_time: BTreeSet<(u64, H256)>,  // pool transactions from the oldest
+    expiry: Duration,
+    stem_expiry: Duration,
+    tip_state: State,  // utxo of the longest chain tip, transactions are validated against it plus pool outputs
+    next_height: usize,  // height of the next block on the tip
+    coinbase_maturity: usize,
+    check_trans: bool,  // can only be false in test or on a supernode
+    changes: u64,  // number of times a transaction entered or left the pool
 }
 
 impl MemPool {
     // Create an empty mempool
     pub fn new() -> Self {
+        Self::with_size_limit(POOL_SIZE_LIMIT)
+    }
+
+    pub fn with_size_limit(size_limit: usize) -> Self {
         Self {
             transactions: HashMap::new(),
             input_tran_map: HashMap::new(),
             ts_addr_map: HashMap::new(),
             dandelion_buffer: HashMap::new(),
+            private: HashSet::new(),
+            orphans: OrphanTranPool::new(ORPHAN_TRAN_POOL_SIZE_LIMIT, ORPHAN_TRAN_PEER_LIMIT,
+                                         Duration::from_millis(ORPHAN_TRAN_EXPIRY)),
+            children: HashMap::new(),
+            fees: HashMap::new(),
+            by_fee_rate: BTreeSet::new(),
+            rolling_min_fee_rate: 0,
+            rolling_min_fee_updated: Instant::now(),
+            fee_filter_sent: MIN_RELAY_FEE_RATE,
+            size_limit,
+            entered: HashMap::new(),
+            by_entry_time: BTreeSet::new(),
+            expiry: Duration::from_millis(MEMPOOL_EXPIRY),
+            stem_expiry: Duration::from_millis(STEM_BUFFER_EXPIRY),
+            tip_state: State::new(),
+            next_height: 1,
+            coinbase_maturity: ChainParams::main().coinbase_maturity,
+            check_trans: true,
+            changes: 0,
         }
     }
 
@@ -37,16 +99,284 @@ impl MemPool {
         return mempool;
     }
 
-    // Add a valid transaction after signature check && double-spend txinput check
+    // Add a valid transaction after signature check, utxo check, fee rate check && double-spend txinput check
     pub fn add_with_check(&mut self, tran: &SignedTransaction) -> bool {
-        if self.exist(&tran.hash) || !tran.sign_check() || self.size() >= POOL_SIZE_LIMIT {
-            return false;
+        !self.add_with_check_from(tran, None).0.is_empty()
+    }
+
+    // Same as add_with_check, a transaction spending outputs unknown yet is kept as an orphan of the peer
+    // who supplied it instead, and orphans waiting for an added transaction are retried. Return hashes of
+    // the transactions getting into the pool, parents before children, and of the pool transactions they
+    // replaced together with their descendants
+    pub fn add_with_check_from(&mut self, tran: &SignedTransaction, peer: Option<SocketAddr>) -> (Vec<H256>, Vec<H256>) {
+        if let Some(mut replaced) = self.try_add(tran) {
+            let mut added = vec![tran.hash];
+            let (orphans_added, orphans_replaced) = self.accept_orphans(tran);
+            added.extend(orphans_added);
+            replaced.extend(orphans_replaced);
+            // an orphan may replace a transaction added just before it
+            added.retain(|hash| self.exist(hash));
+            return (added, replaced);
+        }
+        if self.check_trans && !self.exist(&tran.hash) && !self.orphans.contains(&tran.hash) && tran.sign_check() {
+            let missing = self.missing_inputs(tran);
+            if !missing.is_empty() {
+                debug!("Keep {:?} as an orphan, {} inputs are missing", tran.hash, missing.len());
+                self.orphans.insert(tran.clone(), missing, peer);
+            }
+        }
+        (vec![], vec![])
+    }
+
+    // Retry orphans spending outputs of a transaction now in the pool or in a block, and then their own
+    // orphans. Return hashes of those getting into the pool and of the pool transactions they replaced
+    fn accept_orphans(&mut self, parent: &SignedTransaction) -> (Vec<H256>, Vec<H256>) {
+        let mut added = Vec::<H256>::new();
+        let mut replaced = Vec::<H256>::new();
+        let mut parents = vec![parent.clone()];
+        while let Some(parent) = parents.pop() {
+            for orphan in self.orphans.remove_spending(&parent) {
+                if let Some(hashes) = self.try_add(&orphan.tran) {
+                    debug!("Orphan {:?} gets into mempool", orphan.tran.hash);
+                    added.push(orphan.tran.hash);
+                    replaced.extend(hashes);
+                    parents.push(orphan.tran);
+                } else {
+                    let missing = self.missing_inputs(&orphan.tran);
+                    self.orphans.insert(orphan.tran, missing, orphan.peer);
+                }
+            }
+        }
+        (added, replaced)
+    }
+
+    // Inputs spending outputs neither on the tip nor in the pool, nor spent by the pool
+    fn missing_inputs(&self, tran: &SignedTransaction) -> Vec<TxInput> {
+        tran.transaction.inputs.iter()
+            .filter(|input| {
+                let key = (input.pre_hash, input.index);
+                !self.tip_state.contains_key(&key) && self.pool_output(&key).is_none()
+                    && !self.input_tran_map.contains_key(input)
+            })
+            .cloned()
+            .collect()
+    }
+
+    pub fn is_orphan(&self, hash: &H256) -> bool {
+        self.orphans.contains(hash)
+    }
+
+    // Hashes of the transactions an orphan is waiting for, to be requested from its peer
+    pub fn missing_parents(&self, hash: &H256) -> Vec<H256> {
+        self.orphans.missing_parents(hash)
+    }
+
+    pub fn orphan_count(&self) -> usize {
+        self.orphans.len()
+    }
+
+    // Grows whenever the pool transactions change, so a miner can tell its block content is outdated
+    pub fn changes(&self) -> u64 {
+        self.changes
+    }
+
+    // Same as add_with_check, return the pool transactions replaced by the new one with their descendants,
+    // None if it is rejected. A full pool evicts its lowest fee rates, which may be the new transaction itself
+    pub fn try_add(&mut self, tran: &SignedTransaction) -> Option<Vec<H256>> {
+        if self.exist(&tran.hash) || !tran.sign_check() {
+            return None;
+        }
+        let fee = match self.checked_fee(tran) {
+            Ok(fee) => fee,
+            Err(e) => {
+                debug!("Reject {:?} from mempool: {}", tran.hash, e);
+                return None;
+            }
+        };
+        let rate = fee_rate(fee, tran.size());
+        if rate < self.min_fee_rate() {
+            debug!("Reject {:?} from mempool: fee rate {} below {}", tran.hash, rate, self.min_fee_rate());
+            return None;
+        }
+        let replaced = self.try_insert(tran, fee)?;
+        self.trim_to_size();
+        if self.exist(&tran.hash) {
+            Some(replaced)
+        } else {
+            None
+        }
+    }
+
+    // Fee of a transaction on the tip plus the pool, unknown ones are taken as 0 if unchecked
+    fn checked_fee(&self, tran: &SignedTransaction) -> Result<u64, BlockValidationError> {
+        match self.check_tran(tran) {
+            Err(_) if !self.check_trans => Ok(0),
+            result => result,
         }
-        return self.try_insert(tran);
+    }
+
+    // Evict the lowest fee rate transactions with their descendants until the pool fits in its limit,
+    // and raise the min fee rate above the evicted ones
+    fn trim_to_size(&mut self) {
+        while self.size() > self.size_limit {
+            let (rate, hash) = match self.by_fee_rate.iter().next() {
+                Some(lowest) => *lowest,
+                None => break,
+            };
+            let evicted = self.remove_with_descendants(&hash);
+            debug!("Evict {} transactions from full mempool at fee rate {}", evicted.len(), rate);
+            let new_min = rate + INCREMENTAL_FEE_RATE;
+            if new_min > self.min_fee_rate() {
+                self.rolling_min_fee_rate = new_min;
+                self.rolling_min_fee_updated = Instant::now();
+            }
+        }
+    }
+
+    // Min fee rate to enter the pool, raised by evictions and halving every MIN_FEE_HALF_LIFE afterwards
+    pub fn min_fee_rate(&self) -> u64 {
+        let rolling = decayed_fee_rate(self.rolling_min_fee_rate, self.rolling_min_fee_updated.elapsed(),
+                                       Duration::from_millis(MIN_FEE_HALF_LIFE));
+        rolling.max(MIN_RELAY_FEE_RATE)
+    }
+
+    // The min fee rate to announce to peers if it changed since the last announcement
+    pub fn fee_filter_update(&mut self) -> Option<u64> {
+        let rate = self.min_fee_rate();
+        if rate == self.fee_filter_sent {
+            return None;
+        }
+        self.fee_filter_sent = rate;
+        Some(rate)
+    }
+
+    // Check a transaction can be in the next block on the tip, given its inputs are either in the tip
+    // state or outputs of pool transactions, and return its fee. Inputs spent by other pool transactions
+    // are left to the conflict check of try_insert
+    fn check_tran(&self, tran: &SignedTransaction) -> Result<u64, BlockValidationError> {
+        let sender_addr: H160 = tran.sender_addr();
+        let mut spent = HashSet::<(H256, u32)>::new();
+        let mut input_val = 0u64;
+        for input in tran.transaction.inputs.iter() {
+            let key = (input.pre_hash, input.index);
+            if !spent.insert(key) {
+                return Err(BlockValidationError::DoubleSpend(tran.hash));
+            }
+            let (val, owner_addr) = match self.tip_state.get(&key) {
+                Some(utxo) => {
+                    if !self.tip_state.is_mature(&key, self.next_height, self.coinbase_maturity) {
+                        return Err(BlockValidationError::ImmatureCoinbaseSpend(tran.hash));
+                    }
+                    *utxo
+                }
+                None => self.pool_output(&key).ok_or(BlockValidationError::DoubleSpend(tran.hash))?,
+            };
+            if owner_addr != sender_addr {
+                return Err(BlockValidationError::WrongOwner(tran.hash));
+            }
+            input_val = input_val.checked_add(val).ok_or(BlockValidationError::UnbalancedTransaction(tran.hash))?;
+        }
+        let output_val = tran.transaction.outputs.iter().try_fold(0u64, |sum, o| sum.checked_add(o.val))
+            .ok_or(BlockValidationError::UnbalancedTransaction(tran.hash))?;
+        input_val.checked_sub(output_val).ok_or(BlockValidationError::UnbalancedTransaction(tran.hash))
+    }
+
+    // Value and owner of an output of a pool transaction
+    fn pool_output(&self, key: &(H256, u32)) -> Option<(u64, H160)> {
+        let output = self.transactions.get(&key.0)?.transaction.outputs.get(key.1 as usize)?;
+        Some((output.val, output.rec_address))
     }
 
     pub fn insert_buffer_tran(&mut self, tran: SignedTransaction) {
-        self.dandelion_buffer.insert(tran.hash.clone(), tran.clone());
+        self.dandelion_buffer.insert(tran.hash, (tran, helper::get_current_time_in_millis()));
+    }
+
+    // Set how long transactions can stay in the pool and in the stem buffer
+    pub fn set_expiry(&mut self, expiry: Duration, stem_expiry: Duration) {
+        self.expiry = expiry;
+        self.stem_expiry = stem_expiry;
+    }
+
+    // Drop pool transactions older than the expiry with their descendants, and stem transactions older
+    // than the stem expiry. Return the number of transactions dropped
+    pub fn expire(&mut self) -> usize {
+        self.orphans.expire();
+        let now = helper::get_current_time_in_millis();
+        let cutoff = now.saturating_sub(self.expiry.as_millis() as u64);
+        let mut expired = 0usize;
+        while let Some((entered, hash)) = self.by_entry_time.iter().next().copied() {
+            if entered >= cutoff {
+                break;
+            }
+            expired += self.remove_with_descendants(&hash).len();
+        }
+        let stem_cutoff = now.saturating_sub(self.stem_expiry.as_millis() as u64);
+        let stem_num = self.dandelion_buffer.len();
+        self.dandelion_buffer.retain(|_, (_, entered)| *entered >= stem_cutoff);
+        expired += stem_num - self.dandelion_buffer.len();
+        if expired > 0 {
+            debug!("{} transactions expired from mempool", expired);
+        }
+        expired
+    }
+
+    // Keep the time a transaction first entered when it is put back
+    fn restamp(&mut self, hash: &H256, entered: u64) {
+        if let Some(old) = self.entered.insert(*hash, entered) {
+            self.by_entry_time.remove(&(old, *hash));
+            self.by_entry_time.insert((entered, *hash));
+        }
+    }
+
+    // Write the pool and the stem buffer into dir, replacing the last dump at once
+    pub fn dump(&self, dir: &Path) -> io::Result<()> {
+        let dump = MempoolDump {
+            trans: self.transactions.values().map(|t| (t.clone(), self.entered[&t.hash])).collect(),
+            stem: self.dandelion_buffer.values().cloned().collect(),
+            private: self.private.iter().copied().collect(),
+        };
+        fs::create_dir_all(dir)?;
+        let tmp_path = dir.join(MEMPOOL_TMP_FILE);
+        let file = File::create(&tmp_path)?;
+        bincode::serialize_into(BufWriter::new(&file), &dump)
+            .map_err(io::Error::other)?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, dir.join(MEMPOOL_FILE))?;
+        info!("Dumped {} transactions and {} stem transactions of mempool to {:?}",
+              dump.trans.len(), dump.stem.len(), dir);
+        Ok(())
+    }
+
+    // Reload the pool and the stem buffer dumped into dir, keeping those still valid on the current tip
+    // and not expired. Return the number of transactions reloaded into the pool
+    pub fn load(&mut self, dir: &Path) -> io::Result<usize> {
+        let path = dir.join(MEMPOOL_FILE);
+        if !path.exists() {
+            return Ok(0);
+        }
+        let dump: MempoolDump = bincode::deserialize_from(BufReader::new(File::open(&path)?))
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let entered: HashMap<H256, u64> = dump.trans.iter().map(|(t, time)| (t.hash, *time)).collect();
+        let trans: Vec<SignedTransaction> = dump.trans.into_iter()
+            .map(|(t, _)| t)
+            .filter(|t| !self.exist(&t.hash) && t.sign_check())
+            .collect();
+        let total = trans.len();
+        let before = self.size();
+        self.readmit(trans, &entered);
+        for hash in dump.private.into_iter() {
+            self.mark_private(&hash);
+        }
+        for (tran, time) in dump.stem.into_iter() {
+            if !self.exist(&tran.hash) && tran.sign_check() && self.checked_fee(&tran).is_ok() {
+                self.dandelion_buffer.insert(tran.hash, (tran, time));
+            }
+        }
+        self.expire();
+        let loaded = self.size().saturating_sub(before);
+        info!("Reloaded {} of {} dumped transactions and {} stem transactions into mempool from {:?}",
+              loaded, total, self.dandelion_buffer.len(), dir);
+        Ok(loaded)
     }
 
     pub fn insert_ts_and_addr(&mut self, hash: H256, addr: SocketAddr) {
@@ -58,32 +388,64 @@ impl MemPool {
         }
     }
 
-    // try insert transaction if no conflict input
-    // or the transaction has the minimal timestamp among conflict trans
-    fn try_insert(&mut self, tran: &SignedTransaction) -> bool {
+    // try insert transaction if no conflict input, or replace the conflict trans if it pays strictly more
+    // than them with their descendants in total, and at a strictly higher fee rate than each of them.
+    // Return the replaced trans with their descendants, None if it is rejected
+    fn try_insert(&mut self, tran: &SignedTransaction, fee: u64) -> Option<Vec<H256>> {
         debug!("Try to add {:?} into mempool", tran);
-        let mut to_remove_hash: Vec<H256> = Vec::new();
-        let ts = tran.transaction.ts;
         self.remove_buffered_tran(&tran.hash);
-        for input in tran.transaction.inputs.iter() {
-            if let Some((conf_hash, conf_ts)) = self.input_tran_map.get(input) {
-                if ts < *conf_ts {
-                    to_remove_hash.push(conf_hash.clone());
-                } else {
-                    return false; // conflict and has bigger timestamp
+        let mut conflicts: Vec<H256> = tran.transaction.inputs.iter()
+            .filter_map(|input| self.input_tran_map.get(input).copied())
+            .collect();
+        conflicts.sort();
+        conflicts.dedup();
+
+        let mut replaced = Vec::<H256>::new();
+        if !conflicts.is_empty() {
+            let mut seen = HashSet::<H256>::new();
+            for conf_hash in conflicts.iter() {
+                for hash in self.descendants(conf_hash) {
+                    if seen.insert(hash) {
+                        replaced.push(hash);
+                    }
                 }
             }
-        }
-        // remove conflict trans
-        for conf_hash in to_remove_hash.iter() {
-            self.remove_tran_internel(conf_hash);
+            let replaced_fee: u64 = replaced.iter().map(|h| self.fee_of(h).unwrap_or(0)).sum();
+            let rate = fee_rate(fee, tran.size());
+            let higher_rate = conflicts.iter().all(|h| rate > self.fee_rate_of(h).unwrap_or(0));
+            // spending an output of a replaced one would leave it with a missing input
+            let spends_replaced = tran.transaction.inputs.iter().any(|input| seen.contains(&input.pre_hash));
+            if fee <= replaced_fee || !higher_rate || spends_replaced {
+                debug!("Reject {:?} from mempool: not paying enough to replace {} transactions", tran.hash, replaced.len());
+                return None;
+            }
+            for hash in replaced.iter() {
+                self.remove_tran_internel(hash);
+            }
+            info!("Replace {} transactions in mempool by {:?}: {:?}", replaced.len(), tran.hash, replaced);
         }
 
         for input in tran.transaction.inputs.iter() {
-            self.input_tran_map.insert(input.clone(), (tran.hash, ts));
+            self.input_tran_map.insert(input.clone(), tran.hash);
+            if self.transactions.contains_key(&input.pre_hash) {
+                self.children.entry(input.pre_hash).or_default().insert(tran.hash);
+            }
         }
+        // children may get in first when the pool is refilled unchecked
+        for index in 0..tran.transaction.outputs.len() {
+            if let Some(spender) = self.input_tran_map.get(&TxInput::new(tran.hash, index as u32)) {
+                self.children.entry(tran.hash).or_default().insert(*spender);
+            }
+        }
+        let size = tran.size();
+        self.fees.insert(tran.hash, (fee, size));
+        self.by_fee_rate.insert((fee_rate(fee, size), tran.hash));
+        let now = helper::get_current_time_in_millis();
+        self.entered.insert(tran.hash, now);
+        self.by_entry_time.insert((now, tran.hash));
         self.transactions.insert(tran.hash.clone(), tran.clone());
-        return true;
+        self.changes += 1;
+        Some(replaced)
     }
 
     // Remove transactions from pool
@@ -100,9 +462,75 @@ impl MemPool {
         }
     }
 
+    // Remove a transaction only, its children then spend outputs either confirmed or missing
     fn remove_tran_internel(&mut self, hash: &H256) {
-        self.transactions.remove(hash);
+        if let Some(tran) = self.transactions.remove(hash) {
+            self.changes += 1;
+            for input in tran.transaction.inputs.iter() {
+                if let Some(spender) = self.input_tran_map.get(input) {
+                    if spender == hash {
+                        self.input_tran_map.remove(input);
+                    }
+                }
+                if let Some(siblings) = self.children.get_mut(&input.pre_hash) {
+                    siblings.remove(hash);
+                    if siblings.is_empty() {
+                        self.children.remove(&input.pre_hash);
+                    }
+                }
+            }
+        }
+        if let Some((fee, size)) = self.fees.remove(hash) {
+            self.by_fee_rate.remove(&(fee_rate(fee, size), *hash));
+        }
+        if let Some(entered) = self.entered.remove(hash) {
+            self.by_entry_time.remove(&(entered, *hash));
+        }
+        self.children.remove(hash);
         self.dandelion_buffer.remove(hash);
+        self.private.remove(hash);
+    }
+
+    // Remove a transaction no longer valid together with all its descendants, return their hashes
+    fn remove_with_descendants(&mut self, hash: &H256) -> Vec<H256> {
+        let removed = self.descendants(hash);
+        for h in removed.iter() {
+            self.remove_tran_internel(h);
+        }
+        if removed.len() > 1 {
+            debug!("Remove {:?} from mempool with {} descendants", hash, removed.len() - 1);
+        }
+        removed
+    }
+
+    // The transaction followed by all pool transactions depending on it, parents before children
+    pub fn descendants(&self, hash: &H256) -> Vec<H256> {
+        if !self.exist(hash) {
+            return vec![];
+        }
+        let mut visited = HashSet::<H256>::new();
+        let mut order = Vec::<H256>::new();
+        self.visit_descendants(hash, &mut visited, &mut order);
+        order.reverse();
+        order
+    }
+
+    // Depth-first, pushing a transaction after all its descendants
+    fn visit_descendants(&self, hash: &H256, visited: &mut HashSet<H256>, order: &mut Vec<H256>) {
+        if !visited.insert(*hash) {
+            return;
+        }
+        if let Some(children) = self.children.get(hash) {
+            for child in children.iter() {
+                self.visit_descendants(child, visited, order);
+            }
+        }
+        order.push(*hash);
+    }
+
+    // Pool transactions spending outputs of the given one
+    pub fn children_of(&self, hash: &H256) -> Vec<H256> {
+        self.children.get(hash).map_or_else(Vec::new, |c| c.iter().cloned().collect())
     }
 
     pub fn contains_buffered_tran(&self, hash: &H256) -> bool {
@@ -110,7 +538,7 @@ impl MemPool {
     }
 
     pub fn remove_buffered_tran(&mut self, hash: &H256) -> Option<SignedTransaction> {
-        return self.dandelion_buffer.remove(hash);
+        self.dandelion_buffer.remove(hash).map(|(tran, _)| tran)
     }
 
     // Remove inputs conflict with already-inserted-to-blockchain ones
@@ -118,25 +546,170 @@ impl MemPool {
         for trans in content.trans.iter() {
             let inputs = &trans.transaction.inputs;
             for input in inputs.iter() {
-                if let Some((tx_hash,_)) = self.input_tran_map.remove(input) {
+                if let Some(tx_hash) = self.input_tran_map.remove(input) {
                     debug!("Remove conflicting input from mempool {:?}", input);
-                    self.remove_tran_internel(&tx_hash);
+                    self.remove_with_descendants(&tx_hash);
                 }
             }
         }
     }
 
-    // Create content for miner's block to include as many transactions as possible
-    pub fn create_content(&self, key_pair: &Ed25519KeyPair) -> Content {
-        let mut trans = Vec::<SignedTransaction>::new();
+    // Update pool after the longest chain changed: drop transactions in connected blocks (and their
+    // conflicts), and put back those from disconnected blocks that are still valid in the next block,
+    // i.e. one at next_height on top of the new tip
+    pub fn apply_tip_change(&mut self, change: &TipChange, tip_state: State, next_height: usize, params: &ChainParams) {
+        self.expire();
+        let mut confirmed = HashSet::<H256>::new();
+        for block in change.connected.iter() {
+            let hashes = block.content.get_trans_hashes();
+            confirmed.extend(hashes.iter().cloned());
+            self.remove_trans(&hashes);
+            self.remove_conflict_tx_inputs(&block.content);
+        }
+        self.tip_state = tip_state;
+        self.next_height = next_height;
+        self.coinbase_maturity = params.coinbase_maturity;
 
-        let coinbase_trans = generate_signed_coinbase_transaction(key_pair);
-        trans.push(coinbase_trans);
+        // coinbase is gone with its block
+        let returned: Vec<SignedTransaction> = change.disconnected.iter().rev()
+            .flat_map(|block| block.content.trans.iter().skip(1))
+            .filter(|tran| !confirmed.contains(&tran.hash))
+            .cloned()
+            .collect();
+        let returned_num = returned.len();
+        let mut dropped = 0;
+        if change.is_reorg() {
+            // outputs of disconnected blocks are gone, and coinbase outputs may be immature again
+            dropped += self.return_trans(returned);
+            dropped += self.remove_invalid();
+        }
+        for block in change.connected.iter() {
+            for tran in block.content.trans.iter() {
+                self.accept_orphans(tran);
+            }
+        }
+        if change.is_reorg() {
+            info!("Reorg: {} blocks disconnected, {} connected; {} transactions back to mempool, {} dropped",
+                  change.disconnected.len(), change.connected.len(), returned_num, dropped);
+        } else if dropped > 0 {
+            debug!("{} transactions dropped from mempool on the new tip", dropped);
+        }
+    }
+
+    // Validate the pool against the tip at start, e.g. when the blockchain is restored from disk
+    pub fn set_tip(&mut self, tip_state: State, next_height: usize, params: &ChainParams) {
+        self.tip_state = tip_state;
+        self.next_height = next_height;
+        self.coinbase_maturity = params.coinbase_maturity;
+        self.remove_invalid();
+    }
+
+    // Put back transactions of disconnected blocks in block order, ahead of the pool: pool transactions
+    // spending the same outputs are removed with their descendants. Return the number of transactions
+    // dropped, either returned ones no longer valid or pool ones they replace
+    fn return_trans(&mut self, returned: Vec<SignedTransaction>) -> usize {
+        let mut dropped = 0;
+        for tran in returned.iter() {
+            if self.exist(&tran.hash) {
+                continue;
+            }
+            let fee = match self.checked_fee(tran) {
+                Ok(fee) => fee,
+                Err(e) => {
+                    debug!("Drop {:?} of a disconnected block: {}", tran.hash, e);
+                    dropped += 1;
+                    continue;
+                }
+            };
+            let conflicts: Vec<H256> = tran.transaction.inputs.iter()
+                .filter_map(|input| self.input_tran_map.get(input).copied())
+                .collect();
+            for hash in conflicts.iter() {
+                dropped += self.remove_with_descendants(hash).len();
+            }
+            self.try_insert(tran, fee);
+        }
+        let before = self.size();
+        self.trim_to_size();
+        dropped + before - self.size()
+    }
+
+    // Remove pool transactions spending outputs neither on the tip nor in the pool, or coinbase outputs
+    // not mature in the next block, with their descendants. Return the number removed
+    fn remove_invalid(&mut self) -> usize {
+        if !self.check_trans {
+            return 0;
+        }
+        let invalid: Vec<H256> = self.transactions.values()
+            .filter(|tran| tran.transaction.inputs.iter().any(|input| {
+                let key = (input.pre_hash, input.index);
+                match self.tip_state.get(&key) {
+                    Some(_) => !self.tip_state.is_mature(&key, self.next_height, self.coinbase_maturity),
+                    None => self.pool_output(&key).is_none(),
+                }
+            }))
+            .map(|tran| tran.hash)
+            .collect();
+        let mut removed = 0;
+        for hash in invalid.iter() {
+            removed += self.remove_with_descendants(hash).len();
+        }
+        removed
+    }
+
+    // Insert transactions valid on the tip, parents before children, keeping the given entry times
+    fn readmit(&mut self, mut pending: Vec<SignedTransaction>, entered: &HashMap<H256, u64>) {
+        // a child fails until its parent is back, so retry as long as something gets in
+        loop {
+            let pending_num = pending.len();
+            let mut left = Vec::<SignedTransaction>::new();
+            for tran in pending.into_iter() {
+                let inserted = match self.checked_fee(&tran) {
+                    Ok(fee) => self.try_insert(&tran, fee).is_some(),
+                    Err(_) => false,
+                };
+                if !inserted {
+                    left.push(tran);
+                } else if let Some(time) = entered.get(&tran.hash) {
+                    self.restamp(&tran.hash, *time);
+                }
+            }
+            pending = left;
+            if pending.is_empty() || pending.len() == pending_num {
+                break;
+            }
+        }
+        self.trim_to_size();
+    }
 
-        for (_, tran) in self.transactions.iter() {
-            trans.push(tran.clone());
+    // Tip state with pool transactions applied, i.e. coins to spend in a new transaction
+    // without waiting for the pool to be confirmed
+    pub fn utxo_view(&self) -> State {
+        let mut state = self.tip_state.clone();
+        for tran in self.transactions.values() {
+            for (index, output) in tran.transaction.outputs.iter().enumerate() {
+                state.insert((tran.hash, index as u32), (output.val, output.rec_address));
+            }
+        }
+        for input in self.input_tran_map.keys() {
+            state.remove(&(input.pre_hash, input.index));
         }
-        Content::new_with_trans(&trans)
+        state
+    }
+
+    // Create content for miner's block at the given height from transactions valid on the tip state,
+    // picked by fee rate within the block limits, the coinbase claims the subsidy plus their fees
+    pub fn create_content(&self, key_pair: &Ed25519KeyPair, height: usize, params: &ChainParams,
+                          extra_nonce: u32) -> Content {
+        BlockAssembler::new(&self.tip_state, height, params)
+            .with_extra_nonce(extra_nonce)
+            .assemble(self.transactions.values(), key_pair)
+    }
+
+    // Same as create_content, with an unsigned coinbase paying the given address, for an external miner
+    pub fn create_content_paying(&self, addr: &H160, height: usize, params: &ChainParams) -> Content {
+        BlockAssembler::new(&self.tip_state, height, params)
+            .assemble_paying(self.transactions.values(), addr)
     }
 
     // check existence of a hash
@@ -155,6 +728,42 @@ impl MemPool {
         trans
     }
 
+    // Keep an own transaction sent in the stem phase from being announced until it is fluffed
+    pub fn mark_private(&mut self, hash: &H256) {
+        if self.exist(hash) {
+            self.private.insert(*hash);
+        }
+    }
+
+    // An own transaction is fluffed, by this node or by another one announcing it
+    pub fn mark_public(&mut self, hash: &H256) {
+        self.private.remove(hash);
+    }
+
+    // Hashes of pool transactions that can be revealed to peers, from the oldest with parents before children.
+    // Own transactions still in the stem phase and those in the stem buffer are left out
+    pub fn public_hashes(&self) -> Vec<H256> {
+        let mut visited = HashSet::<H256>::new();
+        let mut order = Vec::<H256>::new();
+        for (_, hash) in self.by_entry_time.iter() {
+            self.visit_ancestors(hash, &mut visited, &mut order);
+        }
+        order.retain(|hash| !self.private.contains(hash) && !self.dandelion_buffer.contains_key(hash));
+        order
+    }
+
+    fn visit_ancestors(&self, hash: &H256, visited: &mut HashSet<H256>, order: &mut Vec<H256>) {
+        if !visited.insert(*hash) {
+            return;
+        }
+        for input in self.transactions[hash].transaction.inputs.iter() {
+            if self.exist(&input.pre_hash) {
+                self.visit_ancestors(&input.pre_hash, visited, order);
+            }
+        }
+        order.push(*hash);
+    }
+
     // Number of available transactions
     pub fn size(&self) -> usize {
         self.transactions.len()
@@ -164,16 +773,64 @@ impl MemPool {
     pub fn empty(&self) -> bool {
         self.transactions.is_empty()
     }
+
+    // Fee of a pool transaction, 0 if it is unknown in an unchecked pool
+    pub fn fee_of(&self, hash: &H256) -> Option<u64> {
+        self.fees.get(hash).map(|(fee, _)| *fee)
+    }
+
+    pub fn fee_rate_of(&self, hash: &H256) -> Option<u64> {
+        self.fees.get(hash).map(|(fee, size)| fee_rate(*fee, *size))
+    }
+
+    // Number of transactions and their total size of each fee rate range, as (lowest fee rate, count, bytes)
+    // ascending, where ranges are 0 and [2^k, 2^(k+1))
+    pub fn fee_histogram(&self) -> Vec<(u64, usize, usize)> {
+        let mut buckets = Vec::<(u64, usize, usize)>::new();
+        for (rate, hash) in self.by_fee_rate.iter() {
+            let lowest = if *rate == 0 { 0 } else { 1 << (63 - rate.leading_zeros()) };
+            let size = self.fees[hash].1;
+            match buckets.last_mut() {
+                Some(bucket) if bucket.0 == lowest => {
+                    bucket.1 += 1;
+                    bucket.2 += size;
+                }
+                _ => buckets.push((lowest, 1, size)),
+            }
+        }
+        buckets
+    }
+
+    pub fn set_check_trans(&mut self, b: bool) {
+        self.check_trans = b;
+    }
+}
+
+// Fee per 1000 bytes
+pub fn fee_rate(fee: u64, size: usize) -> u64 {
+    (fee as u128 * 1000 / size.max(1) as u128) as u64
+}
+
+// A fee rate halved for every half-life elapsed
+fn decayed_fee_rate(rate: u64, elapsed: Duration, half_life: Duration) -> u64 {
+    let halvings = elapsed.as_millis() / half_life.as_millis().max(1);
+    if halvings >= 64 {
+        0
+    } else {
+        rate >> halvings
+    }
 }
 
 #[cfg(any(test, test_utilities))]
 mod tests {
     use super::*;
     use crate::helper::*;
-    use crate::block::{Block, Content};
+    use crate::block::{Block, Content, UtxoOrigin};
+    use crate::blockchain::Blockchain;
+    use crate::transaction::TxOutput;
     use crate::network::message::Message;
     use crate::spread::Spreader;
-    use crate::config::EASIEST_DIF;
+    use crate::config::{EASIEST_DIF, COINBASE_REWARD};
     use crate::crypto::{key_pair, hash::Hashable};
     use std::net::{SocketAddr, IpAddr, Ipv4Addr};
     use std::thread::sleep;
@@ -182,6 +839,7 @@ mod tests {
     #[test]
     fn test_add_with_check() {
         let mut mempool = MemPool::new();
+        mempool.set_check_trans(false);
         assert!(mempool.empty());
         let t = generate_random_signed_transaction();
         let t_2 = generate_random_signed_transaction();
@@ -198,6 +856,7 @@ mod tests {
     #[test]
     fn test_remove_trans() {
         let mut mempool = MemPool::new();
+        mempool.set_check_trans(false);
         let t = generate_random_signed_transaction();
         let t_2 = generate_random_signed_transaction();
         let t_3 = generate_random_signed_transaction();
@@ -217,17 +876,281 @@ mod tests {
 
     #[test]
     fn test_create_trans() {
-        let key = key_pair::random();
+        let (key, addr, prev_hash, mut state) = generate_funded_state(&[10, 10]);
         let mut mempool = MemPool::new();
-        let mut t = generate_random_signed_transaction();
-        mempool.add_with_check(&t);
-        t = generate_random_signed_transaction();
-        mempool.add_with_check(&t);
-        t = generate_random_signed_transaction();
-        mempool.add_with_check(&t);
+        mempool.set_check_trans(false);  // the block content is checked on its own
+        let t_1 = generate_signed_transaction(&key, vec![TxInput::new(prev_hash, 0)], vec![TxOutput::new(addr, 9)]);
+        let t_2 = generate_signed_transaction(&key, vec![TxInput::new(prev_hash, 1)], vec![TxOutput::new(addr, 7)]);
+        let t_3 = generate_random_signed_transaction();  // input not in state
+        // spends a coinbase of height 1, not mature before height 1 + maturity
+        let params = ChainParams::main();
+        let coinbase_hash = generate_random_hash();
+        state.insert_with_origin((coinbase_hash, 0), (10, addr), UtxoOrigin { height: 1, coinbase: true });
+        let t_4 = generate_signed_transaction(&key, vec![TxInput::new(coinbase_hash, 0)], vec![TxOutput::new(addr, 5)]);
+        mempool.add_with_check(&t_1);
+        mempool.add_with_check(&t_2);
+        mempool.add_with_check(&t_3);
+        mempool.add_with_check(&t_4);
 
-        let content = mempool.create_content(&key);
+        let height = params.coinbase_maturity;
+        mempool.set_tip(state.clone(), height, &params);
+        let content = mempool.create_content(&key, height, &params, 0);
+        assert_eq!(content.trans.len(), 3);
+        assert!(!content.get_trans_hashes().contains(&t_3.hash));
+        assert!(!content.get_trans_hashes().contains(&t_4.hash));
+        assert!(content.trans[0].is_coinbase_tran());
+        assert_eq!(COINBASE_REWARD + 4, content.trans[0].transaction.outputs[0].val);
+        let header = generate_header(&generate_random_hash(), &content, 0, &generate_random_hash());
+        assert!(Block::new(header, content).try_generate_state(&state, height, &params).is_ok());
+
+        let content = mempool.create_content(&key, height + 1, &params, 0);
         assert_eq!(content.trans.len(), 4);
+        assert_eq!(COINBASE_REWARD + 9, content.trans[0].transaction.outputs[0].val);
+
+        // another extra nonce only changes the coinbase
+        let bumped = mempool.create_content(&key, height + 1, &params, 1);
+        assert_eq!(1, bumped.trans[0].transaction.extra_nonce);
+        assert!(bumped.trans[0].is_coinbase_tran());
+        assert_ne!(content.merkle_root(), bumped.merkle_root());
+        assert_eq!(content.get_trans_hashes()[1..], bumped.get_trans_hashes()[1..]);
+    }
+
+    #[test]
+    fn test_utxo_check() {
+        let (key, addr, prev_hash, mut state) = generate_funded_state(&[10, 10]);
+        let other_key = key_pair::random();
+        let params = ChainParams::main();
+        let coinbase_hash = generate_random_hash();
+        state.insert_with_origin((coinbase_hash, 0), (10, addr), UtxoOrigin { height: 1, coinbase: true });
+        let mut mempool = MemPool::new();
+        mempool.set_tip(state.clone(), 2, &params);
+
+        let missing = generate_signed_transaction(&key, vec![TxInput::new(generate_random_hash(), 0)],
+                                                  vec![TxOutput::new(addr, 1)]);
+        let not_owned = generate_signed_transaction(&other_key, vec![TxInput::new(prev_hash, 0)],
+                                                    vec![TxOutput::new(addr, 1)]);
+        let unbalanced = generate_signed_transaction(&key, vec![TxInput::new(prev_hash, 0)],
+                                                     vec![TxOutput::new(addr, 11)]);
+        let immature = generate_signed_transaction(&key, vec![TxInput::new(coinbase_hash, 0)],
+                                                   vec![TxOutput::new(addr, 10)]);
+        let same_input = TxInput::new(prev_hash, 0);
+        let twice = generate_signed_transaction(&key, vec![same_input.clone(), same_input],
+                                                vec![TxOutput::new(addr, 20)]);
+        for tran in [&missing, &not_owned, &unbalanced, &immature, &twice].iter() {
+            assert!(!mempool.add_with_check(tran));
+        }
+        assert!(mempool.empty());
+
+        // outputs of pool transactions can be spent as well
+        let parent = generate_signed_transaction(&key, vec![TxInput::new(prev_hash, 0)], vec![TxOutput::new(addr, 9)]);
+        let child = generate_signed_transaction(&key, vec![TxInput::new(parent.hash, 0)], vec![TxOutput::new(addr, 8)]);
+        let other = generate_signed_transaction(&key, vec![TxInput::new(prev_hash, 1)], vec![TxOutput::new(addr, 10)]);
+        assert!(mempool.add_with_check(&parent));
+        assert!(mempool.add_with_check(&child));
+        assert!(mempool.add_with_check(&other));
+
+        // on a tip where prev_hash:0 is spent, parent and its child are dropped; the coinbase is mature
+        state.remove(&(prev_hash, 0));
+        mempool.set_tip(state.clone(), 1 + params.coinbase_maturity, &params);
+        assert!(!mempool.exist(&parent.hash));
+        assert!(!mempool.exist(&child.hash));
+        assert!(mempool.exist(&other.hash));
+        assert!(mempool.add_with_check(&immature));
+    }
+
+    #[test]
+    fn test_unconfirmed_chain() {
+        let (key, addr, prev_hash, state) = generate_funded_state(&[10]);
+        let params = ChainParams::main();
+        let mut mempool = MemPool::new();
+        mempool.set_tip(state.clone(), 1, &params);
+
+        // conflicts with parent, paying more than parent and its descendants
+        let replacement = generate_signed_transaction(&key, vec![TxInput::new(prev_hash, 0)], vec![TxOutput::new(addr, 9)]);
+        let parent = generate_signed_transaction(&key, vec![TxInput::new(prev_hash, 0)],
+                                                 vec![TxOutput::new(addr, 5), TxOutput::new(addr, 5)]);
+        let child_1 = generate_signed_transaction(&key, vec![TxInput::new(parent.hash, 0)], vec![TxOutput::new(addr, 5)]);
+        let child_2 = generate_signed_transaction(&key, vec![TxInput::new(parent.hash, 1)], vec![TxOutput::new(addr, 5)]);
+        let grandchild = generate_signed_transaction(&key, vec![TxInput::new(child_1.hash, 0), TxInput::new(child_2.hash, 0)],
+                                                     vec![TxOutput::new(addr, 10)]);
+        for tran in [&parent, &child_1, &child_2, &grandchild].iter() {
+            assert!(mempool.add_with_check(tran));
+        }
+        let mut children = mempool.children_of(&parent.hash);
+        children.sort();
+        let mut expected = vec![child_1.hash, child_2.hash];
+        expected.sort();
+        assert_eq!(expected, children);
+        let descendants = mempool.descendants(&parent.hash);
+        assert_eq!(4, descendants.len());
+        assert_eq!(parent.hash, descendants[0]);
+        assert_eq!(grandchild.hash, descendants[3]);
+
+        // only the output of the grandchild is left to spend
+        let view = mempool.utxo_view();
+        assert_eq!(Some(&(10, addr)), view.get(&(grandchild.hash, 0)));
+        assert_eq!(10, view.coins_of(&addr).1);
+
+        // removing the parent for a conflict takes its descendants along
+        assert_eq!((vec![replacement.hash], descendants), mempool.add_with_check_from(&replacement, None));
+        assert_eq!(1, mempool.size());
+        assert!(mempool.exist(&replacement.hash));
+        assert!(mempool.children_of(&parent.hash).is_empty());
+
+        // a confirmed parent leaves its children in the pool
+        let child = generate_signed_transaction(&key, vec![TxInput::new(replacement.hash, 0)], vec![TxOutput::new(addr, 9)]);
+        assert!(mempool.add_with_check(&child));
+        assert_eq!(vec![replacement.hash, child.hash], mempool.descendants(&replacement.hash));
+        mempool.remove_trans(&vec![replacement.hash]);
+        assert!(mempool.exist(&child.hash));
+        assert!(mempool.descendants(&replacement.hash).is_empty());
+    }
+
+    #[test]
+    fn test_orphan_trans() {
+        let (key, addr, prev_hash, state) = generate_funded_state(&[10, 10]);
+        let params = ChainParams::main();
+        let mut mempool = MemPool::new();
+        mempool.set_tip(state.clone(), 1, &params);
+        let peer = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 17421);
+
+        // a grandchild and a child arrive before their parent
+        let parent = generate_signed_transaction(&key, vec![TxInput::new(prev_hash, 0)], vec![TxOutput::new(addr, 9)]);
+        let child = generate_signed_transaction(&key, vec![TxInput::new(parent.hash, 0)], vec![TxOutput::new(addr, 8)]);
+        let grandchild = generate_signed_transaction(&key, vec![TxInput::new(child.hash, 0)], vec![TxOutput::new(addr, 7)]);
+        assert!(mempool.add_with_check_from(&grandchild, Some(peer)).0.is_empty());
+        assert!(mempool.add_with_check_from(&child, Some(peer)).0.is_empty());
+        assert!(mempool.is_orphan(&child.hash));
+        assert_eq!(vec![parent.hash], mempool.missing_parents(&child.hash));
+        assert_eq!(2, mempool.orphan_count());
+
+        // an invalid transaction is not an orphan
+        let unbalanced = generate_signed_transaction(&key, vec![TxInput::new(prev_hash, 1)], vec![TxOutput::new(addr, 11)]);
+        assert!(mempool.add_with_check_from(&unbalanced, Some(peer)).0.is_empty());
+        assert!(!mempool.is_orphan(&unbalanced.hash));
+
+        assert_eq!((vec![parent.hash, child.hash, grandchild.hash], vec![]), mempool.add_with_check_from(&parent, None));
+        assert_eq!(0, mempool.orphan_count());
+
+        // an orphan whose parent gets into a block
+        let confirmed = generate_signed_transaction(&key, vec![TxInput::new(prev_hash, 1)], vec![TxOutput::new(addr, 10)]);
+        let orphan = generate_signed_transaction(&key, vec![TxInput::new(confirmed.hash, 0)], vec![TxOutput::new(addr, 10)]);
+        assert!(!mempool.add_with_check(&orphan));
+        assert!(mempool.is_orphan(&orphan.hash));
+        let content = Content::new_with_trans(&vec![generate_signed_coinbase_transaction(&key), confirmed.clone()]);
+        let header = generate_header(&generate_random_hash(), &content, 0, &generate_random_hash());
+        let block = Block::new(header, content);
+        let tip_state = block.try_generate_state(&state, 1, &params).unwrap();
+        let change = TipChange { disconnected: vec![], connected: vec![block] };
+        mempool.apply_tip_change(&change, tip_state, 2, &params);
+        assert!(mempool.exist(&orphan.hash));
+        assert!(!mempool.is_orphan(&orphan.hash));
+    }
+
+    #[test]
+    fn test_fee_rate_eviction() {
+        let (key, addr, prev_hash, state) = generate_funded_state(&[100; 4]);
+        let mut mempool = MemPool::with_size_limit(3);
+        mempool.set_tip(state.clone(), 1, &ChainParams::main());
+        let tran = |index: u32, fee: u64| generate_signed_transaction(
+            &key, vec![TxInput::new(prev_hash, index)], vec![TxOutput::new(addr, 100 - fee)]);
+        let low = tran(0, 1);
+        let mid = tran(1, 5);
+        let high = tran(2, 9);
+        let cheap = tran(3, 1);
+        let low_child = generate_signed_transaction(&key, vec![TxInput::new(low.hash, 0)], vec![TxOutput::new(addr, 97)]);
+        assert!(mempool.add_with_check(&low));
+        assert!(mempool.add_with_check(&mid));
+        assert!(mempool.add_with_check(&low_child));
+        assert_eq!(Some(2), mempool.fee_of(&low_child.hash));
+        assert_eq!(None, mempool.fee_filter_update());
+
+        // the lowest fee rate goes with its descendants
+        assert!(mempool.add_with_check(&high));
+        assert_eq!(2, mempool.size());
+        assert!(mempool.exist(&mid.hash));
+        assert!(mempool.exist(&high.hash));
+        let min_fee_rate = fee_rate(1, low.size()) + INCREMENTAL_FEE_RATE;
+        assert_eq!(min_fee_rate, mempool.min_fee_rate());
+        assert_eq!(Some(min_fee_rate), mempool.fee_filter_update());
+        assert_eq!(None, mempool.fee_filter_update());
+        assert!(!mempool.add_with_check(&cheap));
+
+        let histogram = mempool.fee_histogram();
+        assert_eq!(2, histogram.iter().map(|b| b.1).sum::<usize>());
+        assert_eq!(mid.size() + high.size(), histogram.iter().map(|b| b.2).sum::<usize>());
+        assert!(histogram.windows(2).all(|w| w[0].0 < w[1].0));
+        let mid_rate = mempool.fee_rate_of(&mid.hash).unwrap();
+        assert!(histogram[0].0 <= mid_rate && mid_rate < histogram[0].0 * 2);
+
+        let half_life = Duration::from_millis(MIN_FEE_HALF_LIFE);
+        assert_eq!(8, decayed_fee_rate(8, Duration::from_millis(0), half_life));
+        assert_eq!(2, decayed_fee_rate(8, half_life * 2, half_life));
+        assert_eq!(0, decayed_fee_rate(8, half_life * 100, half_life));
+    }
+
+    #[test]
+    fn test_expiry() {
+        let (key, addr, prev_hash, state) = generate_funded_state(&[10, 10]);
+        let mut mempool = MemPool::new();
+        mempool.set_tip(state.clone(), 1, &ChainParams::main());
+        let parent = generate_signed_transaction(&key, vec![TxInput::new(prev_hash, 0)], vec![TxOutput::new(addr, 9)]);
+        let child = generate_signed_transaction(&key, vec![TxInput::new(parent.hash, 0)], vec![TxOutput::new(addr, 8)]);
+        let stem = generate_signed_transaction(&key, vec![TxInput::new(prev_hash, 1)], vec![TxOutput::new(addr, 10)]);
+        assert!(mempool.add_with_check(&parent));
+        assert!(mempool.add_with_check(&child));
+        mempool.insert_buffer_tran(stem.clone());
+        sleep(time::Duration::from_millis(30));
+        let other = generate_signed_transaction(&key, vec![TxInput::new(prev_hash, 1)], vec![TxOutput::new(addr, 9)]);
+        assert!(mempool.add_with_check(&other));
+        assert_eq!(0, mempool.expire());
+
+        mempool.set_expiry(Duration::from_millis(20), Duration::from_millis(20));
+        assert_eq!(3, mempool.expire());
+        assert!(!mempool.exist(&parent.hash));
+        assert!(!mempool.exist(&child.hash));
+        assert!(!mempool.contains_buffered_tran(&stem.hash));
+        assert!(mempool.exist(&other.hash));
+    }
+
+    #[test]
+    fn test_dump_and_load() {
+        let (key, addr, prev_hash, mut state) = generate_funded_state(&[10; 3]);
+        let params = ChainParams::main();
+        let mut mempool = MemPool::new();
+        mempool.set_tip(state.clone(), 1, &params);
+        let parent = generate_signed_transaction(&key, vec![TxInput::new(prev_hash, 0)], vec![TxOutput::new(addr, 9)]);
+        let child = generate_signed_transaction(&key, vec![TxInput::new(parent.hash, 0)], vec![TxOutput::new(addr, 8)]);
+        let stem = generate_signed_transaction(&key, vec![TxInput::new(prev_hash, 2)], vec![TxOutput::new(addr, 10)]);
+        assert!(!mempool.add_with_check(&child));  // parent is not in yet
+        assert!(mempool.add_with_check(&parent));
+        assert!(mempool.exist(&child.hash));
+        mempool.mark_private(&child.hash);
+        mempool.insert_buffer_tran(stem.clone());
+        sleep(time::Duration::from_millis(30));
+        let confirmed = generate_signed_transaction(&key, vec![TxInput::new(prev_hash, 1)], vec![TxOutput::new(addr, 10)]);
+        assert!(mempool.add_with_check(&confirmed));
+        let dir = crate::store::tests::temp_data_dir();
+        mempool.dump(&dir).unwrap();
+
+        // confirmed is in a block by the restart
+        state.remove(&(prev_hash, 1));
+        state.insert((confirmed.hash, 0), (10, addr));
+        let mut reloaded = MemPool::new();
+        reloaded.set_tip(state.clone(), 2, &params);
+        assert_eq!(2, reloaded.load(&dir).unwrap());
+        assert!(reloaded.exist(&parent.hash));
+        assert!(reloaded.exist(&child.hash));
+        assert!(!reloaded.exist(&confirmed.hash));
+        assert!(reloaded.contains_buffered_tran(&stem.hash));
+        assert_eq!(vec![parent.hash, child.hash], reloaded.descendants(&parent.hash));
+        assert_eq!(vec![parent.hash], reloaded.public_hashes());
+
+        // entry times are kept
+        reloaded.set_expiry(Duration::from_millis(20), Duration::from_secs(3600));
+        assert_eq!(2, reloaded.expire());
+        assert!(reloaded.contains_buffered_tran(&stem.hash));
+        std::fs::remove_dir_all(&dir).unwrap();
     }
 
     #[test]
@@ -269,7 +1192,7 @@ mod tests {
         let content = Content::new_with_trans(&vec![t_1, t_2, t_3]);
         let header = generate_header(&chain_3.tip(), &content, 0, &difficulty);
         let new_block = Block::new(header, content);
-        chain_3.insert(&new_block);
+        chain_3.insert(&new_block).unwrap();
         drop(chain_3);
 
         // Server3 Only broadcasts a new block
@@ -293,14 +1216,49 @@ mod tests {
         let signed_tran_1 = generate_signed_transaction(&key, vec![input.clone()], Vec::new());
         sleep(time::Duration::from_millis(10));
         let signed_tran_2 = generate_signed_transaction(&key, vec![input.clone()], Vec::new());
-        assert!(mempool.try_insert(&signed_tran_2));
+        assert_eq!(Some(vec![]), mempool.try_insert(&signed_tran_2, 5));
         assert!(mempool.exist(&signed_tran_2.hash));
-        assert!(mempool.try_insert(&signed_tran_1));
-        assert!(!mempool.try_insert(&signed_tran_2));
+        // an earlier timestamp does not replace, a strictly higher fee does
+        assert!(mempool.try_insert(&signed_tran_1, 5).is_none());
+        assert!(mempool.exist(&signed_tran_2.hash));
+        assert_eq!(Some(vec![signed_tran_2.hash]), mempool.try_insert(&signed_tran_1, 6));
+        assert!(mempool.try_insert(&signed_tran_2, 6).is_none());
         assert!(mempool.exist(&signed_tran_1.hash));
         assert!(!mempool.exist(&signed_tran_2.hash));
     }
 
+    #[test]
+    fn test_replace_by_fee() {
+        let (key, addr, prev_hash, state) = generate_funded_state(&[100, 100]);
+        let mut mempool = MemPool::new();
+        mempool.set_tip(state.clone(), 1, &ChainParams::main());
+        let input_0 = TxInput::new(prev_hash, 0);
+        let input_1 = TxInput::new(prev_hash, 1);
+        let parent = generate_signed_transaction(&key, vec![input_0.clone()], vec![TxOutput::new(addr, 90)]);
+        let child = generate_signed_transaction(&key, vec![TxInput::new(parent.hash, 0)], vec![TxOutput::new(addr, 80)]);
+        assert_eq!(Some(vec![]), mempool.try_add(&parent));
+        assert_eq!(Some(vec![]), mempool.try_add(&child));
+
+        // not more than parent and child together
+        let low_fee = generate_signed_transaction(&key, vec![input_0.clone()], vec![TxOutput::new(addr, 80)]);
+        assert!(mempool.try_add(&low_fee).is_none());
+        // more in total, but at a lower fee rate
+        let low_rate = generate_signed_transaction(&key, vec![input_0.clone(), input_1],
+                                                   (0..40).map(|_| TxOutput::new(addr, 4)).collect());
+        assert!(fee_rate(40, low_rate.size()) < fee_rate(10, parent.size()));
+        assert!(mempool.try_add(&low_rate).is_none());
+        // spending an output of a transaction to replace
+        let spends_replaced = generate_signed_transaction(&key, vec![input_0.clone(), TxInput::new(parent.hash, 0)],
+                                                          vec![TxOutput::new(addr, 100)]);
+        assert!(mempool.try_add(&spends_replaced).is_none());
+        assert_eq!(2, mempool.size());
+
+        let replacement = generate_signed_transaction(&key, vec![input_0], vec![TxOutput::new(addr, 79)]);
+        assert_eq!(Some(vec![parent.hash, child.hash]), mempool.try_add(&replacement));
+        assert_eq!(1, mempool.size());
+        assert!(mempool.exist(&replacement.hash));
+    }
+
     #[test]
     fn test_dandelion_buffer() {
         let key = key_pair::random();
@@ -314,7 +1272,7 @@ mod tests {
         assert!(!mempool.contains_buffered_tran(&signed_tran_2.hash));
         mempool.insert_buffer_tran(signed_tran_1.clone());
         assert!(mempool.contains_buffered_tran(&signed_tran_1.hash));
-        mempool.try_insert(&signed_tran_1);
+        mempool.try_insert(&signed_tran_1, 0);
         assert!(!mempool.contains_buffered_tran(&signed_tran_1.hash));
         mempool.insert_buffer_tran(signed_tran_2.clone());
         assert!(mempool.contains_buffered_tran(&signed_tran_2.hash));
@@ -323,6 +1281,37 @@ mod tests {
         assert!(!mempool.contains_buffered_tran(&signed_tran_2.hash));
     }
 
+    #[test]
+    fn test_public_hashes() {
+        let mut mempool = MemPool::new();
+        mempool.set_check_trans(false);
+        let mut trans = Vec::new();
+        for _ in 0..4 {
+            let tran = generate_random_signed_transaction();
+            assert!(mempool.add_with_check(&tran));
+            trans.push(tran);
+            sleep(time::Duration::from_millis(2));
+        }
+        let hashes: Vec<H256> = trans.iter().map(|t| t.hash).collect();
+        assert_eq!(hashes, mempool.public_hashes());
+
+        // an own stem transaction, and one relayed in the stem phase
+        mempool.mark_private(&hashes[1]);
+        mempool.insert_buffer_tran(trans[2].clone());
+        assert_eq!(vec![hashes[0], hashes[3]], mempool.public_hashes());
+
+        mempool.mark_public(&hashes[1]);
+        mempool.remove_buffered_tran(&hashes[2]);
+        assert_eq!(hashes, mempool.public_hashes());
+
+        // not kept for a transaction out of the pool
+        mempool.mark_private(&hashes[0]);
+        mempool.remove_trans(&vec![hashes[0]]);
+        let other = generate_random_signed_transaction();
+        mempool.mark_private(&other.hash);
+        assert!(mempool.private.is_empty());
+    }
+
     #[test]
     fn test_remove_conflict_tx_inputs() {
         let key = key_pair::random();
@@ -333,13 +1322,104 @@ mod tests {
         sleep(time::Duration::from_millis(10));
         let signed_tran_2 = generate_signed_transaction(&key, vec![input.clone()], Vec::new());
         let content_2 = Content::new_with_trans(&vec![signed_tran_2.clone()]);
-        assert!(mempool.try_insert(&signed_tran_1));
+        assert!(mempool.try_insert(&signed_tran_1, 0).is_some());
         assert!(mempool.exist(&signed_tran_1.hash));
         assert!(!mempool.exist(&signed_tran_2.hash));
         mempool.remove_conflict_tx_inputs(&content_2);
         assert!(!mempool.exist(&signed_tran_1.hash));
     }
 
+    #[test]
+    fn test_apply_tip_change() {
+        /*
+         * structure:
+         * genesis <- block_1 <- block_2 <- block_3 (tran_1, tran_2, tran_3)
+         *                         ^
+         *                         -------  fork_3 (tran_4) <- fork_4
+         * tran_1 spends block_1's coinbase, tran_3 spends tran_1's output,
+         * tran_2 & tran_4 both spend block_2's coinbase
+         */
+        let key = key_pair::random();
+        let mut blockchain = Blockchain::new();
+        blockchain.set_chain_params(ChainParams { coinbase_maturity: 1, ..ChainParams::main() });
+        let mut mempool = MemPool::new();
+        let new_block = |parent: &H256, trans: Vec<SignedTransaction>| {
+            sleep(time::Duration::from_millis(2));  // keep coinbase transactions distinct
+            let mut all_trans = vec![generate_signed_coinbase_transaction(&key)];
+            all_trans.extend(trans);
+            let content = Content::new_with_trans(&all_trans);
+            let header = generate_header(parent, &content, 0, &gen_difficulty_array(EASIEST_DIF).into());
+            Block::new(header, content)
+        };
+        let block_1 = new_block(&blockchain.tip(), vec![]);
+        let block_2 = new_block(&block_1.hash, vec![]);
+        let addr = block_1.content.trans[0].sender_addr();
+        let coinbase_1 = TxInput::new(block_1.content.trans[0].hash, 0);
+        let coinbase_2 = TxInput::new(block_2.content.trans[0].hash, 0);
+        let tran_1 = generate_signed_transaction(&key, vec![coinbase_1], vec![TxOutput::new(addr, 50)]);
+        let tran_2 = generate_signed_transaction(&key, vec![coinbase_2.clone()], vec![TxOutput::new(addr, 40)]);
+        let tran_3 = generate_signed_transaction(&key, vec![TxInput::new(tran_1.hash, 0)],
+                                                 vec![TxOutput::new(addr, 30)]);
+        let tran_4 = generate_signed_transaction(&key, vec![coinbase_2], vec![TxOutput::new(addr, 20)]);
+        let block_3 = new_block(&block_2.hash, vec![tran_1.clone(), tran_2.clone(), tran_3.clone()]);
+        let fork_3 = new_block(&block_2.hash, vec![tran_4.clone()]);
+        let fork_4 = new_block(&fork_3.hash, vec![]);
+
+        for block in [&block_1, &block_2, &block_3].iter() {
+            let old_tip = blockchain.tip();
+            assert!(blockchain.insert(block).is_ok());
+            mempool.apply_tip_change(&blockchain.tip_change(&old_tip), blockchain.tip_block_state(),
+                                     blockchain.length(), blockchain.chain_params());
+        }
+        assert!(mempool.empty());
+        // coinbase_2 is spent on the current tip
+        assert!(!mempool.add_with_check(&tran_4));
+
+        let old_tip = blockchain.tip();
+        assert!(blockchain.insert(&fork_3).is_ok());
+        assert!(blockchain.insert(&fork_4).is_ok());
+        let change = blockchain.tip_change(&old_tip);
+        assert!(change.is_reorg());
+        mempool.apply_tip_change(&change, blockchain.tip_block_state(), blockchain.length(), blockchain.chain_params());
+
+        // tran_4 is confirmed, tran_2 conflicts with it, tran_1 & tran_3 are still valid
+        assert!(!mempool.exist(&tran_4.hash));
+        assert!(!mempool.exist(&tran_2.hash));
+        assert!(mempool.exist(&tran_1.hash));
+        assert!(mempool.exist(&tran_3.hash));
+        assert_eq!(2, mempool.size());
+    }
+
+    #[test]
+    fn test_return_trans_first() {
+        let (key, addr, prev_hash, mut state) = generate_funded_state(&[100]);
+        let params = ChainParams::main();
+        let coinbase_hash = generate_random_hash();
+        state.insert_with_origin((coinbase_hash, 0), (100, addr), UtxoOrigin { height: 1, coinbase: true });
+        let mut mempool = MemPool::new();
+        mempool.set_tip(state.clone(), 1 + params.coinbase_maturity, &params);
+
+        // pool transactions paying more than the one in the block, and spending a mature coinbase
+        let pool_tran = generate_signed_transaction(&key, vec![TxInput::new(prev_hash, 0)], vec![TxOutput::new(addr, 50)]);
+        let pool_child = generate_signed_transaction(&key, vec![TxInput::new(pool_tran.hash, 0)], vec![TxOutput::new(addr, 40)]);
+        let coinbase_spend = generate_signed_transaction(&key, vec![TxInput::new(coinbase_hash, 0)], vec![TxOutput::new(addr, 90)]);
+        assert!(mempool.add_with_check(&pool_tran));
+        assert!(mempool.add_with_check(&pool_child));
+        assert!(mempool.add_with_check(&coinbase_spend));
+        let block_tran = generate_signed_transaction(&key, vec![TxInput::new(prev_hash, 0)], vec![TxOutput::new(addr, 99)]);
+        let content = Content::new_with_trans(&vec![generate_signed_coinbase_transaction(&key), block_tran.clone()]);
+        let block = Block::new(generate_header(&generate_random_hash(), &content, 0, &generate_random_hash()), content);
+
+        // the block transaction is back ahead of the pool whatever the fees, and the coinbase is immature again
+        let change = TipChange { disconnected: vec![block], connected: vec![] };
+        mempool.apply_tip_change(&change, state, params.coinbase_maturity, &params);
+        assert!(mempool.exist(&block_tran.hash));
+        assert!(!mempool.exist(&pool_tran.hash));
+        assert!(!mempool.exist(&pool_child.hash));
+        assert!(!mempool.exist(&coinbase_spend.hash));
+        assert_eq!(1, mempool.size());
+    }
+
     #[test]
     fn test_ts_addr_map() {
         let mut mempool = MemPool::new();
@@ -381,4 +1461,102 @@ mod tests {
         sleep(time::Duration::from_millis(100));
         assert_eq!(2, mempool_2.lock().unwrap().ts_addr_map.get(&hash).unwrap().len());
     }
+
+    #[test]
+    fn test_get_mempool() {
+        let p2p_addr_1 = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 17141);
+        let p2p_addr_2 = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 17142);
+
+        let (_server_1, _, _, _, mempool_1, _, _) = new_server_env(p2p_addr_1, Spreader::Default, false);
+        let (server_2, _, _, _, mempool_2, _, _) = new_server_env(p2p_addr_2, Spreader::Default, false);
+
+        // server_1 has a pool before server_2 joins, with an own stem transaction
+        let trans: Vec<SignedTransaction> = (0..3).map(|_| generate_random_signed_transaction()).collect();
+        let mut pool_1 = mempool_1.lock().unwrap();
+        for tran in trans.iter() {
+            assert!(pool_1.add_with_check(tran));
+        }
+        pool_1.mark_private(&trans[2].hash);
+        drop(pool_1);
+
+        connect_peers(&server_2, &vec![p2p_addr_1]);
+        sleep(time::Duration::from_millis(100));
+        server_2.broadcast(Message::GetMempool, None);
+        sleep(time::Duration::from_millis(200));
+        let pool_2 = mempool_2.lock().unwrap();
+        assert_eq!(2, pool_2.size());
+        assert!(pool_2.exist(&trans[0].hash));
+        assert!(pool_2.exist(&trans[1].hash));
+        assert!(!pool_2.exist(&trans[2].hash));
+    }
+
+    #[test]
+    fn test_fee_filter() {
+        let p2p_addr_1 = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 17145);
+        let p2p_addr_2 = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 17146);
+
+        let (server_1, _, _, _, mempool_1, _, _) = new_server_env(p2p_addr_1, Spreader::Default, false);
+        // a supernode records every hash announced to it
+        let (server_2, _, _, _, mempool_2, _, account_2) = new_server_env(p2p_addr_2, Spreader::Default, true);
+        connect_peers(&server_2, &vec![p2p_addr_1]);
+        sleep(time::Duration::from_millis(100));
+
+        // server_2 takes transactions from a fee rate of 100, which a later introduction keeps
+        server_2.broadcast(Message::FeeFilter(100), None);
+        server_2.broadcast(Message::Introduce((account_2.addr, account_2.get_pub_key(), account_2.port)), None);
+        sleep(time::Duration::from_millis(100));
+
+        let (key, addr, prev_hash, state) = generate_funded_state(&[100, 100]);
+        let low = generate_signed_transaction(&key, vec![TxInput::new(prev_hash, 0)], vec![TxOutput::new(addr, 99)]);
+        let high = generate_signed_transaction(&key, vec![TxInput::new(prev_hash, 1)], vec![TxOutput::new(addr, 50)]);
+        assert!(fee_rate(1, low.size()) < 100 && fee_rate(50, high.size()) >= 100);
+        let mut pool_1 = mempool_1.lock().unwrap();
+        pool_1.set_check_trans(true);
+        pool_1.set_tip(state, 1, &ChainParams::main());
+        assert!(pool_1.add_with_check(&low));
+        assert!(pool_1.add_with_check(&high));
+        drop(pool_1);
+
+        // the low one is neither announced nor sent
+        server_1.broadcast(Message::NewTransactionHashes(vec![low.hash, high.hash]), None);
+        sleep(time::Duration::from_millis(100));
+        server_2.broadcast(Message::GetMempool, None);
+        sleep(time::Duration::from_millis(100));
+        let pool_2 = mempool_2.lock().unwrap();
+        assert!(!pool_2.ts_addr_map.contains_key(&low.hash));
+        assert!(pool_2.ts_addr_map.contains_key(&high.hash));
+        assert!(!pool_2.exist(&low.hash));
+        assert!(pool_2.exist(&high.hash));
+    }
+
+    #[test]
+    fn test_request_orphan_parent() {
+        let p2p_addr_1 = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 17143);
+        let p2p_addr_2 = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 17144);
+
+        let (server_1, _, _, _, mempool_1, _, _) = new_server_env(p2p_addr_1, Spreader::Default, false);
+        let (server_2, _, _, _, mempool_2, _, _) = new_server_env(p2p_addr_2, Spreader::Default, false);
+
+        // server_2 validates against the tip
+        let (key, addr, prev_hash, state) = generate_funded_state(&[10]);
+        let mut pool_2 = mempool_2.lock().unwrap();
+        pool_2.set_check_trans(true);
+        pool_2.set_tip(state.clone(), 1, &ChainParams::main());
+        drop(pool_2);
+
+        let parent = generate_signed_transaction(&key, vec![TxInput::new(prev_hash, 0)], vec![TxOutput::new(addr, 9)]);
+        let child = generate_signed_transaction(&key, vec![TxInput::new(parent.hash, 0)], vec![TxOutput::new(addr, 8)]);
+        assert!(mempool_1.lock().unwrap().add_with_check(&parent));
+        assert!(mempool_1.lock().unwrap().add_with_check(&child));
+
+        // the child reaches server_2 alone, and its parent is requested from server_1
+        connect_peers(&server_2, &vec![p2p_addr_1]);
+        sleep(time::Duration::from_millis(100));
+        server_1.broadcast(Message::Transactions(vec![child.clone()]), None);
+        sleep(time::Duration::from_millis(200));
+        let pool_2 = mempool_2.lock().unwrap();
+        assert!(pool_2.exist(&parent.hash));
+        assert!(pool_2.exist(&child.hash));
+        assert_eq!(0, pool_2.orphan_count());
+    }
 }
\ No newline at end of file
@@ -1,21 +1,26 @@
 use crate::network::server::Handle as ServerHandle;
 
-use log::info;
+use log::{debug, info, error};
 
 use crossbeam::channel::{unbounded, Receiver, Sender, TryRecvError};
+use std::collections::VecDeque;
 use std::time;
-use std::time::SystemTime;
+use std::time::{Duration, Instant, SystemTime};
 
 use std::thread;
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::sync::atomic::{AtomicBool, Ordering};
 use ring::signature::Ed25519KeyPair;
 
-use crate::blockchain::Blockchain;
-use crate::block::{Header, Block};
+use crate::blockchain::{Blockchain, is_from_future};
+use crate::block::{Header, Block, Content, PowHasher, BlockValidationError};
 use crate::network::message::{Message};
-use crate::crypto::hash::H256;
-use crate::config::MINING_STEP;
+use crate::crypto::hash::{H256, H160};
+use crate::crypto::merkle::MerkleTree;
+use crate::config::{MINING_STEP, HASH_RATE_WINDOW, MINER_TIP_POLL, MAX_BLOCK_TEMPLATES};
 use crate::mempool::MemPool;
+use crate::transaction::SignedTransaction;
+use crate::helper::generate_signed_coinbase_transaction_with_extra_nonce;
 
 enum ControlSignal {
     Start(u64), // the number controls the lambda of interval between block generation
@@ -36,15 +41,157 @@ pub struct Context {
     server: ServerHandle,
     blockchain: Arc<Mutex<Blockchain>>,
     mempool: Arc<Mutex<MemPool>>,
-    pub nonce: u32,
+    pub nonce: u32,  // progress of each thread in its nonce range
+    pub extra_nonce: u32,  // of the coinbase, bumped once the nonce ranges are searched through
     pub mined_num: usize,
     key_pair: Arc<Ed25519KeyPair>,
+    threads: usize,
+    hash_rate: Arc<Mutex<HashRate>>,
+    template: Option<Template>,
+    workers: Option<Workers>,  // started on the first round, and again once the number of threads changes
+    cancel: Arc<AtomicBool>,  // stops the workers once one finds a block or the tip changes
+}
+
+// Block being mined, kept across rounds until the tip or the mempool changes. Once its nonces run out,
+// the coinbase changes instead
+struct Template {
+    header: Header,  // with nonce 0
+    content: Content,
+    pool_changes: u64,  // MemPool::changes when the content was picked
+}
+
+impl Template {
+    // Sign the coinbase again under the given extra nonce, for a new merkle root over the same transactions
+    fn set_extra_nonce(&mut self, extra_nonce: u32, key_pair: &Ed25519KeyPair) {
+        let val = self.content.trans[0].transaction.outputs[0].val;
+        self.content.trans[0] = generate_signed_coinbase_transaction_with_extra_nonce(key_pair, val, extra_nonce);
+        self.header = Header::new(&self.header.parent, 0, self.header.timestamp as u128,
+                                  &self.header.difficulty, &self.content.merkle_root());
+    }
+}
+
+// Long-lived mining threads, each searching a nonce range of the template it is given
+struct Workers {
+    jobs: Vec<Sender<(Header, u32)>>,  // template with the first nonce to try, and the number of nonces
+    results: Receiver<(Option<Header>, u64)>,  // header found and number of hashes tried by each thread
+}
+
+impl Workers {
+    fn new(threads: usize, cancel: &Arc<AtomicBool>) -> Self {
+        let (result_tx, results) = unbounded();
+        let jobs = (0..threads).map(|i| {
+            let (job_tx, job_rx) = unbounded::<(Header, u32)>();
+            let result_tx = result_tx.clone();
+            let cancel = cancel.clone();
+            thread::Builder::new()
+                .name(format!("miner-worker-{}", i))
+                .spawn(move || {
+                    // ends once the miner drops the sending side
+                    for (mut header, steps) in job_rx.iter() {
+                        let difficulty = header.difficulty;
+                        let (bingo, hashes) = mining_range(&mut header, difficulty, steps, &cancel);
+                        if bingo {
+                            cancel.store(true, Ordering::Relaxed);
+                        }
+                        if result_tx.send((if bingo { Some(header) } else { None }, hashes)).is_err() {
+                            break;
+                        }
+                    }
+                })
+                .unwrap();
+            job_tx
+        }).collect();
+        Self { jobs, results }
+    }
 }
 
 #[derive(Clone)]
 pub struct Handle {
     /// Channel for sending signal to the miner thread
     control_chan: Sender<ControlSignal>,
+    hash_rate: Arc<Mutex<HashRate>>,
+    server: ServerHandle,
+    blockchain: Arc<Mutex<Blockchain>>,
+    mempool: Arc<Mutex<MemPool>>,
+    templates: Arc<Mutex<VecDeque<BlockTemplate>>>,  // latest ones handed out, oldest first
+}
+
+// Block for an external miner to search nonces of, its coinbase is unsigned until the block is mined
+#[derive(Clone)]
+pub struct BlockTemplate {
+    pub header: Header,  // with nonce 0
+    pub content: Content,
+    pub height: usize,
+    pub min_timestamp: u64,  // the median time past plus 1
+}
+
+impl BlockTemplate {
+    // The merkle root, unique to the coinbase and transactions of the template
+    pub fn id(&self) -> H256 {
+        self.header.merkle_root()
+    }
+
+    // Hashes to combine with the coinbase hash from the bottom up to get the merkle root,
+    // the coinbase side always on the left
+    pub fn merkle_branch(&self) -> Vec<H256> {
+        MerkleTree::new(&self.content.trans).proof(0)
+    }
+}
+
+// Reason a solved block template is not accepted
+#[derive(Debug, PartialEq, Eq)]
+pub enum SubmitError {
+    UnknownTemplate,  // never handed out or pushed out by newer ones
+    InvalidCoinbase,  // the key does not own the address or the signature is wrong
+    TimestampTooFarAhead,  // would be held back until its time comes, so submit it then
+    Rejected(BlockValidationError),
+}
+
+impl std::fmt::Display for SubmitError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            SubmitError::UnknownTemplate => write!(f, "unknown block template"),
+            SubmitError::InvalidCoinbase => write!(f, "invalid coinbase signature"),
+            SubmitError::TimestampTooFarAhead => write!(f, "timestamp too far ahead of local time"),
+            SubmitError::Rejected(e) => write!(f, "block rejected: {}", e),
+        }
+    }
+}
+
+// Hashes tried by all mining threads in the rounds of the last HASH_RATE_WINDOW
+pub struct HashRate {
+    rounds: VecDeque<(Instant, Duration, u64)>,  // end, duration and number of hashes of each round
+    window: Duration,
+}
+
+impl HashRate {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            rounds: VecDeque::new(),
+            window,
+        }
+    }
+
+    pub fn record(&mut self, duration: Duration, hashes: u64) {
+        self.rounds.push_back((Instant::now(), duration, hashes));
+        while let Some((end, _, _)) = self.rounds.front() {
+            if end.elapsed() <= self.window {
+                break;
+            }
+            self.rounds.pop_front();
+        }
+    }
+
+    // Hashes per second while mining, 0 if no round ended within the window
+    pub fn per_second(&self) -> f64 {
+        let (busy, hashes) = self.rounds.iter()
+            .filter(|(end, _, _)| end.elapsed() <= self.window)
+            .fold((Duration::from_secs(0), 0u64), |(busy, hashes), (_, d, h)| (busy + *d, hashes + h));
+        if hashes == 0 {
+            return 0.0;
+        }
+        hashes as f64 / busy.as_secs_f64().max(1e-9)
+    }
 }
 
 pub fn new(
@@ -54,20 +201,32 @@ pub fn new(
     key_pair: Arc<Ed25519KeyPair>,
 ) -> (Context, Handle) {
     let (signal_chan_sender, signal_chan_receiver) = unbounded();
+    let hash_rate = Arc::new(Mutex::new(HashRate::new(Duration::from_millis(HASH_RATE_WINDOW))));
 
     let ctx = Context {
         control_chan: signal_chan_receiver,
         operating_state: OperatingState::Paused,
-        server: server,
-        blockchain: blockchain,
-        mempool: mempool,
+        server: server.clone(),
+        blockchain: blockchain.clone(),
+        mempool: mempool.clone(),
         nonce: 0,
+        extra_nonce: 0,
         mined_num: 0,
         key_pair: key_pair,
+        threads: 1,
+        hash_rate: hash_rate.clone(),
+        template: None,
+        workers: None,
+        cancel: Arc::new(AtomicBool::new(false)),
     };
 
     let handle = Handle {
         control_chan: signal_chan_sender,
+        hash_rate,
+        server,
+        blockchain,
+        mempool,
+        templates: Arc::new(Mutex::new(VecDeque::new())),
     };
 
     (ctx, handle)
@@ -95,9 +254,79 @@ impl Handle {
             .send(ControlSignal::Paused)
             .unwrap()
     }
+
+    // Hashes per second of all mining threads
+    pub fn hash_rate(&self) -> f64 {
+        self.hash_rate.lock().unwrap().per_second()
+    }
+
+    // Build a block on the tip whose coinbase pays the given address, and keep it for submit_block
+    pub fn block_template(&self, addr: &H160) -> BlockTemplate {
+        let blockchain = self.blockchain.lock().unwrap();
+        let tip = blockchain.tip();
+        let difficulty = blockchain.difficulty();
+        let min_timestamp = blockchain.median_time_past(&tip).unwrap() + 1;
+        let height = blockchain.length();
+        let mempool = self.mempool.lock().unwrap();
+        let content = mempool.create_content_paying(addr, height, blockchain.chain_params());
+        drop(mempool);
+        drop(blockchain);
+
+        let ts = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap().as_millis();
+        let ts = std::cmp::max(ts, min_timestamp as u128);
+        let header = Header::new(&tip, 0, ts, &difficulty, &content.merkle_root());
+        let template = BlockTemplate { header, content, height, min_timestamp };
+        debug!("Hand out block template {:?} at height {}", template.id(), height);
+
+        let mut templates = self.templates.lock().unwrap();
+        templates.retain(|t| t.id() != template.id());
+        if templates.len() >= MAX_BLOCK_TEMPLATES {
+            templates.pop_front();
+        }
+        templates.push_back(template.clone());
+        template
+    }
+
+    // Complete a template with the nonce found, the timestamp if changed and the coinbase signed by the
+    // owner of its address, then insert the block and relay it as if mined here. Return the block hash
+    pub fn submit_block(&self, id: &H256, nonce: u32, timestamp: Option<u64>, public_key: &[u8], signature: &[u8])
+                        -> Result<H256, SubmitError> {
+        let template = self.templates.lock().unwrap().iter()
+            .find(|t| t.id() == *id)
+            .cloned()
+            .ok_or(SubmitError::UnknownTemplate)?;
+        let mut content = template.content;
+        let coinbase = SignedTransaction::new(content.trans[0].transaction.clone(), signature.into(), public_key.into());
+        if !coinbase.is_coinbase_tran() || !coinbase.sign_check() {
+            return Err(SubmitError::InvalidCoinbase);
+        }
+        content.trans[0] = coinbase;
+        let mut header = template.header;
+        header.nonce = nonce;
+        if let Some(timestamp) = timestamp {
+            header.timestamp = timestamp;
+        }
+        if is_from_future(&header) {
+            return Err(SubmitError::TimestampTooFarAhead);
+        }
+        let block = Block::new(header, content);
+
+        let mut blockchain = self.blockchain.lock().unwrap();
+        let old_tip = blockchain.tip();
+        blockchain.insert_with_check(&block).map_err(SubmitError::Rejected)?;
+        info!("Accept submitted block {:?} at height {}", block.hash, template.height);
+        relay_block(&self.server, blockchain, &self.mempool, &old_tip, block.hash);
+        Ok(block.hash)
+    }
 }
 
 impl Context {
+    // Search the nonce space with the given number of threads, each in its own range
+    pub fn set_threads(&mut self, threads: usize) {
+        self.threads = std::cmp::max(threads, 1);
+    }
+
     pub fn start(mut self) {
         thread::Builder::new()
             .name("miner".to_string())
@@ -163,75 +392,173 @@ impl Context {
 
     // Procedures when new block found
     pub(crate) fn found(&mut self, block: Block) {
+        // insert block into chain, a rejected one is neither counted nor relayed
+        let mut blockchain = self.blockchain.lock().unwrap();
+        let old_tip = blockchain.tip();
+        if let Err(e) = blockchain.insert(&block) {
+            error!("Mined block {:?} is rejected: {}", block.hash, e);
+            return;
+        }
         self.mined_num += 1;
         info!("Mined a block: {:?}, number of transactions: {:?}. Total mined: {}",
                 block.hash, block.content.trans.len(), self.mined_num);
-
-        let hash_of_trans = block.content.get_trans_hashes();
-        // insert block into chain
-        let mut blockchain = self.blockchain.lock().unwrap();
-        blockchain.insert(&block);
-        drop(blockchain);
-
-        // remove content's all transactions from mempool
-        let mut mempool = self.mempool.lock().unwrap();
-        mempool.remove_trans(&hash_of_trans);
-        mempool.remove_conflict_tx_inputs(&block.content);
-
-        // broadcast new block
-        let vec = vec![block.hash.clone()];
-        self.server.broadcast(Message::NewBlockHashes(vec), None);
+        relay_block(&self.server, blockchain, &self.mempool, &old_tip, block.hash);
     }
 
     // Mining process! Return true: mining a block successfully
     fn mining(&mut self) -> bool {
         let blockchain = self.blockchain.lock().unwrap();
         let tip = blockchain.tip();  // previous hash
-        let difficulty = blockchain.difficulty();
-        drop(blockchain);
-
         let mempool = self.mempool.lock().unwrap();
-
-        // Miner put transactions into block content from mempool!!
-        let content = mempool.create_content(&self.key_pair);
+        let outdated = self.template.as_ref()
+            .is_some_and(|t| t.header.parent != tip || t.pool_changes != mempool.changes());
+        if outdated {
+            debug!("Tip or mempool changed, rebuild the block template");
+            self.template = None;
+            self.nonce = 0;
+            self.extra_nonce = 0;
+        }
+        if self.template.is_none() {
+            // Miner put transactions into block content from mempool!!
+            let content = mempool.create_content(&self.key_pair, blockchain.length(), blockchain.chain_params(),
+                                                 self.extra_nonce);
+            let ts = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)
+                    .unwrap().as_millis();
+            // the timestamp has to be above the median time past even if the local clock lags behind
+            let ts = std::cmp::max(ts, blockchain.median_time_past(&tip).unwrap() as u128 + 1);
+            let header = Header::new(&tip, 0, ts,
+                    &blockchain.difficulty(), &content.merkle_root());
+            self.template = Some(Template { header, content, pool_changes: mempool.changes() });
+        }
         drop(mempool);
+        drop(blockchain);
+        let header = self.template.as_ref().unwrap().header.clone();
+
+        // the longest of the thread ranges, which differ in length by one at most
+        let range = (1u64 << 32).div_ceil(self.threads as u64);
+        let steps = std::cmp::min(MINING_STEP as u64, range - self.nonce as u64) as u32;
+        let round_start = Instant::now();
+        let (found, hashes) = self.search(&header, &tip, steps);
+        self.hash_rate.lock().unwrap().record(round_start.elapsed(), hashes);
+        match found {
+            Some(header) => {
+                let content = self.template.take().unwrap().content;
+                self.found(Block::new(header, content));
+                self.nonce = 0;
+                self.extra_nonce = 0;
+                true
+            }
+            None => {
+                let progress = self.nonce as u64 + steps as u64;
+                if progress < range {
+                    self.nonce = progress as u32;
+                } else {
+                    // nonces would be hashed again under the same merkle root
+                    self.nonce = 0;
+                    self.extra_nonce = self.extra_nonce.wrapping_add(1);
+                    self.template.as_mut().unwrap().set_extra_nonce(self.extra_nonce, &self.key_pair);
+                    debug!("Nonce ranges searched through, bump the extra nonce to {}", self.extra_nonce);
+                }
+                false
+            }
+        }
+    }
 
-        let nonce = self.nonce;
-        let ts = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)
-                .unwrap().as_millis();
-        let mut header = Header::new(&tip, nonce, ts,
-                &difficulty, &content.merkle_root());
-
-        let mut bingo = false;
-        if mining_base(&mut header, difficulty) {
-            let block = Block::new(header, content);
-            self.found(block);
-            bingo = true;
-            self.nonce = 0;
-        } else {
-            self.nonce = header.nonce;
+    // Let each worker try the given number of nonces of its range on the template, all of them stop as soon as
+    // one finds a block or the tip changes. Return the header found and the number of hashes tried
+    fn search(&mut self, template: &Header, tip: &H256, steps: u32) -> (Option<Header>, u64) {
+        if self.workers.as_ref().is_none_or(|w| w.jobs.len() != self.threads) {
+            self.workers = Some(Workers::new(self.threads, &self.cancel));
+        }
+        let workers = self.workers.as_ref().unwrap();
+        self.cancel.store(false, Ordering::Relaxed);
+        for (i, job) in workers.jobs.iter().enumerate() {
+            // thread i searches [i * 2^32 / threads, (i + 1) * 2^32 / threads), so the ranges cover all nonces
+            let start = (i as u64 * (1u64 << 32)) / self.threads as u64 + self.nonce as u64;
+            let end = ((i as u64 + 1) * (1u64 << 32)) / self.threads as u64;
+            let steps = std::cmp::min(steps as u64, end.saturating_sub(start)) as u32;
+            let mut header = template.clone();
+            header.nonce = start as u32;
+            job.send((header, steps)).unwrap();
+        }
+
+        let mut found = None;
+        let mut hashes = 0u64;
+        let mut running = self.threads;
+        while running > 0 {
+            match workers.results.recv_timeout(Duration::from_millis(MINER_TIP_POLL)) {
+                Ok((header, tried)) => {
+                    running -= 1;
+                    hashes += tried;
+                    if found.is_none() {
+                        found = header;
+                    }
+                }
+                Err(_) => {
+                    if self.blockchain.lock().unwrap().tip() != *tip {
+                        self.cancel.store(true, Ordering::Relaxed);
+                    }
+                }
+            }
         }
-        bingo
+        (found, hashes)
     }
 
     #[cfg(any(test, test_utilities))]
     fn change_difficulty(&mut self, new_difficulty: &H256) {
         let mut blockchain = self.blockchain.lock().unwrap();
         blockchain.change_difficulty(new_difficulty);
+        // the tip stays the same, so the template would keep the old difficulty
+        self.template = None;
+    }
+}
+
+// After a new local block is inserted, remove transactions of newly connected blocks from mempool,
+// put back ones of disconnected blocks, and broadcast the block
+fn relay_block(server: &ServerHandle, blockchain: MutexGuard<Blockchain>, mempool: &Mutex<MemPool>,
+               old_tip: &H256, hash: H256) {
+    let mut mempool = mempool.lock().unwrap();
+    if blockchain.tip() != *old_tip {
+        let change = blockchain.tip_change(old_tip);
+        mempool.apply_tip_change(&change, blockchain.tip_block_state(), blockchain.length(), blockchain.chain_params());
     }
+    drop(mempool);
+    drop(blockchain);
+
+    // broadcast new block
+    server.broadcast(Message::NewBlockHashes(vec![hash]), None);
 }
 
-// Perforn mining for MINING_STEP here
+// Perforn mining for MINING_STEP here, stop early once the nonce wraps so the caller can bump the extra nonce
 pub fn mining_base(header: &mut Header, difficulty: H256) -> bool {
+    let hasher = PowHasher::new(header);
     for _ in 0..MINING_STEP {
-        if header.hash() < difficulty {
+        if hasher.hash(header.nonce) < difficulty {
             return true;
         }
-        header.change_nonce();
+        if header.change_nonce() {
+            return false;
+        }
     }
     return false;
 }
 
+// Try up to steps nonces from that of the header, stop early once cancelled.
+// Return whether a block is found, and the number of hashes tried
+pub fn mining_range(header: &mut Header, difficulty: H256, steps: u32, cancel: &AtomicBool) -> (bool, u64) {
+    let hasher = PowHasher::new(header);
+    for tried in 0..steps {
+        if cancel.load(Ordering::Relaxed) {
+            return (false, tried as u64);
+        }
+        if hasher.hash(header.nonce) < difficulty {
+            return (true, tried as u64 + 1);
+        }
+        header.change_nonce();
+    }
+    (false, steps as u64)
+}
+
 #[cfg(any(test, test_utilities))]
 pub mod tests {
     use crate::miner;
@@ -241,8 +568,14 @@ pub mod tests {
     use std::time;
     use std::thread;
     use std::net::{SocketAddr, IpAddr, Ipv4Addr};
-    use crate::config::{BLOCK_SIZE_LIMIT, EASIEST_DIF};
+    use crate::config::{BLOCK_SIZE_LIMIT, EASIEST_DIF, MAX_FUTURE_DRIFT};
     use crate::spread::Spreader;
+    use crate::crypto::{key_pair, merkle};
+    use crate::block::BlockValidationError;
+    use crate::transaction::sign;
+    use ring::signature::KeyPair;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, Ordering};
 
     #[test]
     fn test_miner() {
@@ -275,6 +608,171 @@ pub mod tests {
         assert_eq!(miner::MINING_STEP, miner.nonce);
     }
 
+    #[test]
+    fn test_mining_threads() {
+        let p2p_addr_1 = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 17014);
+        let (_server_handle, mut miner, _, blockchain, _, _, _) = new_server_env(p2p_addr_1, Spreader::Default, false);
+        miner.set_threads(4);
+
+        // every thread tries its whole step on an impossible difficulty
+        miner.change_difficulty(&gen_difficulty_array(256).into());
+        assert!(!miner.mining());
+        assert_eq!(miner::MINING_STEP, miner.nonce);
+        assert_eq!(4 * miner::MINING_STEP as u64, miner.hash_rate.lock().unwrap().rounds[0].2);
+        assert!(miner.hash_rate.lock().unwrap().per_second() > 0.0);
+
+        // the others stop once one finds a block
+        miner.change_difficulty(&gen_difficulty_array(0).into());
+        assert!(miner.mining());
+        assert_eq!(0, miner.nonce);
+        assert_eq!(2, blockchain.lock().unwrap().length());
+        assert!(miner.hash_rate.lock().unwrap().rounds[1].2 < 4 * miner::MINING_STEP as u64);
+
+        // uneven ranges, the last thread searches one more nonce up to the end of the nonce space
+        miner.change_difficulty(&gen_difficulty_array(256).into());
+        miner.set_threads(3);
+        miner.nonce = ((1u64 << 32) / 3 - 4) as u32;
+        assert!(!miner.mining());
+        assert_eq!(4 + 4 + 5, miner.hash_rate.lock().unwrap().rounds[2].2);
+        assert_eq!(0, miner.nonce);
+    }
+
+    #[test]
+    fn test_template_kept() {
+        let p2p_addr_1 = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 17020);
+        let (_server_handle, mut miner, _, _blockchain, mempool, _, _) = new_server_env(p2p_addr_1, Spreader::Default, false);
+        miner.change_difficulty(&gen_difficulty_array(256).into());
+
+        // rounds go on with the same header, only the nonces move
+        assert!(!miner.mining());
+        let header = miner.template.as_ref().unwrap().header.clone();
+        assert!(!miner.mining());
+        assert_eq!(header.hash(), miner.template.as_ref().unwrap().header.hash());
+        assert_eq!(2 * miner::MINING_STEP, miner.nonce);
+
+        // a new pool transaction is picked up by a new template
+        mempool.lock().unwrap().set_check_trans(false);
+        assert!(mempool.lock().unwrap().add_with_check(&generate_random_signed_transaction()));
+        assert!(!miner.mining());
+        assert_ne!(header.merkle_root(), miner.template.as_ref().unwrap().header.merkle_root());
+        assert_eq!(miner::MINING_STEP, miner.nonce);
+    }
+
+    #[test]
+    fn test_extra_nonce() {
+        let p2p_addr_1 = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 17015);
+        let (_server_handle, mut miner, _, _blockchain, _, _, _) = new_server_env(p2p_addr_1, Spreader::Default, false);
+        miner.change_difficulty(&gen_difficulty_array(256).into());
+
+        // the round stops at the end of the nonce space, and goes on under a new coinbase
+        miner.nonce = u32::MAX - miner::MINING_STEP - 9;
+        assert!(!miner.mining());
+        assert_eq!(u32::MAX - 9, miner.nonce);
+        let root = miner.template.as_ref().unwrap().header.merkle_root();
+        assert!(!miner.mining());
+        assert_eq!(10, miner.hash_rate.lock().unwrap().rounds[1].2);
+        assert_eq!(0, miner.nonce);
+        assert_eq!(1, miner.extra_nonce);
+
+        // only the coinbase changes, so does the merkle root exactly there
+        let template = miner.template.as_ref().unwrap();
+        assert_ne!(root, template.header.merkle_root());
+        assert_eq!(template.content.merkle_root(), template.header.merkle_root());
+        assert_eq!(1, template.content.trans[0].transaction.extra_nonce);
+        let root = template.header.merkle_root();
+        assert!(!miner.mining());
+        assert_eq!(miner::MINING_STEP, miner.nonce);
+        assert_eq!(1, miner.extra_nonce);
+        assert_eq!(root, miner.template.as_ref().unwrap().header.merkle_root());
+
+        // the same with each thread in a quarter of it
+        miner.set_threads(4);
+        miner.nonce = (1 << 30) - 5;
+        assert!(!miner.mining());
+        assert_eq!(20, miner.hash_rate.lock().unwrap().rounds[3].2);
+        assert_eq!(0, miner.nonce);
+        assert_eq!(2, miner.extra_nonce);
+
+        // uneven ranges, the last thread searches one more nonce up to the end of the nonce space
+        miner.set_threads(3);
+        miner.nonce = ((1u64 << 32) / 3 - 4) as u32;
+        assert!(!miner.mining());
+        assert_eq!(4 + 4 + 5, miner.hash_rate.lock().unwrap().rounds[4].2);
+        assert_eq!(0, miner.nonce);
+        assert_eq!(3, miner.extra_nonce);
+
+        // a new block starts over
+        miner.change_difficulty(&gen_difficulty_array(0).into());
+        assert!(miner.mining());
+        assert_eq!(0, miner.extra_nonce);
+
+        let mut header = generate_header(&generate_random_hash(), &generate_random_content(), u32::MAX,
+                                         &gen_difficulty_array(256).into());
+        assert!(!miner::mining_base(&mut header, gen_difficulty_array(256).into()));
+        assert_eq!(0, header.nonce);
+    }
+
+    #[test]
+    fn test_block_template() {
+        let p2p_addr_1 = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 17016);
+        let p2p_addr_2 = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 17017);
+        let (server_1, _, _, blockchain_1, mempool_1, _, _) = new_server_env(p2p_addr_1, Spreader::Default, false);
+        let (server_2, _, _, blockchain_2, _, _, _) = new_server_env(p2p_addr_2, Spreader::Default, false);
+        connect_peers(&server_2, &vec![p2p_addr_1]);
+        let (_, handle) = miner::new(server_1, blockchain_1.clone(), mempool_1, Arc::new(key_pair::random()));
+
+        // the coinbase pays an address of the external miner
+        let key = key_pair::random();
+        let addr = generate_signed_coinbase_transaction(&key).sender_addr();
+        let template = handle.block_template(&addr);
+        assert_eq!(blockchain_1.lock().unwrap().tip(), template.header.parent);
+        assert_eq!(1, template.height);
+        let coinbase = template.content.trans[0].transaction.clone();
+        assert_eq!(addr, coinbase.outputs[0].rec_address);
+        assert!(merkle::verify(&template.id(), &template.content.trans[0].hash, &template.merkle_branch(), 0,
+                               template.content.trans.len()));
+
+        let mut header = template.header.clone();
+        let difficulty = header.difficulty;
+        while !miner::mining_base(&mut header, difficulty) {}
+
+        let other = key_pair::random();
+        assert_eq!(Err(miner::SubmitError::InvalidCoinbase),
+                   handle.submit_block(&template.id(), header.nonce, None, other.public_key().as_ref(),
+                                       sign(&coinbase, &other).as_ref()));
+        let signature = sign(&coinbase, &key);
+        assert_eq!(Err(miner::SubmitError::UnknownTemplate),
+                   handle.submit_block(&generate_random_hash(), header.nonce, None, key.public_key().as_ref(),
+                                       signature.as_ref()));
+
+        let now = time::SystemTime::now().duration_since(time::SystemTime::UNIX_EPOCH).unwrap().as_millis() as u64;
+        assert_eq!(Err(miner::SubmitError::TimestampTooFarAhead),
+                   handle.submit_block(&template.id(), header.nonce, Some(now + 2 * MAX_FUTURE_DRIFT),
+                                       key.public_key().as_ref(), signature.as_ref()));
+
+        // accepted and relayed like a block mined here
+        let hash = handle.submit_block(&template.id(), header.nonce, None, key.public_key().as_ref(),
+                                       signature.as_ref()).unwrap();
+        assert_eq!(hash, blockchain_1.lock().unwrap().tip());
+        thread::sleep(time::Duration::from_millis(100));
+        assert_eq!(hash, blockchain_2.lock().unwrap().tip());
+        assert_eq!(Err(miner::SubmitError::Rejected(BlockValidationError::Duplicate)),
+                   handle.submit_block(&template.id(), header.nonce, None, key.public_key().as_ref(),
+                                       signature.as_ref()));
+    }
+
+    #[test]
+    fn test_mining_range_cancel() {
+        let mut header = generate_header(&generate_random_hash(), &generate_random_content(), 0, &gen_difficulty_array(256).into());
+        let difficulty = header.difficulty;
+        let cancel = AtomicBool::new(false);
+        assert_eq!((false, 16), miner::mining_range(&mut header, difficulty, 16, &cancel));
+        assert_eq!(16, header.nonce);
+        cancel.store(true, Ordering::Relaxed);
+        assert_eq!((false, 0), miner::mining_range(&mut header, difficulty, 16, &cancel));
+        assert_eq!(16, header.nonce);
+    }
+
     #[test]
     fn test_block_relay() {
         let p2p_addr_1 = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 17011);
@@ -356,7 +854,7 @@ pub mod tests {
         // test get missing parent
         let mut chain_1 = blockchain_1.lock().unwrap();
         let new_block_1 = generate_mined_block(&chain_1.tip(), &difficulty);
-        chain_1.insert(&new_block_1);
+        chain_1.insert(&new_block_1).unwrap();
         drop(chain_1);
         assert_eq!(5, blockchain_1.lock().unwrap().length());
         assert_eq!(4, blockchain_2.lock().unwrap().length());
@@ -373,10 +871,30 @@ pub mod tests {
         let mut chain_1 = blockchain_1.lock().unwrap();
         let wrong_difficulty: H256 = gen_difficulty_array(1).into();
         let wrong_block = generate_mined_block(&chain_1.tip(), &wrong_difficulty);
-        assert!(!chain_1.insert_with_check(&wrong_block));
-        assert!(!chain_1.insert_with_check(&new_block_1));
+        assert!(chain_1.insert_with_check(&wrong_block).is_err());
+        assert!(chain_1.insert_with_check(&new_block_1).is_err());
         let correct_difficulty: H256 = gen_difficulty_array(EASIEST_DIF).into();
         let correct_block = generate_mined_block(&chain_1.tip(), &correct_difficulty);
-        assert!(chain_1.insert_with_check(&correct_block));
+        assert!(chain_1.insert_with_check(&correct_block).is_ok());
+    }
+
+    #[test]
+    fn test_found_rejected() {
+        let p2p_addr_1 = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 17018);
+        let p2p_addr_2 = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 17019);
+        let (_server_1, mut miner_ctx_1, _, blockchain_1, _, _, _) = new_server_env(p2p_addr_1, Spreader::Default, false);
+        let (server_2, _, _, blockchain_2, _, _, _) = new_server_env(p2p_addr_2, Spreader::Default, false);
+        blockchain_2.lock().unwrap().set_check_trans(false);
+        connect_peers(&server_2, &vec![p2p_addr_1]);
+
+        // random transactions spend nothing in the tip state
+        let tip = blockchain_1.lock().unwrap().tip();
+        let difficulty = blockchain_1.lock().unwrap().difficulty();
+        let block = generate_mined_block(&tip, &difficulty);
+        miner_ctx_1.found(block.clone());
+        thread::sleep(time::Duration::from_millis(100));
+        assert_eq!(0, miner_ctx_1.mined_num);
+        assert_eq!(tip, blockchain_1.lock().unwrap().tip());
+        assert!(!blockchain_2.lock().unwrap().exist(&block.hash));
     }
 }
\ No newline at end of file
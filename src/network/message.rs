@@ -1,6 +1,6 @@
 use serde::{Serialize, Deserialize};
 
-use crate::block::Block;
+use crate::block::{Block, Header};
 use crate::crypto::hash::{H256, H160};
 use crate::transaction::SignedTransaction;
 use ring::signature::ED25519_PUBLIC_KEY_LEN;
@@ -12,10 +12,14 @@ pub enum Message {
     NewBlockHashes(Vec<H256>),
     GetBlocks(Vec<H256>),
     Blocks(Vec<Block>),
+    GetHeaders(Vec<H256>),  // block locator of the sender's best header chain
+    Headers(Vec<Header>),
     NewTransactionHashes(Vec<H256>),
     GetTransactions(Vec<H256>),
     Transactions(Vec<SignedTransaction>),
     NewPeers(Vec<(H160, Box<[u8; ED25519_PUBLIC_KEY_LEN]>, u16)>),
     Introduce((H160, Box<[u8; ED25519_PUBLIC_KEY_LEN]>, u16)),
     NewDandelionTransactions(Vec<SignedTransaction>),
+    FeeFilter(u64),  // min fee rate(per 1000 bytes) of transactions the sender takes into its mempool
+    GetMempool,  // answered with NewTransactionHashes of the pool, stem transactions left out
 }
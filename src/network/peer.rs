@@ -5,6 +5,8 @@ use mio_extras::channel;
 use std::convert::TryInto;
 use std::io::{Read, Write};
 use std::sync::mpsc;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
 
 enum DecodeState {
     Length,
@@ -186,6 +188,7 @@ pub fn new(
         write_queue: write_sender,
         addr,
         key,
+        fee_filter: Arc::new(AtomicU64::new(0)),
     };
     let ctx = Context {
         addr,
@@ -218,6 +221,7 @@ pub struct Handle {
     pub addr: std::net::SocketAddr,
     write_queue: channel::Sender<Vec<u8>>,
     pub key: usize,
+    fee_filter: Arc<AtomicU64>,  // min fee rate(per 1000 bytes) the peer announced, gone with the connection
 }
 
 impl Handle {
@@ -228,4 +232,13 @@ impl Handle {
             warn!("Failed to send write request for peer {}, channel detached", self.addr);
         }
     }
+
+    pub fn set_fee_filter(&self, rate: u64) {
+        self.fee_filter.store(rate, Ordering::Relaxed);
+    }
+
+    // Transactions below this fee rate are not announced nor sent to the peer
+    pub fn fee_filter(&self) -> u64 {
+        self.fee_filter.load(Ordering::Relaxed)
+    }
 }
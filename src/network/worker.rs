@@ -8,9 +8,11 @@ use super::message::Message;
 use super::peer;
 use crate::network::server::Handle as ServerHandle;
 use crate::blockchain::Blockchain;
+use crate::block::BlockValidationError;
 use crate::crypto::hash::{H256, Hashable, H160};
 use crate::mempool::MemPool;
 use crate::peers::Peers;
+use crate::config::{MAX_HEADERS_PER_MSG, BLOCK_FETCH_WINDOW, BLOCK_FETCH_BATCH, MAX_MEMPOOL_HASHES_PER_MSG};
 
 use ring::signature::ED25519_PUBLIC_KEY_LEN;
 
@@ -110,25 +112,73 @@ impl Context {
                     let mut mempool = self.mempool.lock().unwrap();
                     let mut new_hashes = Vec::<H256>::new();
                     let mut missing_parents = Vec::<H256>::new();
+                    let old_tip = blockchain.tip();
+                    new_hashes.extend(blockchain.retry_future_blocks());
                     for b in blocks.iter() {
-                        if blockchain.insert_with_check(b) {
-                            if !self.supernode {
-                                mempool.remove_trans(&b.content.get_trans_hashes());
-                            }
-                            new_hashes.push(b.hash.clone());
+                        match blockchain.insert_with_check_from(b, Some(peer.addr)) {
+                            Ok(()) => new_hashes.push(b.hash),
+                            Err(BlockValidationError::Duplicate) => {}
+                            Err(e) => warn!("Block {:?} from peer {} is rejected: {}", b.hash, peer.addr, e),
                         }
                         if let Some(parent_hash) = blockchain.missing_parent(&b.hash) {
                             missing_parents.push(parent_hash);
                         }
                     }
+                    if !self.supernode && blockchain.tip() != old_tip {
+                        let change = blockchain.tip_change(&old_tip);
+                        mempool.apply_tip_change(&change, blockchain.tip_block_state(),
+                                                 blockchain.length(), blockchain.chain_params());
+                    }
+                    drop(mempool);
+                    // keep the body download window of headers-first sync full
+                    let to_fetch = blockchain.blocks_to_fetch(BLOCK_FETCH_WINDOW);
                     drop(blockchain);
+                    missing_parents.retain(|h| !to_fetch.contains(h));
                     if missing_parents.len() > 0 {
                         peer.write(Message::GetBlocks(missing_parents));
                     }
+                    for batch in to_fetch.chunks(BLOCK_FETCH_BATCH) {
+                        peer.write(Message::GetBlocks(batch.to_vec()));
+                    }
                     if new_hashes.len() > 0 {
                         self.server.broadcast(Message::NewBlockHashes(new_hashes), Some(peer_key));
                     }
                 }
+                Message::GetHeaders(locator) => {
+                    //Send headers of the longest chain following the last common block in the locator
+                    debug!("GetHeaders message received: {:?}", locator);
+                    let headers = self.blockchain.lock().unwrap().headers_after(&locator, MAX_HEADERS_PER_MSG);
+                    if !headers.is_empty() {
+                        peer.write(Message::Headers(headers));
+                    }
+                }
+                Message::Headers(headers) => {
+                    //Index the headers after checking their PoW; ask for more headers if they do not connect or
+                    //there may be more, and fetch missing bodies in several batches at once
+                    debug!("Headers message received: {} headers", headers.len());
+                    let mut blockchain = self.blockchain.lock().unwrap();
+                    let mut get_more = headers.len() >= MAX_HEADERS_PER_MSG;
+                    for h in headers.iter() {
+                        if !blockchain.has_header(&h.parent) {
+                            get_more = true;
+                            break;
+                        }
+                        if !blockchain.insert_header(h) {
+                            warn!("Invalid header from peer {}", peer.addr);
+                            get_more = false;
+                            break;
+                        }
+                    }
+                    let locator = blockchain.locator();
+                    let to_fetch = blockchain.blocks_to_fetch(BLOCK_FETCH_WINDOW);
+                    drop(blockchain);
+                    if get_more {
+                        peer.write(Message::GetHeaders(locator));
+                    }
+                    for batch in to_fetch.chunks(BLOCK_FETCH_BATCH) {
+                        peer.write(Message::GetBlocks(batch.to_vec()));
+                    }
+                }
                 Message::NewTransactionHashes(hashes) => {
                     //Check whether the transactions are already in mempool/blockchain; if not,sending GetTransactions to ask for them.
                     debug!("NewTransactionHashes message received: {:?}", hashes);
@@ -138,8 +188,12 @@ impl Context {
                             mempool.insert_ts_and_addr(h.clone(), peer.addr.clone());
                         }
                     }
+                    // an own stem transaction announced by another node is fluffed
+                    for h in hashes.iter() {
+                        mempool.mark_public(h);
+                    }
                     let to_get: Vec<H256> = hashes.into_iter()
-                                .filter(|h|!mempool.exist(h)).collect();
+                                .filter(|h|!mempool.exist(h) && !mempool.is_orphan(h)).collect();
                     drop(mempool);
                     if to_get.len() > 0 {
                         peer.write(Message::GetTransactions(to_get));
@@ -161,25 +215,68 @@ impl Context {
                 Message::GetTransactions(hashes) => {
                     //Check whether the hashes are already in mempool; if yes,sending the corresponding transactions thru Transactions.
                     debug!("GetTransactions message received: {:?}", hashes);
-                    let trans = self.mempool.lock().unwrap().get_trans(&hashes);
+                    let filter = peer.fee_filter();
+                    let mempool = self.mempool.lock().unwrap();
+                    let trans: Vec<_> = mempool.get_trans(&hashes).into_iter()
+                        .filter(|t| mempool.fee_rate_of(&t.hash).unwrap_or(0) >= filter)
+                        .collect();
+                    drop(mempool);
                     if trans.len() > 0 {
                         peer.write(Message::Transactions(trans));
                     }
                 }
                 Message::Transactions(trans) => {
                     //Add the transactions into mempool if not already in it and passing signature check
+                    //Ask the peer for parents of the orphans
                     debug!("Transactions message received!!");
                     let mut mempool = self.mempool.lock().unwrap();
                     let mut new_hashes = Vec::<H256>::new();
+                    let mut parents = Vec::<H256>::new();
                     for t in trans.iter() {
-                        if mempool.add_with_check(t) {
-                            new_hashes.push(t.hash());
+                        let (added, replaced) = mempool.add_with_check_from(t, Some(peer.addr));
+                        if !replaced.is_empty() {
+                            debug!("{} transactions replaced by {:?}: {:?}", replaced.len(), t.hash, replaced);
+                            // a later transaction of the message may replace an earlier one, which is not announced then
+                            new_hashes.retain(|hash| !replaced.contains(hash));
                         }
+                        if added.is_empty() && mempool.is_orphan(&t.hash) {
+                            for parent in mempool.missing_parents(&t.hash) {
+                                if !parents.contains(&parent) && !mempool.is_orphan(&parent) {
+                                    parents.push(parent);
+                                }
+                            }
+                        }
+                        new_hashes.extend(added);
                     }
+                    let fee_filter = mempool.fee_filter_update();
                     drop(mempool);
+                    if !parents.is_empty() {
+                        peer.write(Message::GetTransactions(parents));
+                    }
                     if new_hashes.len() > 0  && !self.supernode {
                         self.server.broadcast(Message::NewTransactionHashes(new_hashes), Some(peer_key));
                     }
+                    if let Some(rate) = fee_filter {
+                        self.server.broadcast(Message::FeeFilter(rate), None);
+                    }
+                }
+                Message::GetMempool => {
+                    //Announce the pool in batches, leaving out transactions below the peer's fee filter
+                    debug!("GetMempool message received");
+                    let filter = peer.fee_filter();
+                    let mempool = self.mempool.lock().unwrap();
+                    let hashes: Vec<H256> = mempool.public_hashes().into_iter()
+                        .filter(|h| mempool.fee_rate_of(h).unwrap_or(0) >= filter)
+                        .collect();
+                    drop(mempool);
+                    for batch in hashes.chunks(MAX_MEMPOOL_HASHES_PER_MSG) {
+                        peer.write(Message::NewTransactionHashes(batch.to_vec()));
+                    }
+                }
+                Message::FeeFilter(rate) => {
+                    //Keep transactions below the peer's min fee rate from it
+                    debug!("FeeFilter message received: {}", rate);
+                    peer.set_fee_filter(rate);
                 }
                 Message::NewPeers(content) => {
                     //Broadcast all known address(including itself) to p2p_peers
@@ -208,6 +305,7 @@ impl Context {
                     let pub_key = content.1.clone();
                     let port = content.2;
                     debug!("Server {:?} receive IntroduceAddr {:?}!!", self.self_addr, addr);
+                    let min_fee_rate = self.mempool.lock().unwrap().min_fee_rate();
                     let blockchain = self.blockchain.lock().unwrap();
                     let mut peers_info = self.peers_info.lock().unwrap();
 
@@ -221,7 +319,12 @@ impl Context {
                         self.server.broadcast(Message::NewPeers(vec![content]), Some(peer_key));
                     }
 
-                    peer.write(Message::NewBlockHashes(blockchain.hash_chain()));
+                    // announce the tip by its header, the new peer then syncs headers-first from its locator
+                    let tip_header = blockchain.get_block(&blockchain.tip()).unwrap().header;
+                    peer.write(Message::Headers(vec![tip_header]));
+                    if min_fee_rate > 0 {
+                        peer.write(Message::FeeFilter(min_fee_rate));
+                    }
                 }
             }
         }
@@ -0,0 +1,159 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use crate::block::Block;
+use crate::crypto::hash::H256;
+use crate::orphan_pool::{OrphanEntry, OrphanPool};
+
+pub struct Orphan {
+    pub block: Block,
+    pub peer: Option<SocketAddr>,  // peer who supplied it, none if added locally
+    pub unchecked: bool,  // difficulty is checked once its parent arrives
+}
+
+impl OrphanEntry for Orphan {
+    fn peer(&self) -> Option<SocketAddr> {
+        self.peer
+    }
+}
+
+// Blocks whose parent is unknown yet, limited in number and age
+pub struct OrphanBlockPool {
+    orphans: OrphanPool<Orphan>,
+    children: HashMap<H256, Vec<H256>>,  // key is the hash of the parent
+}
+
+impl OrphanBlockPool {
+    pub fn new(capacity: usize, expiry: Duration) -> Self {
+        Self {
+            orphans: OrphanPool::new("blocks", capacity, expiry),
+            children: HashMap::new(),
+        }
+    }
+
+    // Add an orphan, dropping expired ones first and then the oldest one if the pool is full
+    pub fn insert(&mut self, block: Block, peer: Option<SocketAddr>, unchecked: bool) {
+        if self.orphans.contains(&block.hash) {
+            return;
+        }
+        let (dropped, room) = self.orphans.make_room(peer);
+        for (hash, orphan) in dropped.iter() {
+            self.unindex(hash, orphan);
+        }
+        if !room {
+            return;
+        }
+        self.children.entry(block.header.parent).or_default().push(block.hash);
+        self.orphans.insert(block.hash, Orphan { block, peer, unchecked });
+    }
+
+    // Drop orphans older than the expiry, return the number dropped
+    pub fn expire(&mut self) -> usize {
+        let expired = self.orphans.expire();
+        for (hash, orphan) in expired.iter() {
+            self.unindex(hash, orphan);
+        }
+        expired.len()
+    }
+
+    pub fn remove(&mut self, hash: &H256) -> Option<Orphan> {
+        let orphan = self.orphans.remove(hash)?;
+        self.unindex(hash, &orphan);
+        Some(orphan)
+    }
+
+    fn unindex(&mut self, hash: &H256, orphan: &Orphan) {
+        let parent = orphan.block.header.parent;
+        if let Some(siblings) = self.children.get_mut(&parent) {
+            siblings.retain(|h| h != hash);
+            if siblings.is_empty() {
+                self.children.remove(&parent);
+            }
+        }
+    }
+
+    // Take out all orphans waiting for the given parent
+    pub fn remove_children(&mut self, parent: &H256) -> Vec<Orphan> {
+        let hashes = self.children.remove(parent).unwrap_or_default();
+        hashes.iter().filter_map(|h| self.orphans.remove(h)).collect()
+    }
+
+    pub fn contains(&self, hash: &H256) -> bool {
+        self.orphans.contains(hash)
+    }
+
+    pub fn get(&self, hash: &H256) -> Option<&Block> {
+        self.orphans.get(hash).map(|o| &o.block)
+    }
+
+    pub fn peer_of(&self, hash: &H256) -> Option<SocketAddr> {
+        self.orphans.get(hash).and_then(|o| o.peer)
+    }
+
+    // Number of orphans supplied by each peer, locally-added ones are left out
+    pub fn count_by_peer(&self) -> HashMap<SocketAddr, usize> {
+        self.orphans.count_by_peer()
+    }
+
+    pub fn len(&self) -> usize {
+        self.orphans.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.orphans.is_empty()
+    }
+}
+
+#[cfg(any(test, test_utilities))]
+mod tests {
+    use super::*;
+    use crate::helper::*;
+    use std::net::{IpAddr, Ipv4Addr};
+    use std::thread;
+
+    #[test]
+    fn test_eviction() {
+        let peer_1 = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 17401);
+        let peer_2 = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 17402);
+        let mut pool = OrphanBlockPool::new(2, Duration::from_secs(600));
+        let parent = generate_random_hash();
+        let block_1 = generate_random_block(&parent);
+        let block_2 = generate_random_block(&parent);
+        let block_3 = generate_random_block(&block_1.hash);
+        pool.insert(block_1.clone(), Some(peer_1), true);
+        thread::sleep(Duration::from_millis(2));
+        pool.insert(block_2.clone(), Some(peer_2), false);
+        thread::sleep(Duration::from_millis(2));
+        assert_eq!(2, pool.len());
+
+        // the oldest one goes first
+        pool.insert(block_3.clone(), Some(peer_2), false);
+        assert_eq!(2, pool.len());
+        assert!(!pool.contains(&block_1.hash));
+        assert_eq!(Some(peer_2), pool.peer_of(&block_3.hash));
+        assert_eq!(Some(&2), pool.count_by_peer().get(&peer_2));
+        assert!(!pool.count_by_peer().contains_key(&peer_1));
+
+        let children = pool.remove_children(&parent);
+        assert_eq!(1, children.len());
+        assert_eq!(block_2, children[0].block);
+        assert!(pool.remove_children(&block_1.hash)[0].block == block_3);
+        assert!(pool.is_empty());
+    }
+
+    #[test]
+    fn test_expire() {
+        let mut pool = OrphanBlockPool::new(10, Duration::from_millis(20));
+        let block_1 = generate_random_block(&generate_random_hash());
+        let block_2 = generate_random_block(&generate_random_hash());
+        pool.insert(block_1.clone(), None, false);
+        thread::sleep(Duration::from_millis(40));
+        pool.insert(block_2.clone(), None, false);
+        assert!(!pool.contains(&block_1.hash));
+        assert!(pool.contains(&block_2.hash));
+        thread::sleep(Duration::from_millis(40));
+        assert_eq!(1, pool.expire());
+        assert!(pool.is_empty());
+    }
+}
@@ -0,0 +1,188 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use log::debug;
+
+use crate::crypto::hash::H256;
+
+// What an orphan pool keeps of each orphan
+pub trait OrphanEntry {
+    fn peer(&self) -> Option<SocketAddr>;  // peer who supplied it, none if added locally
+}
+
+struct Entry<T> {
+    orphan: T,
+    received: Instant,
+}
+
+// Orphans limited in number, in number per peer and in age, the oldest ones are dropped first.
+// Indexes of the orphans are left to the owner, which unindexes the ones dropped here
+pub struct OrphanPool<T: OrphanEntry> {
+    entries: HashMap<H256, Entry<T>>,
+    by_peer: HashMap<SocketAddr, usize>,  // number of orphans supplied by each peer
+    capacity: usize,
+    peer_capacity: usize,  // max number of orphans supplied by one peer
+    expiry: Duration,
+    kind: &'static str,  // of the orphans, for logs
+}
+
+impl<T: OrphanEntry> OrphanPool<T> {
+    pub fn new(kind: &'static str, capacity: usize, expiry: Duration) -> Self {
+        Self {
+            entries: HashMap::new(),
+            by_peer: HashMap::new(),
+            capacity,
+            peer_capacity: usize::MAX,
+            expiry,
+            kind,
+        }
+    }
+
+    pub fn with_peer_capacity(mut self, peer_capacity: usize) -> Self {
+        self.peer_capacity = peer_capacity;
+        self
+    }
+
+    // Make room for an orphan of the peer, dropping expired ones first, then the oldest one of the peer
+    // if the peer's share is full, and then the oldest one if the pool is full. Return the dropped ones,
+    // and whether the orphan can be added at all
+    pub fn make_room(&mut self, peer: Option<SocketAddr>) -> (Vec<(H256, T)>, bool) {
+        let mut dropped = self.expire();
+        if let Some(peer) = peer {
+            if self.peer_capacity == 0 {
+                return (dropped, false);
+            }
+            while self.count_of(&peer) >= self.peer_capacity {
+                let oldest = self.oldest(|o| o.peer() == Some(peer)).unwrap();
+                debug!("Orphan {} of {} are too many, evict {:?}", self.kind, peer, oldest);
+                dropped.extend(self.remove(&oldest).map(|o| (oldest, o)));
+            }
+        }
+        if self.capacity == 0 {
+            return (dropped, false);
+        }
+        while self.entries.len() >= self.capacity {
+            let oldest = self.oldest(|_| true).unwrap();
+            debug!("Orphan {} pool is full, evict {:?}", self.kind, oldest);
+            dropped.extend(self.remove(&oldest).map(|o| (oldest, o)));
+        }
+        (dropped, true)
+    }
+
+    // Add an orphan, after make_room
+    pub fn insert(&mut self, hash: H256, orphan: T) {
+        if let Some(peer) = orphan.peer() {
+            *self.by_peer.entry(peer).or_insert(0) += 1;
+        }
+        if let Some(old) = self.entries.insert(hash, Entry { orphan, received: Instant::now() }) {
+            self.uncount(&old.orphan);
+        }
+    }
+
+    fn oldest<F: Fn(&T) -> bool>(&self, filter: F) -> Option<H256> {
+        self.entries.iter()
+            .filter(|(_, e)| filter(&e.orphan))
+            .min_by_key(|(_, e)| e.received)
+            .map(|(h, _)| *h)
+    }
+
+    // Drop orphans older than the expiry and return them
+    pub fn expire(&mut self) -> Vec<(H256, T)> {
+        let expiry = self.expiry;
+        let expired: Vec<H256> = self.entries.iter()
+            .filter(|(_, e)| e.received.elapsed() > expiry)
+            .map(|(h, _)| *h)
+            .collect();
+        if !expired.is_empty() {
+            debug!("Expire {} orphan {}", expired.len(), self.kind);
+        }
+        expired.into_iter()
+            .filter_map(|h| self.remove(&h).map(|o| (h, o)))
+            .collect()
+    }
+
+    pub fn remove(&mut self, hash: &H256) -> Option<T> {
+        let entry = self.entries.remove(hash)?;
+        self.uncount(&entry.orphan);
+        Some(entry.orphan)
+    }
+
+    fn uncount(&mut self, orphan: &T) {
+        if let Some(peer) = orphan.peer() {
+            if let Some(count) = self.by_peer.get_mut(&peer) {
+                *count -= 1;
+                if *count == 0 {
+                    self.by_peer.remove(&peer);
+                }
+            }
+        }
+    }
+
+    pub fn get(&self, hash: &H256) -> Option<&T> {
+        self.entries.get(hash).map(|e| &e.orphan)
+    }
+
+    pub fn contains(&self, hash: &H256) -> bool {
+        self.entries.contains_key(hash)
+    }
+
+    pub fn count_of(&self, peer: &SocketAddr) -> usize {
+        self.by_peer.get(peer).copied().unwrap_or(0)
+    }
+
+    // Number of orphans supplied by each peer, locally-added ones are left out
+    pub fn count_by_peer(&self) -> HashMap<SocketAddr, usize> {
+        self.by_peer.clone()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+#[cfg(any(test, test_utilities))]
+mod tests {
+    use super::*;
+    use crate::helper::*;
+    use std::net::{IpAddr, Ipv4Addr};
+    use std::thread;
+
+    impl OrphanEntry for Option<SocketAddr> {
+        fn peer(&self) -> Option<SocketAddr> {
+            *self
+        }
+    }
+
+    #[test]
+    fn test_peer_counts() {
+        let peer_1 = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 17421);
+        let peer_2 = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 17422);
+        let mut pool = OrphanPool::new("tests", 3, Duration::from_secs(600)).with_peer_capacity(2);
+        let hashes: Vec<H256> = (0..5).map(|_| generate_random_hash()).collect();
+        for (hash, peer) in hashes.iter().zip([Some(peer_1), Some(peer_1), None].iter()) {
+            assert_eq!((vec![], true), pool.make_room(*peer));
+            pool.insert(*hash, *peer);
+            thread::sleep(Duration::from_millis(2));
+        }
+
+        // the peer's share is full, its oldest one goes first
+        assert_eq!((vec![(hashes[0], Some(peer_1))], true), pool.make_room(Some(peer_1)));
+        pool.insert(hashes[3], Some(peer_1));
+        assert_eq!(2, pool.count_of(&peer_1));
+
+        // then the oldest one of the full pool
+        assert_eq!((vec![(hashes[1], Some(peer_1))], true), pool.make_room(Some(peer_2)));
+        pool.insert(hashes[4], Some(peer_2));
+        assert_eq!(Some(&1), pool.count_by_peer().get(&peer_1));
+        assert_eq!(Some(&1), pool.count_by_peer().get(&peer_2));
+        assert_eq!(Some(None), pool.remove(&hashes[2]));
+        pool.remove(&hashes[3]);
+        assert_eq!(0, pool.count_of(&peer_1));
+        assert!(!pool.count_by_peer().contains_key(&peer_1));
+        assert_eq!(1, pool.len());
+    }
+}
@@ -0,0 +1,199 @@
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use crate::crypto::hash::H256;
+use crate::orphan_pool::{OrphanEntry, OrphanPool};
+use crate::transaction::{SignedTransaction, TxInput};
+
+pub struct OrphanTran {
+    pub tran: SignedTransaction,
+    pub peer: Option<SocketAddr>,  // peer who supplied it, none if added locally
+    missing: Vec<TxInput>,  // inputs spending outputs unknown when it arrived
+}
+
+impl OrphanEntry for OrphanTran {
+    fn peer(&self) -> Option<SocketAddr> {
+        self.peer
+    }
+}
+
+// Transactions spending outputs unknown yet, limited in number, in number per peer and in age
+pub struct OrphanTranPool {
+    orphans: OrphanPool<OrphanTran>,
+    by_outpoint: HashMap<TxInput, HashSet<H256>>,  // key is a missing outpoint
+}
+
+impl OrphanTranPool {
+    pub fn new(capacity: usize, peer_capacity: usize, expiry: Duration) -> Self {
+        Self {
+            orphans: OrphanPool::new("transactions", capacity, expiry).with_peer_capacity(peer_capacity),
+            by_outpoint: HashMap::new(),
+        }
+    }
+
+    // Add an orphan waiting for the missing outpoints, dropping expired ones first, then the oldest one
+    // of the peer if the peer's share is full, and then the oldest one if the pool is full
+    pub fn insert(&mut self, tran: SignedTransaction, missing: Vec<TxInput>, peer: Option<SocketAddr>) {
+        if self.orphans.contains(&tran.hash) || missing.is_empty() {
+            return;
+        }
+        let (dropped, room) = self.orphans.make_room(peer);
+        for (hash, orphan) in dropped.iter() {
+            self.unindex(hash, orphan);
+        }
+        if !room {
+            return;
+        }
+        for outpoint in missing.iter() {
+            self.by_outpoint.entry(outpoint.clone()).or_default().insert(tran.hash);
+        }
+        self.orphans.insert(tran.hash, OrphanTran { tran, peer, missing });
+    }
+
+    // Drop orphans older than the expiry, return the number dropped
+    pub fn expire(&mut self) -> usize {
+        let expired = self.orphans.expire();
+        for (hash, orphan) in expired.iter() {
+            self.unindex(hash, orphan);
+        }
+        expired.len()
+    }
+
+    pub fn remove(&mut self, hash: &H256) -> Option<OrphanTran> {
+        let orphan = self.orphans.remove(hash)?;
+        self.unindex(hash, &orphan);
+        Some(orphan)
+    }
+
+    fn unindex(&mut self, hash: &H256, orphan: &OrphanTran) {
+        for outpoint in orphan.missing.iter() {
+            if let Some(spenders) = self.by_outpoint.get_mut(outpoint) {
+                spenders.remove(hash);
+                if spenders.is_empty() {
+                    self.by_outpoint.remove(outpoint);
+                }
+            }
+        }
+    }
+
+    // Take out all orphans spending outputs of the given transaction
+    pub fn remove_spending(&mut self, parent: &SignedTransaction) -> Vec<OrphanTran> {
+        let mut hashes = HashSet::<H256>::new();
+        for index in 0..parent.transaction.outputs.len() {
+            if let Some(spenders) = self.by_outpoint.get(&TxInput::new(parent.hash, index as u32)) {
+                hashes.extend(spenders.iter().copied());
+            }
+        }
+        hashes.iter().filter_map(|h| self.remove(h)).collect()
+    }
+
+    // Hashes of the transactions an orphan is waiting for
+    pub fn missing_parents(&self, hash: &H256) -> Vec<H256> {
+        let mut parents = Vec::<H256>::new();
+        for outpoint in self.orphans.get(hash).into_iter().flat_map(|o| o.missing.iter()) {
+            if !parents.contains(&outpoint.pre_hash) {
+                parents.push(outpoint.pre_hash);
+            }
+        }
+        parents
+    }
+
+    pub fn contains(&self, hash: &H256) -> bool {
+        self.orphans.contains(hash)
+    }
+
+    // Number of orphans supplied by each peer, locally-added ones are left out
+    pub fn count_by_peer(&self) -> HashMap<SocketAddr, usize> {
+        self.orphans.count_by_peer()
+    }
+
+    pub fn len(&self) -> usize {
+        self.orphans.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.orphans.is_empty()
+    }
+}
+
+#[cfg(any(test, test_utilities))]
+mod tests {
+    use super::*;
+    use crate::helper::*;
+    use crate::transaction::TxOutput;
+    use crate::crypto::key_pair;
+    use std::net::{IpAddr, Ipv4Addr};
+    use std::thread;
+
+    fn spending(parent: &H256, index: u32) -> SignedTransaction {
+        let key = key_pair::random();
+        generate_signed_transaction(&key, vec![TxInput::new(*parent, index)], vec![TxOutput::new(generate_random_h160(), 1)])
+    }
+
+    #[test]
+    fn test_eviction() {
+        let peer_1 = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 17411);
+        let peer_2 = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 17412);
+        let mut pool = OrphanTranPool::new(3, 2, Duration::from_secs(600));
+        let parent = generate_random_hash();
+        let orphans: Vec<SignedTransaction> = (0..4).map(|i| spending(&parent, i)).collect();
+        let missing = |t: &SignedTransaction| t.transaction.inputs.clone();
+        pool.insert(orphans[0].clone(), missing(&orphans[0]), Some(peer_1));
+        thread::sleep(Duration::from_millis(2));
+        pool.insert(orphans[1].clone(), missing(&orphans[1]), Some(peer_1));
+        thread::sleep(Duration::from_millis(2));
+        pool.insert(orphans[2].clone(), missing(&orphans[2]), None);
+        thread::sleep(Duration::from_millis(2));
+        assert_eq!(3, pool.len());
+
+        // peer_1 is at its share, so its oldest one goes first
+        pool.insert(orphans[3].clone(), missing(&orphans[3]), Some(peer_1));
+        assert_eq!(3, pool.len());
+        assert!(!pool.contains(&orphans[0].hash));
+        assert_eq!(Some(&2), pool.count_by_peer().get(&peer_1));
+
+        // then the oldest one of the full pool
+        let other = spending(&generate_random_hash(), 0);
+        pool.insert(other.clone(), missing(&other), Some(peer_2));
+        assert_eq!(3, pool.len());
+        assert!(!pool.contains(&orphans[1].hash));
+        assert_eq!(vec![other.transaction.inputs[0].pre_hash], pool.missing_parents(&other.hash));
+    }
+
+    #[test]
+    fn test_remove_spending() {
+        let key = key_pair::random();
+        let parent = generate_signed_transaction(&key, vec![TxInput::new(generate_random_hash(), 0)],
+                                                 vec![TxOutput::new(generate_random_h160(), 1); 2]);
+        let mut pool = OrphanTranPool::new(10, 10, Duration::from_secs(600));
+        let child_1 = spending(&parent.hash, 0);
+        let child_2 = spending(&parent.hash, 1);
+        let unrelated = spending(&parent.hash, 2);
+        for tran in [&child_1, &child_2, &unrelated].iter() {
+            pool.insert((*tran).clone(), tran.transaction.inputs.clone(), None);
+        }
+        let mut taken: Vec<H256> = pool.remove_spending(&parent).into_iter().map(|o| o.tran.hash).collect();
+        taken.sort();
+        let mut expected = vec![child_1.hash, child_2.hash];
+        expected.sort();
+        assert_eq!(expected, taken);
+        assert_eq!(1, pool.len());
+        assert!(pool.contains(&unrelated.hash));
+    }
+
+    #[test]
+    fn test_expire() {
+        let mut pool = OrphanTranPool::new(10, 10, Duration::from_millis(20));
+        let tran_1 = spending(&generate_random_hash(), 0);
+        let tran_2 = spending(&generate_random_hash(), 0);
+        pool.insert(tran_1.clone(), tran_1.transaction.inputs.clone(), None);
+        thread::sleep(Duration::from_millis(40));
+        pool.insert(tran_2.clone(), tran_2.transaction.inputs.clone(), None);
+        assert!(!pool.contains(&tran_1.hash));
+        assert!(pool.contains(&tran_2.hash));
+        thread::sleep(Duration::from_millis(40));
+        assert_eq!(1, pool.expire());
+        assert!(pool.is_empty());
+    }
+}
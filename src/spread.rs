@@ -4,7 +4,7 @@ use crate::network::peer::Handle;
 use crate::network::server::Handle as ServerHandle;
 use crate::helper;
 use crate::config::{TRICKLE_GAP_TIME, DIFFUSION_BASE_GAP_TIME, DIFFUSION_RATE,
-    EPOCH_MS, PHASE_SWITCH_PROB, IS_DIFFUSER_PROB, T_BASE};
+    EPOCH_MS, PHASE_SWITCH_PROB, IS_DIFFUSER_PROB, T_BASE, MEMPOOL_EXPIRY_CHECK};
 
 use std::thread;
 use std::sync::{Mutex, Arc};
@@ -36,13 +36,16 @@ pub enum TimerTask {
     DandelionResetEpoch(i64, Arc<Mutex<usize>>),
     DandelionPlusResetEpoch(i64, Arc<Mutex<HashMap<usize, usize>>>),
     DandelionPlusFailSafeCheck(i64, Vec<H256>),
+    MempoolExpiry,
 }
 
 fn new_base(mempool: Arc<Mutex<MemPool>>, handle: ServerHandle) -> (MessageTimer<TimerTask>, Arc<Mutex<HashMap<i64, Guard>>>, Context) {
     let (sender, receiver) = channel();
     let timer = MessageTimer::new(sender);
     let guard_map = Arc::new(Mutex::new(HashMap::new()));
-    let context = Context { receiver, guard_map: guard_map.clone(), mempool, server: handle };
+    let expiry_guard = timer.schedule_repeating(chrono::Duration::milliseconds(MEMPOOL_EXPIRY_CHECK as i64),
+                                                TimerTask::MempoolExpiry);
+    let context = Context { receiver, guard_map: guard_map.clone(), mempool, server: handle, expiry_guard };
 
     return (timer, guard_map, context);
 }
@@ -52,6 +55,7 @@ pub struct Context {
     guard_map: Arc<Mutex<HashMap<i64, Guard>>>,
     mempool: Arc<Mutex<MemPool>>,
     server: ServerHandle,
+    expiry_guard: Guard,  // keeps the mempool expiry sweeps going
 }
 
 impl Context {
@@ -61,7 +65,9 @@ impl Context {
                 Ok(task) => {
                     match task {
                         TimerTask::PeerWrite(nano, handle, msg) => {
-                            handle.write(msg);
+                            if let Some(msg) = self.apply_fee_filter(&handle, msg) {
+                                handle.write(msg);
+                            }
                             self.guard_map.lock().unwrap().remove(&nano);
                         }
                         TimerTask::DandelionResetEpoch(nano, target_index) => {
@@ -80,6 +86,7 @@ impl Context {
                                     Some(tran) => {
                                         if mempool.exist(&tran.hash) {
                                             // source of this transaction
+                                            mempool.mark_public(&tran.hash);
                                             new_hashes.push(tran.hash());
                                         } else if mempool.add_with_check(&tran) {
                                             new_hashes.push(tran.hash());
@@ -94,6 +101,9 @@ impl Context {
                             }
                             self.guard_map.lock().unwrap().remove(&nano);
                         }
+                        TimerTask::MempoolExpiry => {
+                            self.mempool.lock().unwrap().expire();
+                        }
                     }
                 }
                 _ => {}
@@ -101,6 +111,25 @@ impl Context {
         }
     }
 
+    // Leave out announced transactions below the fee filter of the peer, None if none is left
+    fn apply_fee_filter(&self, handle: &Handle, msg: Message) -> Option<Message> {
+        let filter = handle.fee_filter();
+        match msg {
+            Message::NewTransactionHashes(hashes) if filter > 0 => {
+                let mempool = self.mempool.lock().unwrap();
+                let hashes: Vec<H256> = hashes.into_iter()
+                    .filter(|h| mempool.fee_rate_of(h).unwrap_or(0) >= filter)
+                    .collect();
+                if hashes.is_empty() {
+                    None
+                } else {
+                    Some(Message::NewTransactionHashes(hashes))
+                }
+            }
+            msg => Some(msg),
+        }
+    }
+
     pub fn start(mut self) {
         thread::Builder::new()
             .name("message_loop".to_string())
@@ -237,6 +266,7 @@ impl Spreading for DandelionSpreader {
                     let mut mempool = self.mempool.lock().unwrap();
                     for t in trans.iter() {
                         if mempool.exist(&t.hash()) { // source
+                            mempool.mark_public(&t.hash);
                             new_hashes.push(t.hash());
                         } else if mempool.add_with_check(t) {
                             new_hashes.push(t.hash());
@@ -361,6 +391,7 @@ impl Spreading for DandelionPlusSpreader {
                     let mut mempool = self.mempool.lock().unwrap();
                     for t in trans.iter() {
                         if mempool.exist(&t.hash()) {
+                            mempool.mark_public(&t.hash);
                             new_hashes.push(t.hash());
                         } else if mempool.add_with_check(t) {
                             new_hashes.push(t.hash());
@@ -0,0 +1,324 @@
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use log::{info, warn};
+use ring::digest;
+
+use crate::block::Block;
+use crate::crypto::hash::H256;
+
+static BLOCKS_FILE: &str = "blocks.dat";
+static INDEX_FILE: &str = "index.dat";
+static TIP_FILE: &str = "tip";
+static TIP_TMP_FILE: &str = "tip.tmp";
+
+// hash(32) + height(8) + offset(8) + length(4) + checksum(4)
+const INDEX_RECORD_LEN: usize = 56;
+
+// Blocks are appended to blocks.dat and each one gets a fixed-size record in index.dat, written only
+// after the block itself is synced. The tip pointer is replaced atomically by renaming, so a crash can
+// only leave a torn record at the end of a file, which is cut off by length/checksum on the next open.
+pub struct BlockStore {
+    dir: PathBuf,
+    blocks_file: File,
+    index_file: File,
+    blocks_len: u64,
+    index: HashMap<H256, IndexEntry>,
+    heights: HashMap<usize, Vec<H256>>,
+    tip: Option<H256>,
+}
+
+#[derive(Clone, Copy)]
+struct IndexEntry {
+    height: usize,
+    offset: u64,
+    len: u32,
+    checksum: u32,
+}
+
+impl BlockStore {
+    // Open (or create) the store in dir, dropping any torn record left by a crash
+    pub fn open(dir: &Path) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        let mut blocks_file = OpenOptions::new().read(true).write(true).create(true).truncate(false)
+            .open(dir.join(BLOCKS_FILE))?;
+        let mut index_file = OpenOptions::new().read(true).write(true).create(true).truncate(false)
+            .open(dir.join(INDEX_FILE))?;
+
+        let mut raw_index = Vec::new();
+        index_file.read_to_end(&mut raw_index)?;
+        let blocks_file_len = blocks_file.metadata()?.len();
+
+        let mut index = HashMap::new();
+        let mut heights: HashMap<usize, Vec<H256>> = HashMap::new();
+        let mut blocks_len = 0u64;
+        let mut valid_records = 0usize;
+        for record in raw_index.chunks(INDEX_RECORD_LEN) {
+            if record.len() < INDEX_RECORD_LEN {
+                break;
+            }
+            let (hash, entry) = decode_index_record(record);
+            let end = entry.offset + entry.len as u64;
+            if entry.offset != blocks_len || end > blocks_file_len {
+                break;
+            }
+            let bytes = read_at(&mut blocks_file, entry.offset, entry.len)?;
+            if checksum(&bytes) != entry.checksum {
+                break;
+            }
+            heights.entry(entry.height).or_default().push(hash);
+            index.insert(hash, entry);
+            blocks_len = end;
+            valid_records += 1;
+        }
+
+        // cut off whatever a crash left behind the last complete record
+        let index_len = (valid_records * INDEX_RECORD_LEN) as u64;
+        if index_len != raw_index.len() as u64 || blocks_len != blocks_file_len {
+            warn!("Block store at {:?} has a torn tail, keeping {} blocks", dir, valid_records);
+            index_file.set_len(index_len)?;
+            blocks_file.set_len(blocks_len)?;
+            index_file.sync_all()?;
+            blocks_file.sync_all()?;
+        }
+
+        let tip = match fs::read(dir.join(TIP_FILE)) {
+            Ok(bytes) if bytes.len() == 32 => {
+                let raw: [u8; 32] = bytes[..].try_into().unwrap();
+                let hash: H256 = raw.into();
+                if index.contains_key(&hash) { Some(hash) } else { None }
+            }
+            _ => None,
+        };
+
+        info!("Opened block store at {:?} with {} blocks", dir, index.len());
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            blocks_file,
+            index_file,
+            blocks_len,
+            index,
+            heights,
+            tip,
+        })
+    }
+
+    // Append a block (its index must already be set) and its index record
+    pub fn put(&mut self, block: &Block) -> io::Result<()> {
+        if self.index.contains_key(&block.hash) {
+            return Ok(());
+        }
+        let bytes = bincode::serialize(block).unwrap();
+        let entry = IndexEntry {
+            height: block.index,
+            offset: self.blocks_len,
+            len: bytes.len() as u32,
+            checksum: checksum(&bytes),
+        };
+
+        self.blocks_file.seek(SeekFrom::Start(entry.offset))?;
+        self.blocks_file.write_all(&bytes)?;
+        self.blocks_file.sync_data()?;
+
+        // the block is durable now, so the record pointing to it can follow, over any torn one
+        self.index_file.seek(SeekFrom::Start((self.index.len() * INDEX_RECORD_LEN) as u64))?;
+        self.index_file.write_all(&encode_index_record(&block.hash, &entry))?;
+        self.index_file.sync_data()?;
+
+        self.blocks_len += entry.len as u64;
+        self.heights.entry(entry.height).or_default().push(block.hash);
+        self.index.insert(block.hash, entry);
+        Ok(())
+    }
+
+    // Replace the tip pointer atomically
+    pub fn set_tip(&mut self, hash: &H256) -> io::Result<()> {
+        let tmp_path = self.dir.join(TIP_TMP_FILE);
+        let mut tmp = File::create(&tmp_path)?;
+        tmp.write_all(hash.as_ref())?;
+        tmp.sync_all()?;
+        fs::rename(&tmp_path, self.dir.join(TIP_FILE))?;
+        self.tip = Some(*hash);
+        Ok(())
+    }
+
+    pub fn tip(&self) -> Option<H256> {
+        self.tip
+    }
+
+    pub fn get(&mut self, hash: &H256) -> io::Result<Option<Block>> {
+        let entry = match self.index.get(hash) {
+            Some(entry) => *entry,
+            None => return Ok(None),
+        };
+        let bytes = read_at(&mut self.blocks_file, entry.offset, entry.len)?;
+        match bincode::deserialize(&bytes) {
+            Ok(block) => Ok(Some(block)),
+            Err(e) => Err(io::Error::new(io::ErrorKind::InvalidData, e)),
+        }
+    }
+
+    // Load every stored block, parents before children
+    pub fn load_blocks(&mut self) -> io::Result<Vec<Block>> {
+        let mut heights: Vec<usize> = self.heights.keys().cloned().collect();
+        heights.sort();
+        let mut blocks = Vec::with_capacity(self.index.len());
+        for height in heights.iter() {
+            for hash in self.heights[height].clone().iter() {
+                if let Some(block) = self.get(hash)? {
+                    blocks.push(block);
+                }
+            }
+        }
+        Ok(blocks)
+    }
+
+    pub fn contains(&self, hash: &H256) -> bool {
+        self.index.contains_key(hash)
+    }
+
+    pub fn height_of(&self, hash: &H256) -> Option<usize> {
+        self.index.get(hash).map(|e| e.height)
+    }
+
+    // Hashes of all stored blocks (of any fork) at the given height
+    pub fn hashes_at(&self, height: usize) -> Vec<H256> {
+        self.heights.get(&height).cloned().unwrap_or_default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.index.is_empty()
+    }
+}
+
+fn checksum(bytes: &[u8]) -> u32 {
+    let d = digest::digest(&digest::SHA256, bytes);
+    u32::from_be_bytes(d.as_ref()[0..4].try_into().unwrap())
+}
+
+fn read_at(file: &mut File, offset: u64, len: u32) -> io::Result<Vec<u8>> {
+    let mut bytes = vec![0u8; len as usize];
+    file.seek(SeekFrom::Start(offset))?;
+    file.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+fn encode_index_record(hash: &H256, entry: &IndexEntry) -> Vec<u8> {
+    let mut record = Vec::with_capacity(INDEX_RECORD_LEN);
+    record.extend_from_slice(hash.as_ref());
+    record.extend_from_slice(&(entry.height as u64).to_be_bytes());
+    record.extend_from_slice(&entry.offset.to_be_bytes());
+    record.extend_from_slice(&entry.len.to_be_bytes());
+    record.extend_from_slice(&entry.checksum.to_be_bytes());
+    record
+}
+
+fn decode_index_record(record: &[u8]) -> (H256, IndexEntry) {
+    let raw: [u8; 32] = record[0..32].try_into().unwrap();
+    let entry = IndexEntry {
+        height: u64::from_be_bytes(record[32..40].try_into().unwrap()) as usize,
+        offset: u64::from_be_bytes(record[40..48].try_into().unwrap()),
+        len: u32::from_be_bytes(record[48..52].try_into().unwrap()),
+        checksum: u32::from_be_bytes(record[52..56].try_into().unwrap()),
+    };
+    (raw.into(), entry)
+}
+
+#[cfg(any(test, test_utilities))]
+pub mod tests {
+    use super::*;
+    use crate::helper::*;
+
+    pub fn temp_data_dir() -> PathBuf {
+        std::env::temp_dir().join(format!("bitcoin-test-{}", generate_random_str()))
+    }
+
+    #[test]
+    fn test_put_and_reopen() {
+        let dir = temp_data_dir();
+        let mut store = BlockStore::open(&dir).unwrap();
+        assert!(store.is_empty());
+        let mut block_1 = generate_random_block(&generate_random_hash());
+        block_1.index = 1;
+        let mut block_2 = generate_random_block(&block_1.hash);
+        block_2.index = 2;
+        store.put(&block_1).unwrap();
+        store.put(&block_2).unwrap();
+        store.set_tip(&block_2.hash).unwrap();
+        drop(store);
+
+        let mut store = BlockStore::open(&dir).unwrap();
+        assert_eq!(2, store.len());
+        assert_eq!(Some(block_2.hash), store.tip());
+        assert_eq!(Some(1), store.height_of(&block_1.hash));
+        assert_eq!(vec![block_2.hash], store.hashes_at(2));
+        let blocks = store.load_blocks().unwrap();
+        assert_eq!(block_1, blocks[0]);
+        assert_eq!(block_2, blocks[1]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_torn_tail() {
+        let dir = temp_data_dir();
+        let mut store = BlockStore::open(&dir).unwrap();
+        let mut block_1 = generate_random_block(&generate_random_hash());
+        block_1.index = 1;
+        let mut block_2 = generate_random_block(&block_1.hash);
+        block_2.index = 2;
+        store.put(&block_1).unwrap();
+        store.put(&block_2).unwrap();
+        drop(store);
+
+        // half-written index record and a corrupted block body
+        let mut index_file = OpenOptions::new().append(true).open(dir.join(INDEX_FILE)).unwrap();
+        index_file.write_all(&[7u8; INDEX_RECORD_LEN / 2]).unwrap();
+        let mut blocks_file = OpenOptions::new().write(true).open(dir.join(BLOCKS_FILE)).unwrap();
+        let last_byte = *fs::read(dir.join(BLOCKS_FILE)).unwrap().last().unwrap();
+        blocks_file.seek(SeekFrom::End(-1)).unwrap();
+        blocks_file.write_all(&[!last_byte]).unwrap();
+        drop(index_file);
+        drop(blocks_file);
+
+        let mut store = BlockStore::open(&dir).unwrap();
+        assert_eq!(1, store.len());
+        assert!(store.contains(&block_1.hash));
+        assert!(!store.contains(&block_2.hash));
+
+        // the store stays appendable after recovery
+        store.put(&block_2).unwrap();
+        drop(store);
+        let store = BlockStore::open(&dir).unwrap();
+        assert_eq!(2, store.len());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_torn_write_overwritten() {
+        let dir = temp_data_dir();
+        let mut store = BlockStore::open(&dir).unwrap();
+        let mut block_1 = generate_random_block(&generate_random_hash());
+        block_1.index = 1;
+        let mut block_2 = generate_random_block(&block_1.hash);
+        block_2.index = 2;
+        store.put(&block_1).unwrap();
+
+        // an index record torn by a failed write, while the store is open
+        let mut index_file = OpenOptions::new().append(true).open(dir.join(INDEX_FILE)).unwrap();
+        index_file.write_all(&[7u8; INDEX_RECORD_LEN / 2]).unwrap();
+        drop(index_file);
+
+        store.put(&block_2).unwrap();
+        drop(store);
+        let store = BlockStore::open(&dir).unwrap();
+        assert_eq!(2, store.len());
+        assert!(store.contains(&block_2.hash));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -6,7 +6,6 @@ use std::time::SystemTime;
 use std::str;
 
 use crate::crypto::hash::{Hashable, H256, H160};
-use crate::config::COINBASE_REWARD;
 
 ///UTXO model transaction
 #[derive(Eq, PartialEq, Serialize, Deserialize, Debug, Default, Clone, Hash)]
@@ -22,6 +21,7 @@ pub struct Transaction {
     pub inputs: Vec<TxInput>,
     pub outputs: Vec<TxOutput>,
     pub ts: u64,  // timestamp to avoid same hash
+    pub extra_nonce: u32,  // bumped in a coinbase for a new merkle root once header nonces run out, 0 otherwise
 }
 
 #[derive(Serialize, Deserialize)]
@@ -31,6 +31,7 @@ pub struct PrintableTransaction {
     pub public_key: String,
     pub inputs: Vec<PrintableTxInput>,
     pub outputs: Vec<PrintableTxOutput>,
+    pub fee: Option<u64>,  // none if unknown
 }
 
 #[derive(Eq, PartialEq, Serialize, Deserialize, Debug, Default, Clone, Hash)]
@@ -72,7 +73,7 @@ impl Transaction {
     pub fn new(inputs: Vec<TxInput>, outputs: Vec<TxOutput>) -> Self {
         let ts = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)
                 .unwrap().as_millis() as u64;
-        Self {inputs: inputs, outputs: outputs, ts: ts}
+        Self {inputs, outputs, ts, extra_nonce: 0}
     }
 }
 
@@ -95,17 +96,19 @@ impl SignedTransaction {
         digest::digest(&digest::SHA256, &self.public_key).into()
     }
 
+    // Serialized size(bytes), which fee rates are based on
+    pub fn size(&self) -> usize {
+        bincode::serialized_size(self).unwrap() as usize
+    }
+
+    // Check the shape of a coinbase, its value is checked against the fees of its block
     pub fn is_coinbase_tran(&self) -> bool {
         // check length
         if self.transaction.inputs.len() > 0 ||
            self.transaction.outputs.len() != 1 {
             return false;
         }
-        // check value
         let output = self.transaction.outputs[0].clone();
-        if output.val != COINBASE_REWARD {
-            return false;
-        }
         // match address with public_key
         let addr: H160 = digest::digest(&digest::SHA256, &self.public_key).into();
         if addr != output.rec_address {
@@ -116,9 +119,10 @@ impl SignedTransaction {
 }
 
 impl PrintableTransaction {
-    pub fn from_signedtx_vec(txs: &Vec<SignedTransaction>) -> Vec<Self> {
+    // fees[i] is the fee of txs[i], missing ones are unknown
+    pub fn from_signedtx_vec(txs: &[SignedTransaction], fees: &[Option<u64>]) -> Vec<Self> {
         let mut ptxs = Vec::<Self>::new();
-        for tx in txs {
+        for (index, tx) in txs.iter().enumerate() {
 
             let signature = hex::encode(tx.signature.as_ref());
             let public_key = hex::encode(tx.public_key.as_ref());
@@ -131,6 +135,7 @@ impl PrintableTransaction {
                 public_key,
                 inputs,
                 outputs,
+                fee: fees.get(index).copied().flatten(),
             };
             ptxs.push(p);
         }
@@ -282,11 +287,11 @@ pub mod tests {
         let signed_tran = SignedTransaction::new(coinbase_tran.clone(), sig_bytes.clone(), key_bytes.clone());
         assert!(!signed_tran.is_coinbase_tran());
 
-        // wrong reward
+        // reward plus fees, checked in its block
         let txoutput = TxOutput {rec_address: h160.clone(), val: COINBASE_REWARD+1};
         let coinbase_tran = Transaction::new(Vec::new(), vec![txoutput]);
         let signed_tran = SignedTransaction::new(coinbase_tran.clone(), sig_bytes.clone(), key_bytes.clone());
-        assert!(!signed_tran.is_coinbase_tran());
+        assert!(signed_tran.is_coinbase_tran());
 
         // wrong txoutput length - 0
         let coinbase_tran = Transaction::new(Vec::new(), Vec::new());
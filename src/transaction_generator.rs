@@ -160,14 +160,20 @@ impl Context {
 
     // Generating logic method!
     fn tx_generating(&mut self) {
-        // Update state from tip of longest-chain
-        let state = self.blockchain.lock().unwrap().tip_block_state();
+        // Update state from tip of longest-chain plus the pool, so that change of pending transactions
+        // can be spent; the new transaction can be in the next block at the earliest
+        let blockchain = self.blockchain.lock().unwrap();
+        let state = self.mempool.lock().unwrap().utxo_view();
+        let next_height = blockchain.length();
+        let maturity = blockchain.chain_params().coinbase_maturity;
+        drop(blockchain);
         if let Some(rec_addr) = self.random_peer_addr() {
-            if let Some(tran) = helper::generate_valid_tran(&state, &self.account, &rec_addr) {
+            if let Some(tran) = helper::generate_valid_tran(&state, &self.account, &rec_addr, next_height, maturity) {
                 let mut mempool = self.mempool.lock().unwrap();
                 if mempool.add_with_check(&tran) {
                     info!("Put a new transaction into client! Now mempool has {} transaction", mempool.size());
                     if self.dandelion {
+                        mempool.mark_private(&tran.hash);
                         let vec_trans = vec![tran];
                         self.server.broadcast(Message::NewDandelionTransactions(vec_trans), None);
                     } else {
@@ -208,6 +214,7 @@ impl Context {
         let mut mempool = self.mempool.lock().unwrap();
         if mempool.add_with_check(&new_t) {
             if self.dandelion {
+                mempool.mark_private(&new_t.hash);
                 let vec_trans = vec![new_t];
                 self.server.broadcast(Message::NewDandelionTransactions(vec_trans), None);
             } else {